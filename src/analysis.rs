@@ -0,0 +1,216 @@
+use regex_syntax::hir::{Class, Hir, HirKind, Literal, RepetitionKind, RepetitionRange};
+
+/// Static facts about a terminal pattern, derived from its syntax tree.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PatternInfo {
+    /// Character ranges a non-empty match can start with.
+    pub first: Vec<(char, char)>,
+    /// Whether the pattern can match without consuming input (zero-width assertions count).
+    pub nullable: bool,
+    /// Longest possible match in bytes, if bounded.
+    pub max_len: Option<usize>,
+}
+
+impl PatternInfo {
+    pub fn new(pattern: &str) -> Self {
+        match regex_syntax::Parser::new().parse(pattern) {
+            Ok(hir) => PatternInfo::from_hir(&hir),
+            Err(_) => PatternInfo{first: vec![('\0', char::MAX)], nullable: true, max_len: None},
+        }
+    }
+
+    fn from_hir(hir: &Hir) -> Self {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => PatternInfo{first: vec![], nullable: true, max_len: Some(0)},
+            HirKind::Literal(Literal::Unicode(c)) => PatternInfo{first: vec![(*c, *c)], nullable: false, max_len: Some(c.len_utf8())},
+            HirKind::Literal(Literal::Byte(b)) => PatternInfo{first: vec![(*b as char, *b as char)], nullable: false, max_len: Some(1)},
+            HirKind::Class(Class::Unicode(class)) => PatternInfo{
+                first: class.iter().map(|r| (r.start(), r.end())).collect(),
+                nullable: false,
+                max_len: Some(class.iter().map(|r| r.end().len_utf8()).max().unwrap_or(0)),
+            },
+            HirKind::Class(Class::Bytes(class)) => PatternInfo{
+                first: class.iter().map(|r| (r.start() as char, r.end() as char)).collect(),
+                nullable: false,
+                max_len: Some(1),
+            },
+            HirKind::Group(group) => PatternInfo::from_hir(&group.hir),
+            HirKind::Repetition(rep) => {
+                let inner = PatternInfo::from_hir(&rep.hir);
+                let (min, max) = match rep.kind {
+                    RepetitionKind::ZeroOrOne => (0, Some(1)),
+                    RepetitionKind::ZeroOrMore => (0, None),
+                    RepetitionKind::OneOrMore => (1, None),
+                    RepetitionKind::Range(RepetitionRange::Exactly(n)) => (n, Some(n)),
+                    RepetitionKind::Range(RepetitionRange::AtLeast(n)) => (n, None),
+                    RepetitionKind::Range(RepetitionRange::Bounded(n, m)) => (n, Some(m)),
+                };
+                let max_len = match (inner.max_len, max) {
+                    (Some(0), _) => Some(0),
+                    (Some(len), Some(max)) => Some(len * max as usize),
+                    _ => None,
+                };
+                PatternInfo{first: inner.first, nullable: min == 0 || inner.nullable, max_len}
+            }
+            HirKind::Concat(hirs) => {
+                let mut info = PatternInfo{first: vec![], nullable: true, max_len: Some(0)};
+                for each in hirs.iter().map(PatternInfo::from_hir) {
+                    info.max_len = info.max_len.and_then(|a| each.max_len.map(|b| a + b));
+                    if info.nullable {
+                        info.first.extend(each.first);
+                    }
+                    info.nullable = info.nullable && each.nullable;
+                }
+                info
+            }
+            HirKind::Alternation(hirs) => {
+                let mut info = PatternInfo{first: vec![], nullable: false, max_len: Some(0)};
+                for each in hirs.iter().map(PatternInfo::from_hir) {
+                    info.max_len = info.max_len.and_then(|a| each.max_len.map(|b| a.max(b)));
+                    info.nullable = info.nullable || each.nullable;
+                    info.first.extend(each.first);
+                }
+                info
+            }
+        }
+    }
+
+    pub fn can_start(&self, c: char) -> bool {
+        self.first.iter().any(|&(start, end)| start <= c && c <= end)
+    }
+}
+
+/// The text `pattern` matches if it matches exactly one string.
+pub(crate) fn literal(pattern: &str) -> Option<String> {
+    fn collect(hir: &Hir, out: &mut String) -> bool {
+        match hir.kind() {
+            HirKind::Empty => true,
+            HirKind::Literal(Literal::Unicode(c)) => {
+                out.push(*c);
+                true
+            }
+            HirKind::Group(group) => collect(&group.hir, out),
+            HirKind::Concat(hirs) => hirs.iter().all(|hir| collect(hir, out)),
+            _ => false,
+        }
+    }
+    let hir = regex_syntax::Parser::new().parse(pattern).ok()?;
+    let mut out = String::new();
+    if collect(&hir, &mut out) {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// The character ranges of a pattern that is exactly one character class, such as `[a-z]`.
+pub(crate) fn class(pattern: &str) -> Option<Vec<(char, char)>> {
+    fn find(hir: &Hir) -> Option<Vec<(char, char)>> {
+        match hir.kind() {
+            HirKind::Class(Class::Unicode(class)) => Some(class.iter().map(|r| (r.start(), r.end())).collect()),
+            HirKind::Group(group) => find(&group.hir),
+            _ => None,
+        }
+    }
+    find(&regex_syntax::Parser::new().parse(pattern).ok()?)
+}
+
+/// A pattern matching the texts that some match of `pattern` starts with, such as `a`,
+/// `aa` and `aab` for `a+b`. Assertions are dropped and repetitions unbounded, so it may
+/// match more of them, never fewer.
+pub(crate) fn prefixes(pattern: &str) -> Option<String> {
+    fn whole(hir: &Hir) -> String {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => String::new(),
+            HirKind::Literal(_) | HirKind::Class(_) => format!("(?:{})", hir),
+            HirKind::Group(group) => whole(&group.hir),
+            HirKind::Repetition(rep) => {
+                let min = match rep.kind {
+                    RepetitionKind::ZeroOrOne | RepetitionKind::ZeroOrMore => 0,
+                    RepetitionKind::OneOrMore => 1,
+                    RepetitionKind::Range(RepetitionRange::Exactly(n) | RepetitionRange::AtLeast(n) | RepetitionRange::Bounded(n, _)) => n,
+                };
+                format!("(?:{}){{{},}}", whole(&rep.hir), min)
+            }
+            HirKind::Concat(hirs) => hirs.iter().map(whole).collect(),
+            HirKind::Alternation(hirs) => format!("(?:{})", hirs.iter().map(whole).collect::<Vec<_>>().join("|")),
+        }
+    }
+    fn prefix(hir: &Hir) -> String {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => String::new(),
+            HirKind::Literal(_) | HirKind::Class(_) => format!("(?:{})?", hir),
+            HirKind::Group(group) => prefix(&group.hir),
+            HirKind::Repetition(rep) => format!("(?:{})*{}", whole(&rep.hir), prefix(&rep.hir)),
+            HirKind::Concat(hirs) => hirs.iter().rev().fold(String::new(), |rest, hir| {
+                format!("(?:{}|{}{})", prefix(hir), whole(hir), rest)
+            }),
+            HirKind::Alternation(hirs) => format!("(?:{})", hirs.iter().map(prefix).collect::<Vec<_>>().join("|")),
+        }
+    }
+    let hir = regex_syntax::Parser::new().parse(pattern).ok()?;
+    Some(prefix(&hir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_info_ok() {
+        let info = PatternInfo::new("true|false");
+        assert_eq!(info, PatternInfo{first: vec![('t', 't'), ('f', 'f')], nullable: false, max_len: Some(5)});
+
+        let info = PatternInfo::new("-?(0|[1-9][0-9]*)");
+        assert!(info.can_start('-') && info.can_start('0') && info.can_start('9'));
+        assert!(!info.can_start('a'));
+        assert!(!info.nullable);
+        assert_eq!(info.max_len, None);
+
+        let info = PatternInfo::new("x{2,3}");
+        assert_eq!(info.max_len, Some(3));
+        assert!(PatternInfo::new("").nullable);
+        assert!(PatternInfo::new("a*").nullable);
+    }
+
+    #[test]
+    fn literal_ok() {
+        assert_eq!(literal("\\[").as_deref(), Some("["));
+        assert_eq!(literal("true").as_deref(), Some("true"));
+        assert_eq!(literal("(a)b").as_deref(), Some("ab"));
+        assert_eq!(literal("").as_deref(), Some(""));
+        assert_eq!(literal("a|b"), None);
+        assert_eq!(literal("\\s*"), None);
+    }
+
+    #[test]
+    fn class_ok() {
+        assert_eq!(class("[a-cx]"), Some(vec![('a', 'c'), ('x', 'x')]));
+        assert_eq!(class("(?i)k"), Some(vec![('K', 'K'), ('k', 'k'), ('\u{212a}', '\u{212a}')]));
+        assert_eq!(class("[a-z]+"), None);
+        assert_eq!(class("a"), None);
+    }
+
+    #[test]
+    fn prefixes_ok() {
+        let regex = regex::Regex::new(&format!("^(?:{})$", prefixes("a+b|a").unwrap())).unwrap();
+        for text in ["", "a", "aaa", "aab"] {
+            assert!(regex.is_match(text), "{:?}", text);
+        }
+        for text in ["b", "aabb", "ac"] {
+            assert!(!regex.is_match(text), "{:?}", text);
+        }
+        let regex = regex::Regex::new(&format!("^(?:{})$", prefixes("\\b\"[^\"]*\"").unwrap())).unwrap();
+        assert!(regex.is_match("\"ab") && regex.is_match("\"ab\""));
+        assert!(!regex.is_match("\"ab\"c"));
+        assert_eq!(prefixes("("), None);
+    }
+
+    #[test]
+    fn pattern_info_error() {
+        let info = PatternInfo::new("(");
+        assert!(info.nullable);
+        assert!(info.can_start('('));
+        assert_eq!(info.max_len, None);
+    }
+}
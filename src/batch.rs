@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::{Failure, Parser, Success};
+
+impl Parser {
+    /// Parses every input on `threads` worker threads (0 = available parallelism).
+    /// Results are returned in the same order as `inputs`.
+    pub fn parse_batch<S: AsRef<str> + Sync>(&self, inputs: &[S], threads: usize) -> Vec<Result<Success, Failure>> {
+        let threads = match threads {
+            0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            n => n,
+        }.min(inputs.len()).max(1);
+        let next = AtomicUsize::new(0);
+        let slots: Vec<Mutex<Option<Result<Success, Failure>>>> = inputs.iter().map(|_| Mutex::new(None)).collect();
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= inputs.len() {
+                        break;
+                    }
+                    let result = self.parse(inputs[i].as_ref());
+                    *slots[i].lock().unwrap() = Some(result);
+                });
+            }
+        });
+        slots.into_iter().map(|slot| slot.into_inner().unwrap().unwrap()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Reply, Value};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn parser_is_send_sync() {
+        assert_send_sync::<Parser>();
+    }
+
+    #[test]
+    fn parse_batch_ok() {
+        let parser = Parser::regex("[0-9]+", 0).and(Parser::skip(",").and(Parser::regex("[0-9]+", 0)).repeat()).flat();
+        let inputs: Vec<String> = (0..50).map(|n| (0..n % 5 + 1).map(|m| m.to_string()).collect::<Vec<_>>().join(",")).collect();
+        let results = parser.parse_batch(&inputs, 4);
+        assert_eq!(results.len(), inputs.len());
+        for (input, result) in inputs.iter().zip(results.iter()) {
+            let expected: Vec<Value> = input.split(',').map(|n| Value::Some(n.to_string())).collect();
+            assert_eq!(result.value(), Some(Value::List(expected)));
+        }
+    }
+
+    #[test]
+    fn parse_batch_error() {
+        let parser = Parser::regex("x+", 0);
+        let results = parser.parse_batch(&["xx", "xy", "", "x"], 0);
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].err_position(), Some(1));
+        assert_eq!(results[2].err_position(), Some(0));
+        assert!(results[3].is_ok());
+
+        let results = parser.parse_batch::<&str>(&[], 2);
+        assert!(results.is_empty());
+    }
+}
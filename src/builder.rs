@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use crate::{BuildError, Failure, Parser};
+
+/// Collects terminal construction errors so a grammar can be assembled first and checked once.
+#[derive(Default)]
+pub struct GrammarBuilder {
+    errors: Vec<BuildError>,
+}
+
+impl GrammarBuilder {
+    pub fn new() -> Self {
+        GrammarBuilder::default()
+    }
+
+    /// Like `Parser::regex`, but a bad pattern is recorded and a parser that never matches is returned.
+    pub fn regex(&mut self, pattern: &str, group: isize) -> Parser {
+        match Parser::try_regex(pattern, group) {
+            Ok(parser) => parser,
+            Err(e) => {
+                self.errors.push(e);
+                let s = pattern.to_string();
+                Parser::from_func(Arc::new(move |_root:&Parser, _source:&str, position:i32| Err(Failure{position, expected:vec![s.clone()]})))
+            }
+        }
+    }
+
+    pub fn skip(&mut self, pattern: &str) -> Parser {
+        self.regex(pattern, -1)
+    }
+
+    pub fn errors(&self) -> &[BuildError] {
+        &self.errors
+    }
+
+    /// Returns `root` if every terminal was valid, otherwise the first error.
+    pub fn build(self, root: Parser) -> Result<Parser, BuildError> {
+        match self.errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(root),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Reply, Value};
+
+    #[test]
+    fn build_ok() {
+        let mut g = GrammarBuilder::new();
+        let key = g.regex("[a-z]+", 0);
+        let value = g.regex("[0-9]+", 0);
+        let parser = key.and(g.skip("=")).and(value);
+        let parser = g.build(parser).unwrap();
+        let result = parser.parse("abc=123");
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("abc".to_string()),
+                Value::Some("123".to_string()),
+            ])),
+        );
+    }
+
+    #[test]
+    fn build_error() {
+        let mut g = GrammarBuilder::new();
+        let key = g.regex("[a-z+", 0);
+        let value = g.regex("[0-9]+", 2);
+        let parser = key.and(g.skip("=")).and(value);
+        assert_eq!(g.errors().len(), 2);
+        assert_eq!(g.errors()[1], BuildError::Group{pattern: "[0-9]+".to_string(), group: 2, groups: 0});
+        let error = g.build(parser).err().unwrap();
+        assert_eq!(error.pattern(), "[a-z+");
+        match error {
+            BuildError::Regex{message, ..} => assert!(message.contains("unclosed character class")),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn try_regex_ok() {
+        let parser = Parser::try_regex("(a)(b)", 2).unwrap();
+        assert_eq!(parser.parse("ab").value(), Some(Value::Some("b".to_string())));
+        let parser = Parser::try_skip("a|b").unwrap();
+        assert_eq!(parser.parse("b").value(), Some(Value::None));
+    }
+
+    #[test]
+    fn try_regex_error() {
+        let error = Parser::try_regex("a)|(b", 0).err().unwrap();
+        assert_eq!(error.pattern(), "a)|(b");
+        assert!(error.to_string().starts_with("invalid pattern \"a)|(b\""));
+
+        let error = Parser::try_regex("(a)", 2).err().unwrap();
+        assert_eq!(error.to_string(), "invalid pattern \"(a)\": group 2 requested but the pattern has 1 group(s)");
+
+        assert!(Parser::try_skip("(").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::{Expr, Grammar, GrammarError};
+
+/// Writes one Rust function per grammar node, mirroring the combinators of `Parser` so
+/// values and failures come out exactly as the interpreter produces them.
+struct Writer<'g> {
+    idents: HashMap<&'g str, String>,
+    functions: String,
+    statics: String,
+    count: usize,
+    helpers: Vec<&'static str>,
+}
+
+/// Runtime helpers, each emitted only if some node needs it.
+const HELPERS: &[(&str, &str)] = &[
+    ("complete", r#"
+fn complete(result: Result<Success, Failure>, s: &str) -> Result<Success, Failure> {
+    let success = result?;
+    if success.position < s.len() as i32 {
+        return Err(Failure{position: success.position, expected: vec!["no length".to_string()]});
+    }
+    Ok(success)
+}
+"#),
+    ("terminal", r#"
+fn terminal(regex: &OnceLock<Regex>, pattern: &str, group: isize, s: &str, i: i32) -> Result<Success, Failure> {
+    let regex = regex.get_or_init(|| Regex::new(&format!("^({})", pattern)).unwrap());
+    match regex.captures(&s[i as usize..]) {
+        Some(captures) => {
+            let length = captures.get(0).unwrap().end() as i32;
+            let value = match group < 0 {
+                true => Value::None,
+                false => Value::Some(captures.get(group as usize + 1).map_or("", |m| m.as_str()).to_string()),
+            };
+            Ok(Success{position: i + length, value})
+        }
+        None => Err(Failure{position: i, expected: vec![pattern.to_string()]}),
+    }
+}
+"#),
+    ("join", r#"
+fn join(first: Success, second: Success) -> Success {
+    let mut values: Vec<Value> = vec![first.value, second.value].into_iter().filter(|value| *value != Value::None).collect();
+    let value = match values.len() {
+        0 => Value::None,
+        1 => values.pop().unwrap(),
+        _ => Value::List(values),
+    };
+    Success{position: second.position, value}
+}
+"#),
+    ("merge", r#"
+fn merge(first: Failure, second: Failure) -> Failure {
+    let mut expected = vec![];
+    if first.position >= second.position {
+        expected.extend(first.expected);
+    }
+    let mut position = first.position;
+    if first.position <= second.position {
+        expected.extend(second.expected);
+        position = second.position;
+    }
+    Failure{position, expected}
+}
+"#),
+    ("flat", r#"
+fn flat(mut success: Success) -> Success {
+    if let Value::List(items) = success.value {
+        let mut values = vec![];
+        for item in items {
+            match item {
+                Value::List(inner) => values.extend(inner),
+                Value::None => (),
+                item => values.push(item),
+            }
+        }
+        success.value = if values.is_empty() {Value::None} else {Value::List(values)};
+    }
+    success
+}
+"#),
+];
+
+/// A Rust identifier for a rule name: ASCII alphanumerics, with anything else as `_`.
+fn ident(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() {c.to_ascii_lowercase()} else {'_'}).collect()
+}
+
+impl<'g> Writer<'g> {
+    fn helper(&mut self, name: &'static str) {
+        if !self.helpers.contains(&name) {
+            self.helpers.push(name);
+        }
+    }
+
+    /// Adds a function with `body` and returns its name.
+    fn function(&mut self, body: String) -> String {
+        let name = format!("e{}", self.count);
+        self.count += 1;
+        let _ = write!(self.functions, "\nfn {}(s: &str, i: i32) -> Result<Success, Failure> {{\n{}}}\n", name, body);
+        name
+    }
+
+    fn terminal(&mut self, pattern: &str, group: isize) -> String {
+        self.helper("terminal");
+        let regex = format!("RE{}", self.count);
+        let _ = writeln!(self.statics, "static {}: OnceLock<Regex> = OnceLock::new();", regex);
+        self.function(format!("    terminal(&{}, {:?}, {}, s, i)\n", regex, pattern, group))
+    }
+
+    /// The name of a function parsing `expr`.
+    fn expr(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Regex{pattern, group, ..} => self.terminal(pattern, *group),
+            Expr::Literal{text, skip, ..} => self.terminal(&regex::escape(text), if *skip {-1} else {0}),
+            Expr::Ref(name) => format!("rule_{}", self.idents[name.as_str()]),
+            Expr::Seq(exprs) => self.chain(exprs, |first, second| {
+                format!("    let first = {}(s, i)?;\n    let second = {}(s, first.position)?;\n    Ok(join(first, second))\n", first, second)
+            }),
+            Expr::Alt(exprs) => self.chain(exprs, |first, second| {
+                format!("    match {}(s, i) {{\n        Err(first) => match {}(s, i) {{\n            Err(second) => Err(merge(first, second)),\n            ok => ok,\n        }},\n        ok => ok,\n    }}\n", first, second)
+            }),
+            Expr::Repeat(expr) => {
+                let item = self.expr(expr);
+                self.function(format!("    let mut values = vec![];\n    let mut i = i;\n    while let Ok(success) = {}(s, i) {{\n        i = success.position;\n        if success.value != Value::None {{\n            values.push(success.value);\n        }}\n    }}\n    Ok(Success{{position: i, value: Value::List(values)}})\n", item))
+            }
+            Expr::Optional(expr) => {
+                let inner = self.expr(expr);
+                self.function(format!("    match {}(s, i) {{\n        Err(_) => Ok(Success{{position: i, value: Value::None}}),\n        ok => ok,\n    }}\n", inner))
+            }
+            Expr::List(expr) => {
+                let inner = self.expr(expr);
+                self.function(format!("    let mut success = {}(s, i)?;\n    if success.value != Value::None {{\n        success.value = Value::List(vec![success.value]);\n    }}\n    Ok(success)\n", inner))
+            }
+            Expr::Flat(expr) => {
+                self.helper("flat");
+                let inner = self.expr(expr);
+                self.function(format!("    {}(s, i).map(flat)\n", inner))
+            }
+            Expr::Memo(expr) => self.expr(expr),
+        }
+    }
+
+    /// Folds `exprs` from the left into binary nodes, as `Parser::and` and `Parser::or` chains do.
+    fn chain(&mut self, exprs: &[Expr], body: fn(&str, &str) -> String) -> String {
+        self.helper(if body("a", "b").contains("join") {"join"} else {"merge"});
+        let mut first = self.expr(&exprs[0]);
+        for expr in &exprs[1..] {
+            let second = self.expr(expr);
+            first = self.function(body(&first, &second));
+        }
+        first
+    }
+}
+
+impl Grammar {
+    /// Rust source for a parser equivalent to this grammar's: `pub fn parse_RULE(s)` for
+    /// every rule, returning the same `Success` and `Failure` values as `Parser::parse`.
+    /// The code needs the `regex` crate and takes `Value`, `Success` and `Failure` from
+    /// the crate at path `runtime`, usually `pcc2`. Meant for `build.rs` scripts.
+    pub fn to_rust(&self, runtime: &str) -> Result<String, GrammarError> {
+        self.compile()?;
+        let mut idents = HashMap::new();
+        let mut taken: Vec<String> = vec![];
+        for rule in self.rules.iter() {
+            let base = ident(&rule.name);
+            let mut name = base.clone();
+            let mut suffix = taken.len();
+            while taken.contains(&name) {
+                name = format!("{}_{}", base, suffix);
+                suffix += 1;
+            }
+            taken.push(name.clone());
+            idents.insert(rule.name.as_str(), name);
+        }
+        let mut writer = Writer{idents, functions: String::new(), statics: String::new(), count: 0, helpers: vec!["complete"]};
+        let mut rules = String::new();
+        let mut entries = String::new();
+        for rule in self.rules.iter() {
+            let name = writer.idents[rule.name.as_str()].clone();
+            let body = writer.expr(&rule.expr);
+            let _ = write!(entries, "\n/// Parses all of `s` as rule `{}`.\npub fn parse_{}(s: &str) -> Result<Success, Failure> {{\n    complete(rule_{}(s, 0), s)\n}}\n", rule.name, name, name);
+            let _ = write!(rules, "\nfn rule_{}(s: &str, i: i32) -> Result<Success, Failure> {{\n    {}(s, i)\n}}\n", name, body);
+        }
+        let mut out = String::from("// Generated by pcc2 from a grammar; do not edit.\n\n");
+        if !writer.statics.is_empty() {
+            out.push_str("use std::sync::OnceLock;\n\nuse regex::Regex;\n\n");
+        }
+        let _ = writeln!(out, "use {}::{{Failure, Success, Value}};", runtime);
+        out.push_str(&entries);
+        out.push_str(&rules);
+        out.push_str(&writer.functions);
+        if !writer.statics.is_empty() {
+            out.push('\n');
+            out.push_str(&writer.statics);
+        }
+        for (name, helper) in HELPERS {
+            if writer.helpers.contains(name) {
+                out.push_str(helper);
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod generated {
+    include!("codegen_fixture.rs");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAMMAR: &str = r#"
+        value    = ~/\s*/ (object | array | string | number | "true" | "null") ;
+        object   = ~"{" list(flat(memo(member) (~"," member)*)?) ~/\s*\}/ ;
+        member   = ~/\s*/ string ~/\s*:/ value ;
+        array    = ~"[" flat(list(value) (~"," value)*)? ~/\s*\]/ ;
+        string   = /"((?:[^"\\]|\\.)*)"/1 ;
+        number   = /-?[0-9]+(\.[0-9]+)?/ ;
+        key-name = string ;
+    "#;
+
+    #[test]
+    fn to_rust_ok() {
+        let grammar = Grammar::parse(GRAMMAR).unwrap();
+        // PCC2_REGENERATE=1 rewrites the fixture after a deliberate change to the output.
+        if std::env::var("PCC2_REGENERATE").is_ok() {
+            std::fs::write("src/codegen_fixture.rs", grammar.to_rust("crate").unwrap()).unwrap();
+        }
+        assert_eq!(grammar.to_rust("crate").unwrap(), include_str!("codegen_fixture.rs"), "rerun with PCC2_REGENERATE=1");
+        let parser = grammar.parser(None).unwrap();
+        for input in [
+            r#"{"a": [1, -2.5, "x\"y"], "b": {}, "c": [true, null, []]}"#,
+            "[]", " 7", r#"{"k": {"j": ["deep", {"i": 0}]}}"#,
+            "", "[1,", "{\"a\" 1}", "[1] x", "tru", "{\"a\": [1, }",
+        ] {
+            assert_eq!(generated::parse_value(input), parser.parse(input), "{:?}", input);
+        }
+        let alias = grammar.parser(Some("key-name")).unwrap();
+        assert_eq!(generated::parse_key_name("\"q\""), alias.parse("\"q\""));
+        assert_eq!(generated::parse_key_name("q"), alias.parse("q"));
+    }
+
+    #[test]
+    fn to_rust_error() {
+        let error = Grammar::parse("a = b ;").unwrap().to_rust("pcc2").unwrap_err();
+        assert_eq!(error.to_string(), "line 1, column 1: undefined rule \"b\"");
+        let source = Grammar::parse("a-b = \"x\" ;\na_b = ~/y/ a-b ;").unwrap().to_rust("pcc2").unwrap();
+        assert!(source.contains("pub fn parse_a_b(") && source.contains("pub fn parse_a_b_1("));
+        let source = Grammar::parse("a-b = \"x\" ;\na_b_2 = \"y\" ;\na_b = \"z\" ;").unwrap().to_rust("pcc2").unwrap();
+        assert_eq!(source.matches("pub fn parse_a_b_2(").count(), 1);
+        assert!(source.contains("pub fn parse_a_b_3(") && source.contains("fn rule_a_b_3("));
+        assert!(source.contains("use pcc2::{Failure, Success, Value};"));
+        assert!(!source.contains("fn flat("));
+    }
+}
@@ -0,0 +1,381 @@
+// Generated by pcc2 from a grammar; do not edit.
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::{Failure, Success, Value};
+
+/// Parses all of `s` as rule `value`.
+pub fn parse_value(s: &str) -> Result<Success, Failure> {
+    complete(rule_value(s, 0), s)
+}
+
+/// Parses all of `s` as rule `object`.
+pub fn parse_object(s: &str) -> Result<Success, Failure> {
+    complete(rule_object(s, 0), s)
+}
+
+/// Parses all of `s` as rule `member`.
+pub fn parse_member(s: &str) -> Result<Success, Failure> {
+    complete(rule_member(s, 0), s)
+}
+
+/// Parses all of `s` as rule `array`.
+pub fn parse_array(s: &str) -> Result<Success, Failure> {
+    complete(rule_array(s, 0), s)
+}
+
+/// Parses all of `s` as rule `string`.
+pub fn parse_string(s: &str) -> Result<Success, Failure> {
+    complete(rule_string(s, 0), s)
+}
+
+/// Parses all of `s` as rule `number`.
+pub fn parse_number(s: &str) -> Result<Success, Failure> {
+    complete(rule_number(s, 0), s)
+}
+
+/// Parses all of `s` as rule `key-name`.
+pub fn parse_key_name(s: &str) -> Result<Success, Failure> {
+    complete(rule_key_name(s, 0), s)
+}
+
+fn rule_value(s: &str, i: i32) -> Result<Success, Failure> {
+    e8(s, i)
+}
+
+fn rule_object(s: &str, i: i32) -> Result<Success, Failure> {
+    e19(s, i)
+}
+
+fn rule_member(s: &str, i: i32) -> Result<Success, Failure> {
+    e24(s, i)
+}
+
+fn rule_array(s: &str, i: i32) -> Result<Success, Failure> {
+    e35(s, i)
+}
+
+fn rule_string(s: &str, i: i32) -> Result<Success, Failure> {
+    e36(s, i)
+}
+
+fn rule_number(s: &str, i: i32) -> Result<Success, Failure> {
+    e37(s, i)
+}
+
+fn rule_key_name(s: &str, i: i32) -> Result<Success, Failure> {
+    rule_string(s, i)
+}
+
+fn e0(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE0, "\\s*", -1, s, i)
+}
+
+fn e1(s: &str, i: i32) -> Result<Success, Failure> {
+    match rule_object(s, i) {
+        Err(first) => match rule_array(s, i) {
+            Err(second) => Err(merge(first, second)),
+            ok => ok,
+        },
+        ok => ok,
+    }
+}
+
+fn e2(s: &str, i: i32) -> Result<Success, Failure> {
+    match e1(s, i) {
+        Err(first) => match rule_string(s, i) {
+            Err(second) => Err(merge(first, second)),
+            ok => ok,
+        },
+        ok => ok,
+    }
+}
+
+fn e3(s: &str, i: i32) -> Result<Success, Failure> {
+    match e2(s, i) {
+        Err(first) => match rule_number(s, i) {
+            Err(second) => Err(merge(first, second)),
+            ok => ok,
+        },
+        ok => ok,
+    }
+}
+
+fn e4(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE4, "true", 0, s, i)
+}
+
+fn e5(s: &str, i: i32) -> Result<Success, Failure> {
+    match e3(s, i) {
+        Err(first) => match e4(s, i) {
+            Err(second) => Err(merge(first, second)),
+            ok => ok,
+        },
+        ok => ok,
+    }
+}
+
+fn e6(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE6, "null", 0, s, i)
+}
+
+fn e7(s: &str, i: i32) -> Result<Success, Failure> {
+    match e5(s, i) {
+        Err(first) => match e6(s, i) {
+            Err(second) => Err(merge(first, second)),
+            ok => ok,
+        },
+        ok => ok,
+    }
+}
+
+fn e8(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e0(s, i)?;
+    let second = e7(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e9(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE9, "\\{", -1, s, i)
+}
+
+fn e10(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE10, ",", -1, s, i)
+}
+
+fn e11(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e10(s, i)?;
+    let second = rule_member(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e12(s: &str, i: i32) -> Result<Success, Failure> {
+    let mut values = vec![];
+    let mut i = i;
+    while let Ok(success) = e11(s, i) {
+        i = success.position;
+        if success.value != Value::None {
+            values.push(success.value);
+        }
+    }
+    Ok(Success{position: i, value: Value::List(values)})
+}
+
+fn e13(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = rule_member(s, i)?;
+    let second = e12(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e14(s: &str, i: i32) -> Result<Success, Failure> {
+    e13(s, i).map(flat)
+}
+
+fn e15(s: &str, i: i32) -> Result<Success, Failure> {
+    match e14(s, i) {
+        Err(_) => Ok(Success{position: i, value: Value::None}),
+        ok => ok,
+    }
+}
+
+fn e16(s: &str, i: i32) -> Result<Success, Failure> {
+    let mut success = e15(s, i)?;
+    if success.value != Value::None {
+        success.value = Value::List(vec![success.value]);
+    }
+    Ok(success)
+}
+
+fn e17(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e9(s, i)?;
+    let second = e16(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e18(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE18, "\\s*\\}", -1, s, i)
+}
+
+fn e19(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e17(s, i)?;
+    let second = e18(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e20(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE20, "\\s*", -1, s, i)
+}
+
+fn e21(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e20(s, i)?;
+    let second = rule_string(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e22(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE22, "\\s*:", -1, s, i)
+}
+
+fn e23(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e21(s, i)?;
+    let second = e22(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e24(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e23(s, i)?;
+    let second = rule_value(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e25(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE25, "\\[", -1, s, i)
+}
+
+fn e26(s: &str, i: i32) -> Result<Success, Failure> {
+    let mut success = rule_value(s, i)?;
+    if success.value != Value::None {
+        success.value = Value::List(vec![success.value]);
+    }
+    Ok(success)
+}
+
+fn e27(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE27, ",", -1, s, i)
+}
+
+fn e28(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e27(s, i)?;
+    let second = rule_value(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e29(s: &str, i: i32) -> Result<Success, Failure> {
+    let mut values = vec![];
+    let mut i = i;
+    while let Ok(success) = e28(s, i) {
+        i = success.position;
+        if success.value != Value::None {
+            values.push(success.value);
+        }
+    }
+    Ok(Success{position: i, value: Value::List(values)})
+}
+
+fn e30(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e26(s, i)?;
+    let second = e29(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e31(s: &str, i: i32) -> Result<Success, Failure> {
+    e30(s, i).map(flat)
+}
+
+fn e32(s: &str, i: i32) -> Result<Success, Failure> {
+    match e31(s, i) {
+        Err(_) => Ok(Success{position: i, value: Value::None}),
+        ok => ok,
+    }
+}
+
+fn e33(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e25(s, i)?;
+    let second = e32(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e34(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE34, "\\s*\\]", -1, s, i)
+}
+
+fn e35(s: &str, i: i32) -> Result<Success, Failure> {
+    let first = e33(s, i)?;
+    let second = e34(s, first.position)?;
+    Ok(join(first, second))
+}
+
+fn e36(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE36, "\"((?:[^\"\\\\]|\\\\.)*)\"", 1, s, i)
+}
+
+fn e37(s: &str, i: i32) -> Result<Success, Failure> {
+    terminal(&RE37, "-?[0-9]+(\\.[0-9]+)?", 0, s, i)
+}
+
+static RE0: OnceLock<Regex> = OnceLock::new();
+static RE4: OnceLock<Regex> = OnceLock::new();
+static RE6: OnceLock<Regex> = OnceLock::new();
+static RE9: OnceLock<Regex> = OnceLock::new();
+static RE10: OnceLock<Regex> = OnceLock::new();
+static RE18: OnceLock<Regex> = OnceLock::new();
+static RE20: OnceLock<Regex> = OnceLock::new();
+static RE22: OnceLock<Regex> = OnceLock::new();
+static RE25: OnceLock<Regex> = OnceLock::new();
+static RE27: OnceLock<Regex> = OnceLock::new();
+static RE34: OnceLock<Regex> = OnceLock::new();
+static RE36: OnceLock<Regex> = OnceLock::new();
+static RE37: OnceLock<Regex> = OnceLock::new();
+
+fn complete(result: Result<Success, Failure>, s: &str) -> Result<Success, Failure> {
+    let success = result?;
+    if success.position < s.len() as i32 {
+        return Err(Failure{position: success.position, expected: vec!["no length".to_string()]});
+    }
+    Ok(success)
+}
+
+fn terminal(regex: &OnceLock<Regex>, pattern: &str, group: isize, s: &str, i: i32) -> Result<Success, Failure> {
+    let regex = regex.get_or_init(|| Regex::new(&format!("^({})", pattern)).unwrap());
+    match regex.captures(&s[i as usize..]) {
+        Some(captures) => {
+            let length = captures.get(0).unwrap().end() as i32;
+            let value = match group < 0 {
+                true => Value::None,
+                false => Value::Some(captures.get(group as usize + 1).map_or("", |m| m.as_str()).to_string()),
+            };
+            Ok(Success{position: i + length, value})
+        }
+        None => Err(Failure{position: i, expected: vec![pattern.to_string()]}),
+    }
+}
+
+fn join(first: Success, second: Success) -> Success {
+    let mut values: Vec<Value> = vec![first.value, second.value].into_iter().filter(|value| *value != Value::None).collect();
+    let value = match values.len() {
+        0 => Value::None,
+        1 => values.pop().unwrap(),
+        _ => Value::List(values),
+    };
+    Success{position: second.position, value}
+}
+
+fn merge(first: Failure, second: Failure) -> Failure {
+    let mut expected = vec![];
+    if first.position >= second.position {
+        expected.extend(first.expected);
+    }
+    let mut position = first.position;
+    if first.position <= second.position {
+        expected.extend(second.expected);
+        position = second.position;
+    }
+    Failure{position, expected}
+}
+
+fn flat(mut success: Success) -> Success {
+    if let Value::List(items) = success.value {
+        let mut values = vec![];
+        for item in items {
+            match item {
+                Value::List(inner) => values.extend(inner),
+                Value::None => (),
+                item => values.push(item),
+            }
+        }
+        success.value = if values.is_empty() {Value::None} else {Value::List(values)};
+    }
+    success
+}
@@ -0,0 +1,347 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::instrument::{self, Wrap};
+use crate::json::write_string;
+use crate::{key, Failure, Node, Parser, Success};
+
+/// An `or` chain and how often each alternative matched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChoiceCoverage {
+    /// The rule the chain appears in, if known.
+    pub rule: Option<String>,
+    pub alternatives: Vec<(String, u64)>,
+}
+
+/// How often a `repeat` matched zero, one and more items.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepeatCoverage {
+    pub rule: Option<String>,
+    pub expr: String,
+    pub zero: u64,
+    pub one: u64,
+    pub many: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Coverage {
+    pub choices: Vec<ChoiceCoverage>,
+    pub repeats: Vec<RepeatCoverage>,
+    /// Terminals and how often they matched.
+    pub terminals: Vec<(String, u64)>,
+}
+
+#[derive(Default)]
+struct Registry {
+    coverage: Coverage,
+    /// Registrations per choice; a chain absorbed into a longer one drops to zero.
+    refs: Vec<usize>,
+    choice_ids: HashMap<(Option<String>, String), usize>,
+    repeat_ids: HashMap<(Option<String>, String), usize>,
+    terminal_ids: HashMap<String, usize>,
+}
+
+impl Registry {
+    fn choice(&mut self, rule: Option<String>, alternatives: Vec<String>) -> usize {
+        let key = (rule.clone(), alternatives.join(" | "));
+        let (coverage, refs) = (&mut self.coverage, &mut self.refs);
+        let id = *self.choice_ids.entry(key).or_insert_with(|| {
+            coverage.choices.push(ChoiceCoverage{rule, alternatives: alternatives.into_iter().map(|a| (a, 0)).collect()});
+            refs.push(0);
+            refs.len() - 1
+        });
+        self.refs[id] += 1;
+        id
+    }
+
+    fn repeat(&mut self, rule: Option<String>, expr: String) -> usize {
+        let repeats = &mut self.coverage.repeats;
+        *self.repeat_ids.entry((rule.clone(), expr.clone())).or_insert_with(|| {
+            repeats.push(RepeatCoverage{rule, expr, zero: 0, one: 0, many: 0});
+            repeats.len() - 1
+        })
+    }
+
+    fn terminal(&mut self, name: String) -> usize {
+        let terminals = &mut self.coverage.terminals;
+        *self.terminal_ids.entry(name.clone()).or_insert_with(|| {
+            terminals.push((name, 0));
+            terminals.len() - 1
+        })
+    }
+}
+
+thread_local! {
+    /// Items matched so far by each active `repeat` of this thread.
+    static ITERATIONS: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The rule each statically reachable node belongs to.
+fn rules(parser: &Parser, rule: &Option<String>, found: &mut HashMap<usize, Option<String>>) {
+    if found.contains_key(&key(parser)) {
+        return;
+    }
+    found.insert(key(parser), rule.clone());
+    match &*parser.node {
+        Node::Rule(name, slot) => {
+            if let Some(body) = slot.get() {
+                rules(&body, &Some(name.clone()), found);
+            }
+        }
+        Node::And(a, b) | Node::Or(a, b) => {
+            rules(a, rule, found);
+            rules(b, rule, found);
+        }
+        Node::Repeat(p) | Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => rules(p, rule, found),
+        Node::Regex{..} | Node::Recursive(_) | Node::Root | Node::Custom => (),
+    }
+}
+
+fn on_success(parser: &Parser, record: impl Fn() + Send + Sync + 'static) -> Parser {
+    let inner = parser.func.clone();
+    Parser{node: parser.node.clone(), func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+        let result = inner(root, s, i);
+        if result.is_ok() {
+            record();
+        }
+        result
+    })}
+}
+
+/// A copy of a parser that records which alternatives, repeat counts and terminals
+/// its parses exercise.
+pub struct CoverageRecorder {
+    parser: Parser,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Parser {
+    pub fn coverage(&self) -> CoverageRecorder {
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        let mut found = HashMap::new();
+        rules(self, &self.name().map(str::to_string), &mut found);
+        let chains = Mutex::new(HashMap::<usize, (usize, Vec<Parser>)>::new());
+        let shared = registry.clone();
+        let wrap: Wrap = Arc::new(move |original: &Parser, rebuilt: Parser| {
+            let rule = found.get(&key(original)).cloned().flatten();
+            let registry = shared.clone();
+            match &*rebuilt.node {
+                Node::Or(left, right) => {
+                    let mut chains = chains.lock().unwrap();
+                    let mut alternatives = match chains.get(&key(left)) {
+                        Some((id, alternatives)) => {
+                            registry.lock().unwrap().refs[*id] -= 1;
+                            alternatives.clone()
+                        }
+                        None => vec![left.clone()],
+                    };
+                    alternatives.push(right.clone());
+                    let names = alternatives.iter().map(instrument::describe).collect();
+                    let id = registry.lock().unwrap().choice(rule, names);
+                    let parser = alternatives.iter().enumerate().map(|(n, alternative)| {
+                        let registry = registry.clone();
+                        on_success(alternative, move || registry.lock().unwrap().coverage.choices[id].alternatives[n].1 += 1)
+                    }).reduce(Parser::or).unwrap();
+                    chains.insert(key(&parser), (id, alternatives));
+                    parser
+                }
+                Node::Repeat(item) => {
+                    let id = registry.lock().unwrap().repeat(rule, instrument::describe(&rebuilt));
+                    let repeat = on_success(item, || ITERATIONS.with(|n| *n.borrow_mut().last_mut().unwrap() += 1)).repeat();
+                    let inner = repeat.func.clone();
+                    Parser{node: repeat.node, func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+                        ITERATIONS.with(|n| n.borrow_mut().push(0));
+                        let result = inner(root, s, i);
+                        let count = ITERATIONS.with(|n| n.borrow_mut().pop().unwrap());
+                        let mut registry = registry.lock().unwrap();
+                        let repeat = &mut registry.coverage.repeats[id];
+                        match count {
+                            0 => repeat.zero += 1,
+                            1 => repeat.one += 1,
+                            _ => repeat.many += 1,
+                        }
+                        result
+                    })}
+                }
+                Node::Regex{..} => {
+                    let id = registry.lock().unwrap().terminal(instrument::label(original).unwrap());
+                    on_success(&rebuilt, move || registry.lock().unwrap().coverage.terminals[id].1 += 1)
+                }
+                _ => rebuilt,
+            }
+        });
+        CoverageRecorder{parser: instrument::rebuild(self, &wrap), registry}
+    }
+}
+
+impl CoverageRecorder {
+    pub fn parse(&self, s: &str) -> Result<Success, Failure> {
+        self.parser.parse(s)
+    }
+
+    /// What the parses so far covered.
+    pub fn coverage(&self) -> Coverage {
+        let registry = self.registry.lock().unwrap();
+        let mut coverage = registry.coverage.clone();
+        let mut refs = registry.refs.iter();
+        coverage.choices.retain(|_| *refs.next().unwrap() > 0);
+        coverage
+    }
+}
+
+fn place(rule: &Option<String>) -> String {
+    match rule {
+        Some(rule) => format!("{}: ", rule),
+        None => String::new(),
+    }
+}
+
+impl Coverage {
+    /// Alternatives that never matched, repeat counts never seen and terminals never matched.
+    pub fn uncovered(&self) -> Vec<String> {
+        let mut uncovered = vec![];
+        for choice in self.choices.iter() {
+            for (n, (alternative, count)) in choice.alternatives.iter().enumerate() {
+                if *count == 0 {
+                    uncovered.push(format!("{}alternative {} `{}`", place(&choice.rule), n + 1, alternative));
+                }
+            }
+        }
+        for repeat in self.repeats.iter() {
+            for (name, count) in [("zero", repeat.zero), ("one", repeat.one), ("many", repeat.many)] {
+                if count == 0 {
+                    uncovered.push(format!("{}`{}` with {} items", place(&repeat.rule), repeat.expr, name));
+                }
+            }
+        }
+        for (name, count) in self.terminals.iter() {
+            if *count == 0 {
+                uncovered.push(format!("terminal `{}`", name));
+            }
+        }
+        uncovered
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        let string = |out: &mut String, s: &Option<String>| match s {
+            Some(s) => write_string(out, s),
+            None => out.push_str("null"),
+        };
+        out.push_str("{\"choices\":[");
+        for (n, choice) in self.choices.iter().enumerate() {
+            out.push_str(if n > 0 {",{\"rule\":"} else {"{\"rule\":"});
+            string(&mut out, &choice.rule);
+            out.push_str(",\"alternatives\":[");
+            for (m, (alternative, count)) in choice.alternatives.iter().enumerate() {
+                out.push_str(if m > 0 {",{\"expr\":"} else {"{\"expr\":"});
+                write_string(&mut out, alternative);
+                out.push_str(&format!(",\"count\":{}}}", count));
+            }
+            out.push_str("]}");
+        }
+        out.push_str("],\"repeats\":[");
+        for (n, repeat) in self.repeats.iter().enumerate() {
+            out.push_str(if n > 0 {",{\"rule\":"} else {"{\"rule\":"});
+            string(&mut out, &repeat.rule);
+            out.push_str(",\"expr\":");
+            write_string(&mut out, &repeat.expr);
+            out.push_str(&format!(",\"zero\":{},\"one\":{},\"many\":{}}}", repeat.zero, repeat.one, repeat.many));
+        }
+        out.push_str("],\"terminals\":[");
+        for (n, (name, count)) in self.terminals.iter().enumerate() {
+            out.push_str(if n > 0 {",{\"name\":"} else {"{\"name\":"});
+            write_string(&mut out, name);
+            out.push_str(&format!(",\"count\":{}}}", count));
+        }
+        out.push_str("],\"uncovered\":[");
+        for (n, item) in self.uncovered().iter().enumerate() {
+            if n > 0 {
+                out.push(',');
+            }
+            write_string(&mut out, item);
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for choice in self.choices.iter() {
+            writeln!(f, "{}", format!("choice {}", choice.rule.as_deref().unwrap_or("")).trim_end())?;
+            for (alternative, count) in choice.alternatives.iter() {
+                writeln!(f, "  {:>6}  {}", count, alternative)?;
+            }
+        }
+        for repeat in self.repeats.iter() {
+            writeln!(f, "repeat {}{}", place(&repeat.rule), repeat.expr)?;
+            writeln!(f, "  {:>6}  zero\n  {:>6}  one\n  {:>6}  many", repeat.zero, repeat.one, repeat.many)?;
+        }
+        if !self.terminals.is_empty() {
+            writeln!(f, "terminals")?;
+        }
+        for (name, count) in self.terminals.iter() {
+            writeln!(f, "  {:>6}  {}", count, name)?;
+        }
+        let uncovered = self.uncovered();
+        if !uncovered.is_empty() {
+            writeln!(f, "uncovered")?;
+        }
+        uncovered.iter().try_for_each(|item| writeln!(f, "  {}", item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn recorder() -> CoverageRecorder {
+        let grammar = Grammar::parse("value = number | string | array ; array = ~\"[\" flat(value (~\",\" value)*)? ~\"]\" ; number = /[0-9]+/ ; string = /'[a-z]*'/ ;").unwrap();
+        grammar.parser(None).unwrap().coverage()
+    }
+
+    #[test]
+    fn coverage_ok() {
+        let recorder = recorder();
+        for input in ["1", "[2,3]", "[4]"] {
+            assert!(recorder.parse(input).is_ok());
+        }
+        let coverage = recorder.coverage();
+        assert_eq!(coverage.choices.len(), 2);
+        assert_eq!(coverage.choices[1], ChoiceCoverage{
+            rule: Some("value".to_string()),
+            alternatives: vec![("number".to_string(), 4), ("string".to_string(), 0), ("array".to_string(), 2)],
+        });
+        assert_eq!(coverage.choices[0].alternatives, vec![("flat(value (~/,/ value)*)".to_string(), 2), ("~//".to_string(), 0)]);
+        assert_eq!(coverage.repeats, vec![RepeatCoverage{rule: Some("array".to_string()), expr: "(~/,/ value)*".to_string(), zero: 1, one: 1, many: 0}]);
+        assert_eq!(coverage.uncovered(), vec![
+            "array: alternative 2 `~//`".to_string(),
+            "value: alternative 2 `string`".to_string(),
+            "array: `(~/,/ value)*` with many items".to_string(),
+            "terminal `/'[a-z]*'/`".to_string(),
+            "terminal `~//`".to_string(),
+        ]);
+        let text = coverage.to_string();
+        assert!(text.contains("choice value\n       4  number\n       0  string\n       2  array\n"));
+        assert!(text.ends_with("uncovered\n  array: alternative 2 `~//`\n  value: alternative 2 `string`\n  array: `(~/,/ value)*` with many items\n  terminal `/'[a-z]*'/`\n  terminal `~//`\n"));
+        let json = coverage.to_json();
+        assert!(json.contains("{\"rule\":\"value\",\"alternatives\":[{\"expr\":\"number\",\"count\":4},"));
+        assert!(json.contains("\"repeats\":[{\"rule\":\"array\",\"expr\":\"(~/,/ value)*\",\"zero\":1,\"one\":1,\"many\":0}]"));
+    }
+
+    #[test]
+    fn coverage_error() {
+        let recorder = recorder();
+        assert!(recorder.parse("[x]").is_err());
+        let coverage = recorder.coverage();
+        assert_eq!(coverage.choices[0].alternatives.iter().map(|a| a.1).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(coverage.choices[1].alternatives.iter().map(|a| a.1).sum::<u64>(), 0);
+        assert_eq!(coverage.uncovered().len(), 11);
+        assert_eq!(Coverage::default().to_json(), "{\"choices\":[],\"repeats\":[],\"terminals\":[],\"uncovered\":[]}");
+        assert_eq!(Coverage::default().to_string(), "");
+    }
+}
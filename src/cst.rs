@@ -0,0 +1,250 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::instrument::{self, Wrap};
+use crate::{key, Failure, Node, Parser, ParserFunc, Success, Value};
+
+/// A consumed span, with the captured text for value-producing terminals.
+#[derive(Clone)]
+struct Event {
+    span: Range<usize>,
+    value: Option<String>,
+}
+
+/// A result of a `memo` parser with the spans it recorded.
+#[derive(Clone)]
+struct Memoized {
+    result: Result<Success, Failure>,
+    events: Vec<Event>,
+}
+
+struct Recording {
+    events: Vec<Event>,
+    /// By node and position.
+    memo: HashMap<(usize, i32), Memoized>,
+}
+
+thread_local! {
+    static RECORDING: RefCell<Recording> = RefCell::new(Recording{events: vec![], memo: HashMap::new()});
+}
+
+fn mark() -> usize {
+    RECORDING.with(|recording| recording.borrow().events.len())
+}
+
+/// Drops the spans recorded by an attempt that failed after `mark`.
+fn rewind(mark: usize) {
+    RECORDING.with(|recording| recording.borrow_mut().events.truncate(mark))
+}
+
+fn record(start: usize, end: usize, value: Option<&str>) {
+    if start < end || value.is_some() {
+        RECORDING.with(|recording| recording.borrow_mut().events.push(Event{span: start..end, value: value.map(|v| v.to_string())}))
+    }
+}
+
+/// Records the spans terminals and custom parsers consume, drops those of failed attempts
+/// and replays those of memoized results.
+fn tracked(original: &Parser, rebuilt: Parser) -> Parser {
+    let inner = rebuilt.func.clone();
+    let func: ParserFunc = match &*original.node {
+        Node::Regex{..} | Node::Custom => Arc::new(move |root: &Parser, s: &str, i: i32| {
+            let result = inner(root, s, i);
+            if let Ok(success) = &result {
+                let value = match &success.value {
+                    Value::Some(text) => Some(text.as_str()),
+                    _ => None,
+                };
+                record(i as usize, success.position as usize, value);
+            }
+            result
+        }),
+        Node::Memo(_) => {
+            let id = key(original);
+            Arc::new(move |root: &Parser, s: &str, i: i32| {
+                let cached = RECORDING.with(|recording| {
+                    let mut recording = recording.borrow_mut();
+                    let memoized = recording.memo.get(&(id, i))?.clone();
+                    recording.events.extend(memoized.events);
+                    Some(memoized.result)
+                });
+                if let Some(result) = cached {
+                    return result;
+                }
+                let mark = mark();
+                let result = inner(root, s, i);
+                RECORDING.with(|recording| {
+                    let mut recording = recording.borrow_mut();
+                    if result.is_err() {
+                        recording.events.truncate(mark);
+                    }
+                    let events = recording.events[mark..].to_vec();
+                    recording.memo.insert((id, i), Memoized{result: result.clone(), events});
+                });
+                result
+            })
+        }
+        _ => Arc::new(move |root: &Parser, s: &str, i: i32| {
+            let mark = mark();
+            let result = inner(root, s, i);
+            if result.is_err() {
+                rewind(mark);
+            }
+            result
+        }),
+    };
+    Parser{node: rebuilt.node, func}
+}
+
+/// Text consumed without producing a value: whitespace, comments, punctuation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia {
+    pub span: Range<usize>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cst {
+    None,
+    /// A value-producing terminal. `text` is the whole match, `value` the captured group.
+    Token { leading: Vec<Trivia>, span: Range<usize>, text: String, value: String },
+    List(Vec<Cst>),
+}
+
+/// A lossless parse tree: printing it reproduces the source byte for byte.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstTree {
+    pub root: Cst,
+    pub trailing: Vec<Trivia>,
+}
+
+impl Cst {
+    pub fn value(&self) -> Value {
+        match self {
+            Cst::None => Value::None,
+            Cst::Token{value, ..} => Value::Some(value.clone()),
+            Cst::List(children) => Value::List(children.iter().map(|child| child.value()).collect()),
+        }
+    }
+
+    fn build(value: &Value, source: &str, events: &mut std::iter::Peekable<std::vec::IntoIter<Event>>) -> Cst {
+        match value {
+            Value::None => Cst::None,
+            Value::List(values) => Cst::List(values.iter().map(|value| Cst::build(value, source, events)).collect()),
+            Value::Some(value) => {
+                let mut leading = vec![];
+                for event in events.by_ref() {
+                    if event.value.is_none() {
+                        leading.push(Trivia{text: source[event.span.clone()].to_string(), span: event.span});
+                        continue;
+                    }
+                    return Cst::Token{leading, text: source[event.span.clone()].to_string(), span: event.span, value: value.clone()};
+                }
+                Cst::Token{leading, span: source.len()..source.len(), text: String::new(), value: value.clone()}
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cst::None => Ok(()),
+            Cst::Token{leading, text, ..} => {
+                for trivia in leading {
+                    f.write_str(&trivia.text)?;
+                }
+                f.write_str(text)
+            }
+            Cst::List(children) => children.iter().try_for_each(|child| write!(f, "{}", child)),
+        }
+    }
+}
+
+impl CstTree {
+    pub fn value(&self) -> Value {
+        self.root.value()
+    }
+}
+
+impl fmt::Display for CstTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.root)?;
+        self.trailing.iter().try_for_each(|trivia| f.write_str(&trivia.text))
+    }
+}
+
+impl Parser {
+    /// Parses `s` keeping every consumed span, so the tree can be printed back to the exact input.
+    pub fn parse_cst(&self, s:&str) -> Result<CstTree, Failure> {
+        let wrap: Wrap = Arc::new(tracked);
+        let parser = instrument::rebuild(self, &wrap);
+        let empty = Recording{events: vec![], memo: HashMap::new()};
+        let saved = RECORDING.with(|recording| recording.replace(empty));
+        let result = parser.parse(s);
+        let events = RECORDING.with(|recording| recording.replace(saved)).events;
+        let success = result?;
+        let mut events = events.into_iter().peekable();
+        let root = Cst::build(&success.value, s, &mut events);
+        let trailing = events.map(|event| Trivia{text: s[event.span.clone()].to_string(), span: event.span}).collect();
+        Ok(CstTree{root, trailing})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list() -> Parser {
+        let ws = Parser::skip("(\\s|#[^\\n]*)*");
+        let item = ws.clone().and(Parser::regex("[a-z]+|\"([^\"]*)\"", 0));
+        let comma = ws.clone().and(Parser::skip(","));
+        Parser::skip("\\(")
+            .and(item.clone().and(comma.clone().and(item.clone()).repeat()).flat().or(Parser::skip("")))
+            .and(ws.clone().and(Parser::skip("\\)")))
+            .and(ws)
+    }
+
+    #[test]
+    fn cst_ok() {
+        let source = "( abc , # note\n \"d e\",xy\t) \n";
+        let tree = list().parse_cst(source).unwrap();
+        assert_eq!(tree.to_string(), source);
+        assert_eq!(tree.value(), list().parse(source).unwrap().value);
+        match &tree.root {
+            Cst::List(children) => match &children[1] {
+                Cst::Token{leading, span, text, ..} => {
+                    assert_eq!(leading.iter().map(|t| t.text.as_str()).collect::<Vec<_>>(), vec![" ", ",", " # note\n "]);
+                    assert_eq!((span.clone(), text.as_str()), (16..21, "\"d e\""));
+                }
+                _ => panic!(),
+            },
+            _ => panic!(),
+        }
+        assert_eq!(tree.trailing.iter().map(|t| t.text.as_str()).collect::<String>(), "\t) \n");
+
+        let tree = Parser::regex("([0-9]+)([a-z]+)", 1).parse_cst("123abc").unwrap();
+        assert_eq!(tree.root, Cst::Token{leading: vec![], span: 0..6, text: "123abc".to_string(), value: "123".to_string()});
+        assert_eq!(tree.to_string(), "123abc");
+
+        let tree = list().parse_cst("( )").unwrap();
+        assert_eq!(tree.root, Cst::None);
+        assert_eq!(tree.to_string(), "( )");
+
+        // The second alternative reuses the memoized item, whose spans must come back.
+        let item = Parser::skip(" *").and(Parser::regex("[a-z]+", 0)).memo();
+        let parser = item.clone().and(Parser::skip(";")).or(item);
+        let tree = parser.parse_cst(" ab").unwrap();
+        assert_eq!(tree.to_string(), " ab");
+        assert_eq!(tree.root, Cst::Token{leading: vec![Trivia{span: 0..1, text: " ".to_string()}], span: 1..3, text: "ab".to_string(), value: "ab".to_string()});
+    }
+
+    #[test]
+    fn cst_error() {
+        let result = list().parse_cst("(abc,)");
+        assert_eq!(result.err().unwrap().position, 4);
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::analysis;
+use crate::{key, Node, Parser};
+
+const CHAR_WIDTH: i32 = 8;
+const BOX_HEIGHT: i32 = 22;
+const GAP: i32 = 10;
+const RADIUS: i32 = 10;
+const MARGIN: i32 = 20;
+
+/// A railroad diagram element. Every element is entered on the left and left on the
+/// right at its baseline; `up` and `down` are its extent above and below it.
+enum Element {
+    Terminal{text: String, skip: bool},
+    NonTerminal(String),
+    Skip,
+    Sequence(Vec<Element>),
+    Choice(Vec<Element>),
+    /// One or more; `Choice([Skip, OneOrMore(x)])` is zero or more.
+    OneOrMore(Box<Element>),
+}
+
+impl Element {
+    fn width(&self) -> i32 {
+        match self {
+            Element::Terminal{text, ..} | Element::NonTerminal(text) => text.chars().count() as i32 * CHAR_WIDTH + 2 * GAP,
+            Element::Skip => 0,
+            Element::Sequence(items) => items.iter().map(Element::width).sum::<i32>() + GAP * (items.len() as i32 - 1).max(0),
+            Element::Choice(items) => items.iter().map(Element::width).max().unwrap_or(0) + 4 * RADIUS,
+            Element::OneOrMore(item) => item.width() + 4 * RADIUS,
+        }
+    }
+
+    fn up(&self) -> i32 {
+        match self {
+            Element::Terminal{..} | Element::NonTerminal(_) => BOX_HEIGHT / 2,
+            Element::Skip => 0,
+            Element::Sequence(items) => items.iter().map(Element::up).max().unwrap_or(0),
+            Element::Choice(items) => items.first().map_or(0, Element::up),
+            Element::OneOrMore(item) => item.up(),
+        }
+    }
+
+    fn down(&self) -> i32 {
+        match self {
+            Element::Terminal{..} | Element::NonTerminal(_) => BOX_HEIGHT / 2,
+            Element::Skip => 0,
+            Element::Sequence(items) => items.iter().map(Element::down).max().unwrap_or(0),
+            Element::Choice(items) => {
+                items.first().map_or(0, Element::down) + items.iter().skip(1).map(|item| GAP + item.up() + item.down()).sum::<i32>()
+            }
+            Element::OneOrMore(item) => item.down() + GAP,
+        }
+    }
+
+    fn draw(&self, x: i32, y: i32, out: &mut String) {
+        match self {
+            Element::Terminal{text, skip} => {
+                let class = if *skip {"skip"} else {"terminal"};
+                let _ = write!(out, r#"<rect class="{}" x="{}" y="{}" width="{}" height="{}" rx="10"/>"#, class, x, y - BOX_HEIGHT / 2, self.width(), BOX_HEIGHT);
+                text_at(x + self.width() / 2, y, text, out);
+            }
+            Element::NonTerminal(name) => {
+                let _ = write!(out, r#"<rect class="nonterminal" x="{}" y="{}" width="{}" height="{}"/>"#, x, y - BOX_HEIGHT / 2, self.width(), BOX_HEIGHT);
+                text_at(x + self.width() / 2, y, name, out);
+            }
+            Element::Skip => (),
+            Element::Sequence(items) => {
+                let mut x = x;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        line(x, y, x + GAP, out);
+                        x += GAP;
+                    }
+                    item.draw(x, y, out);
+                    x += item.width();
+                }
+            }
+            Element::Choice(items) => {
+                let end = x + self.width();
+                let mut yi = y;
+                for (i, item) in items.iter().enumerate() {
+                    if i == 0 {
+                        line(x, y, x + 2 * RADIUS, out);
+                    } else {
+                        yi += items[i - 1].down() + GAP + item.up();
+                        let _ = write!(out, r#"<path d="M{} {}Q{} {} {} {}L{} {}Q{} {} {} {}"/>"#,
+                                       x, y, x + RADIUS, y, x + RADIUS, y + RADIUS, x + RADIUS, yi - RADIUS, x + RADIUS, yi, x + 2 * RADIUS, yi);
+                    }
+                    item.draw(x + 2 * RADIUS, yi, out);
+                    line(x + 2 * RADIUS + item.width(), yi, end - 2 * RADIUS, out);
+                    if i == 0 {
+                        line(end - 2 * RADIUS, y, end, out);
+                    } else {
+                        let _ = write!(out, r#"<path d="M{} {}Q{} {} {} {}L{} {}Q{} {} {} {}"/>"#,
+                                       end - 2 * RADIUS, yi, end - RADIUS, yi, end - RADIUS, yi - RADIUS, end - RADIUS, y + RADIUS, end - RADIUS, y, end, y);
+                    }
+                }
+            }
+            Element::OneOrMore(item) => {
+                let end = x + self.width();
+                let back = y + item.down() + GAP;
+                line(x, y, x + 2 * RADIUS, out);
+                item.draw(x + 2 * RADIUS, y, out);
+                line(end - 2 * RADIUS, y, end, out);
+                let _ = write!(out, r#"<path d="M{} {}Q{} {} {} {}L{} {}Q{} {} {} {}L{} {}Q{} {} {} {}L{} {}Q{} {} {} {}"/>"#,
+                               end - 2 * RADIUS, y, end - RADIUS, y, end - RADIUS, y + RADIUS, end - RADIUS, back - RADIUS,
+                               end - RADIUS, back, end - 2 * RADIUS, back, x + 2 * RADIUS, back,
+                               x + RADIUS, back, x + RADIUS, back - RADIUS, x + RADIUS, y + RADIUS, x + RADIUS, y, x + 2 * RADIUS, y);
+            }
+        }
+    }
+}
+
+fn line(x1: i32, y: i32, x2: i32, out: &mut String) {
+    if x1 != x2 {
+        let _ = write!(out, r#"<path d="M{} {}H{}"/>"#, x1, y, x2);
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn text_at(x: i32, y: i32, text: &str, out: &mut String) {
+    let _ = write!(out, r#"<text x="{}" y="{}">{}</text>"#, x, y + 4, escape(text));
+}
+
+/// The grammar reachable from a root parser, with the root named `start` unless it is a rule.
+struct Rules<'a> {
+    root: &'a Parser,
+    root_name: String,
+}
+
+impl<'a> Rules<'a> {
+    fn new(root: &'a Parser) -> Self {
+        Rules{root, root_name: root.name().unwrap_or("start").to_string()}
+    }
+
+    /// What the rule `parser` stands for: a rule's body, or the root's expansion.
+    fn body(&self, parser: &Parser) -> Option<Parser> {
+        match &*parser.node {
+            Node::Rule(_, slot) => slot.get(),
+            Node::Recursive(body) => Some(body.clone()),
+            _ => Some(parser.clone()),
+        }
+    }
+
+    /// Rule names with their parsers, in order of first reference from the root.
+    fn collect(&self) -> Vec<(String, Parser)> {
+        let mut rules = vec![(self.root_name.clone(), self.root.clone())];
+        let mut seen = HashSet::new();
+        seen.insert(key(self.root));
+        let mut i = 0;
+        while i < rules.len() {
+            if let Some(body) = self.body(&rules[i].1) {
+                self.expand_references(&body, &mut seen, &mut rules);
+            }
+            i += 1;
+        }
+        rules
+    }
+
+    fn references(&self, parser: &Parser, seen: &mut HashSet<usize>, rules: &mut Vec<(String, Parser)>) {
+        match &*parser.node {
+            Node::Rule(name, _) => {
+                if seen.insert(key(parser)) {
+                    rules.push((name.clone(), parser.clone()));
+                }
+            }
+            _ if key(parser) == key(self.root) => (),
+            _ => self.expand_references(parser, seen, rules),
+        }
+    }
+
+    /// Like `references`, but looks inside `parser` even if it is the root.
+    fn expand_references(&self, parser: &Parser, seen: &mut HashSet<usize>, rules: &mut Vec<(String, Parser)>) {
+        match &*parser.node {
+            Node::And(a, b) | Node::Or(a, b) => {
+                self.references(a, seen, rules);
+                self.references(b, seen, rules);
+            }
+            Node::Repeat(p) | Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => self.references(p, seen, rules),
+            Node::Recursive(body) => self.references(body, seen, rules),
+            Node::Rule(..) | Node::Regex{..} | Node::Root | Node::Custom => (),
+        }
+    }
+
+    fn element(&self, parser: &Parser) -> Element {
+        match &*parser.node {
+            Node::Rule(name, _) => Element::NonTerminal(name.clone()),
+            Node::Root => Element::NonTerminal(self.root_name.clone()),
+            _ if key(parser) == key(self.root) => Element::NonTerminal(self.root_name.clone()),
+            _ => self.expand(parser),
+        }
+    }
+
+    /// Like `element`, but draws `parser` itself even if it is the root.
+    fn expand(&self, parser: &Parser) -> Element {
+        match &*parser.node {
+            Node::Rule(name, _) => Element::NonTerminal(name.clone()),
+            Node::Regex{pattern, group, ..} => {
+                let skip = *group < 0;
+                match analysis::literal(pattern) {
+                    Some(text) if text.is_empty() && skip => Element::Skip,
+                    Some(text) => Element::Terminal{text: format!("{:?}", text), skip},
+                    None => Element::Terminal{text: format!("/{}/", pattern), skip},
+                }
+            }
+            Node::And(a, b) => {
+                let mut items = match self.element(a) {
+                    Element::Sequence(items) => items,
+                    first => vec![first],
+                };
+                items.push(self.element(b));
+                Element::Sequence(items)
+            }
+            Node::Or(a, b) => {
+                let mut items = match self.element(a) {
+                    Element::Choice(items) => items,
+                    first => vec![first],
+                };
+                items.push(self.element(b));
+                if let Some(skip) = items.iter().position(|item| matches!(item, Element::Skip)) {
+                    let item = items.remove(skip);
+                    items.insert(0, item);
+                }
+                Element::Choice(items)
+            }
+            Node::Repeat(p) => Element::Choice(vec![Element::Skip, Element::OneOrMore(Box::new(self.element(p)))]),
+            Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => self.element(p),
+            Node::Recursive(body) => self.element(body),
+            Node::Root => Element::NonTerminal(self.root_name.clone()),
+            Node::Custom => Element::NonTerminal("<custom>".to_string()),
+        }
+    }
+
+    fn svg(&self, name: &str, parser: &Parser) -> String {
+        let element = match self.body(parser) {
+            Some(body) => self.expand(&body),
+            None => Element::Skip,
+        };
+        let (width, up, down) = (element.width(), element.up(), element.down());
+        let title = 2 * GAP;
+        let y = MARGIN + title + up;
+        let total_width = width + 2 * MARGIN + 2 * GAP;
+        let total_height = y + down + MARGIN;
+        let mut out = String::new();
+        let _ = write!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#, total_width, total_height);
+        out.push_str("<style>path{fill:none;stroke:#333;stroke-width:2}rect{fill:#fff;stroke:#333;stroke-width:2}rect.skip{stroke-dasharray:4 2}\
+text{font:13px monospace;text-anchor:middle}text.title{font-weight:bold;text-anchor:start}</style>");
+        let _ = write!(out, r#"<text class="title" x="{}" y="{}">{}</text>"#, MARGIN, MARGIN + GAP, escape(name));
+        let _ = write!(out, r#"<path d="M{} {}v20M{} {}v20"/>"#, MARGIN, y - GAP, MARGIN + 4, y - GAP);
+        line(MARGIN, y, MARGIN + GAP, &mut out);
+        element.draw(MARGIN + GAP, y, &mut out);
+        line(MARGIN + GAP + width, y, MARGIN + 2 * GAP + width, &mut out);
+        let end = MARGIN + 2 * GAP + width;
+        let _ = write!(out, r#"<path d="M{} {}v20M{} {}v20"/>"#, end, y - GAP, end - 4, y - GAP);
+        out.push_str("</svg>\n");
+        out
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Parser {
+    /// One standalone SVG railroad diagram per rule reachable from this parser, as
+    /// `(rule name, svg)`. A root that is not a rule is named `start`.
+    pub fn railroad(&self) -> Vec<(String, String)> {
+        let rules = Rules::new(self);
+        rules.collect().iter().map(|(name, parser)| (name.clone(), rules.svg(name, parser))).collect()
+    }
+
+    /// A Graphviz digraph with an edge from each rule to every rule it refers to.
+    pub fn to_dot(&self) -> String {
+        let rules = Rules::new(self);
+        let mut out = String::from("digraph grammar {\n  node [shape=box];\n");
+        for (name, parser) in rules.collect() {
+            let _ = writeln!(out, "  {};", quote(&name));
+            let mut referenced = vec![];
+            if let Some(body) = rules.body(&parser) {
+                rules.expand_references(&body, &mut HashSet::new(), &mut referenced);
+                if self.name().is_none() && reaches_root(&rules, &body, &mut HashSet::new(), true) {
+                    referenced.push((rules.root_name.clone(), self.clone()));
+                }
+            }
+            for (target, _) in referenced {
+                let _ = writeln!(out, "  {} -> {};", quote(&name), quote(&target));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Whether `parser` refers to the root directly, without going through a rule.
+/// The `top` parser itself does not count as a reference.
+fn reaches_root(rules: &Rules, parser: &Parser, seen: &mut HashSet<usize>, top: bool) -> bool {
+    if key(parser) == key(rules.root) && !top {
+        return true;
+    }
+    match &*parser.node {
+        Node::And(a, b) | Node::Or(a, b) => reaches_root(rules, a, seen, false) || reaches_root(rules, b, seen, false),
+        Node::Repeat(p) | Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => reaches_root(rules, p, seen, false),
+        Node::Recursive(body) => seen.insert(key(parser)) && reaches_root(rules, body, seen, false),
+        Node::Root => true,
+        Node::Rule(..) | Node::Regex{..} | Node::Custom => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    #[test]
+    fn diagram_ok() {
+        let grammar = Grammar::parse("value = call | name ; call = name ~\"(\" (value (~\",\" value)*)? ~\")\" ; name = /[a-z]+/ ;").unwrap();
+        let parser = grammar.parser(None).unwrap();
+        assert_eq!(parser.to_dot(), "\
+digraph grammar {
+  node [shape=box];
+  \"value\";
+  \"value\" -> \"call\";
+  \"value\" -> \"name\";
+  \"call\";
+  \"call\" -> \"name\";
+  \"call\" -> \"value\";
+  \"name\";
+}
+");
+        let diagrams = parser.railroad();
+        assert_eq!(diagrams.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["value", "call", "name"]);
+        let (_, call) = &diagrams[1];
+        assert!(call.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" "));
+        assert!(call.ends_with("</svg>\n"));
+        assert_eq!(call.matches("class=\"nonterminal\"").count(), 3);
+        assert_eq!(call.matches("class=\"skip\"").count(), 3);
+        assert!(call.contains(">&quot;(&quot;</text>"));
+        assert!(diagrams[2].1.contains(">/[a-z]+/</text>"));
+    }
+
+    #[test]
+    fn diagram_error() {
+        let nested = Parser::new(Box::new(|root: &Parser| Parser::regex("x", 0).or(Parser::skip("<").and(root.clone()).and(Parser::skip(">")))));
+        assert_eq!(nested.to_dot(), "digraph grammar {\n  node [shape=box];\n  \"start\";\n  \"start\" -> \"start\";\n}\n");
+        let diagrams = nested.railroad();
+        assert_eq!(diagrams.len(), 1);
+        assert!(diagrams[0].1.contains(">&quot;&lt;&quot;</text>"));
+        assert_eq!(diagrams[0].1.matches(">start</text>").count(), 2);
+
+        let undefined = Parser::rule("later");
+        assert_eq!(undefined.railroad().len(), 1);
+        assert_eq!(undefined.to_dot(), "digraph grammar {\n  node [shape=box];\n  \"later\";\n}\n");
+    }
+}
@@ -0,0 +1,300 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::{Failure, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    /// The pattern is not a valid regular expression on its own.
+    Regex { pattern: String, message: String },
+    /// The requested capture group does not exist in the pattern, which has `groups` of its own.
+    Group { pattern: String, group: isize, groups: usize },
+    /// The canonical text given for a skipped terminal is not matched by its pattern.
+    Canonical { pattern: String, canonical: String },
+}
+
+impl BuildError {
+    pub fn pattern(&self) -> &str {
+        match self {
+            BuildError::Regex { pattern, .. } => pattern,
+            BuildError::Group { pattern, .. } => pattern,
+            BuildError::Canonical { pattern, .. } => pattern,
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Regex { pattern, message } => write!(f, "invalid pattern {:?}: {}", pattern, message),
+            BuildError::Group { pattern, group, groups } => write!(f, "invalid pattern {:?}: group {} requested but the pattern has {} group(s)", pattern, group, groups),
+            BuildError::Canonical { pattern, canonical } => write!(f, "invalid pattern {:?}: canonical text {:?} does not match it", pattern, canonical),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Byte offset of the failure.
+    pub position: i32,
+    /// 1-based line and column (in characters) of the failure.
+    pub line: usize,
+    pub column: usize,
+    pub expected: Vec<String>,
+}
+
+impl Failure {
+    pub fn to_error(&self, source: &str) -> ParseError {
+        let before = &source[..(self.position.max(0) as usize).min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        ParseError{position: self.position, line, column, expected: self.expected.clone()}
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "parse error at line {}, column {}", self.line, self.column)?;
+        if !self.expected.is_empty() {
+            let expected: Vec<String> = self.expected.iter().map(|e| format!("{:?}", e)).collect();
+            write!(f, ": expected {}", expected.join(" or "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GrammarErrorKind {
+    Syntax(String),
+    UndefinedRule(String),
+    DuplicateRule(String),
+    Pattern(BuildError),
+    /// The rule asked to start parsing from does not exist, or the grammar has no rules.
+    /// Not located in the grammar text, so line and column are 0.
+    UndefinedStart(Option<String>),
+}
+
+/// An error in a grammar file, located at a 1-based line and column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrammarError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: GrammarErrorKind,
+}
+
+impl fmt::Display for GrammarErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GrammarErrorKind::Syntax(message) => f.write_str(message),
+            GrammarErrorKind::UndefinedRule(name) => write!(f, "undefined rule {:?}", name),
+            GrammarErrorKind::DuplicateRule(name) => write!(f, "rule {:?} is defined twice", name),
+            GrammarErrorKind::Pattern(e) => write!(f, "{}", e),
+            GrammarErrorKind::UndefinedStart(Some(name)) => write!(f, "no rule {:?} to start from", name),
+            GrammarErrorKind::UndefinedStart(None) => f.write_str("the grammar has no rules"),
+        }
+    }
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            GrammarErrorKind::UndefinedStart(_) => write!(f, "{}", self.kind),
+            _ => write!(f, "line {}, column {}: {}", self.line, self.column, self.kind),
+        }
+    }
+}
+
+impl Error for GrammarError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnparseError {
+    /// No alternative of the grammar produces the subtree at `path` (child indexes from the root).
+    NoMatch { path: Vec<usize>, value: Value, expected: Vec<String> },
+    /// The printed text parses back to a different value, usually because an earlier `or` branch wins.
+    RoundTrip { text: String },
+}
+
+impl fmt::Display for UnparseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnparseError::NoMatch { path, value, expected } => {
+                let path: Vec<String> = path.iter().map(|i| i.to_string()).collect();
+                write!(f, "no alternative matches value /{} {:?}", path.join("/"), value)?;
+                if !expected.is_empty() {
+                    let expected: Vec<String> = expected.iter().map(|e| format!("{:?}", e)).collect();
+                    write!(f, ": tried {}", expected.join(", "))?;
+                }
+                Ok(())
+            }
+            UnparseError::RoundTrip { text } => write!(f, "printed text {:?} does not parse back to the same value", text),
+        }
+    }
+}
+
+impl Error for UnparseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenerateError {
+    /// The grammar contains a parser built from a function, which cannot be inverted.
+    Custom,
+    /// The grammar refers to a rule that was never defined.
+    Undefined(String),
+    /// No sentence that parses back was found within the given number of attempts.
+    Attempts(usize),
+}
+
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GenerateError::Custom => f.write_str("cannot generate text for a custom parser"),
+            GenerateError::Undefined(name) => write!(f, "rule {:?} is not defined", name),
+            GenerateError::Attempts(n) => write!(f, "no valid sentence found in {} attempts", n),
+        }
+    }
+}
+
+impl Error for GenerateError {}
+
+/// Why a parser could not be compiled by `Parser::compile`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    /// The parser contains a parser built from a function, which has no instructions.
+    Custom,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Custom => f.write_str("cannot compile a custom parser"),
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+/// Why `Parser::reparse` rejected an edit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditError {
+    /// `start` is after `end`, or `end` is past the end of the source of length `len`.
+    Range { start: usize, end: usize, len: usize },
+    /// The byte offset falls inside a UTF-8 character.
+    CharBoundary { position: usize },
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditError::Range { start, end, len } => write!(f, "invalid edit range {}..{} in source of length {}", start, end, len),
+            EditError::CharBoundary { position } => write!(f, "edit offset {} is not on a character boundary", position),
+        }
+    }
+}
+
+impl Error for EditError {}
+
+/// Malformed JSON, or JSON that does not encode a `Value`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonError {
+    /// Byte offset of the offending token.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid JSON at byte {}: {}", self.position, self.message)
+    }
+}
+
+impl Error for JsonError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectorError {
+    /// Byte offset in the selector text.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid selector at {}: {}", self.position, self.message)
+    }
+}
+
+impl Error for SelectorError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternError {
+    /// Byte offset in the pattern text.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid pattern at {}: {}", self.position, self.message)
+    }
+}
+
+impl Error for PatternError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    /// Byte offset in the query text.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid query at {}: {}", self.position, self.message)
+    }
+}
+
+impl Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RewriteError {
+    /// The rules kept applying past the rewrite limit, usually because two of them undo each other.
+    Limit(usize),
+}
+
+impl fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RewriteError::Limit(n) => write!(f, "rewriting did not finish within {} steps", n),
+        }
+    }
+}
+
+impl Error for RewriteError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    #[test]
+    fn parse_error_ok() {
+        let parser = Parser::regex("[a-z]+", 0).and(Parser::skip("\\n")).repeat().flat();
+        assert!(parser.try_parse("abc\ndef\n").is_ok());
+    }
+
+    #[test]
+    fn parse_error_error() {
+        let parser = Parser::skip("[a-z\\n\u{e9}]*").and(Parser::regex("[0-9]+", 0)).and(Parser::skip(";").or(Parser::skip(",")));
+        let error = parser.try_parse("ab\nd\u{e9}f12x").err().unwrap();
+        assert_eq!(error, ParseError{position: 9, line: 2, column: 6, expected: vec![";".to_string(), ",".to_string()]});
+        assert_eq!(error.to_string(), "parse error at line 2, column 6: expected \";\" or \",\"");
+
+        let boxed: Box<dyn Error + Send + Sync> = Box::new(error);
+        assert!(boxed.to_string().starts_with("parse error"));
+
+        let error = Parser::regex("\u{e9}", 0).try_parse("\u{e9}x").err().unwrap();
+        assert_eq!((error.position, error.column), (2, 2));
+    }
+}
@@ -0,0 +1,307 @@
+use std::sync::Arc;
+
+use crate::{Failure, Node, Parser};
+
+/// A line break, chosen by the enclosing group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Break {
+    /// A space when the group fits on the line, a newline otherwise.
+    Line,
+    /// Nothing when the group fits on the line, a newline otherwise.
+    Soft,
+    /// Always a newline; the enclosing groups break.
+    Hard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    Group,
+    Nest(usize),
+    Before(Break),
+    After(Break),
+}
+
+/// A document for the pretty printer (Wadler, "A prettier printer").
+#[derive(Debug, Clone, PartialEq)]
+pub enum Doc {
+    Text(String),
+    Break(Break),
+    Nest(usize, Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+    /// A comment kept from skipped text; `own_line` starts it on a new line and
+    /// `line_end` ends the line after it.
+    Comment { text: String, own_line: bool, line_end: bool },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+impl Doc {
+    fn concat(docs: Vec<Doc>) -> Doc {
+        let mut flat = vec![];
+        for doc in docs {
+            match doc {
+                Doc::Concat(docs) => flat.extend(docs),
+                Doc::Text(text) if text.is_empty() => {}
+                doc => flat.push(doc),
+            }
+        }
+        match flat.len() {
+            1 => flat.pop().unwrap(),
+            _ => Doc::Concat(flat),
+        }
+    }
+
+    fn forces_break(&self) -> bool {
+        match self {
+            Doc::Text(_) => false,
+            Doc::Break(b) => *b == Break::Hard,
+            Doc::Nest(_, doc) | Doc::Group(doc) => doc.forces_break(),
+            Doc::Concat(docs) => docs.iter().any(|doc| doc.forces_break()),
+            Doc::Comment{own_line, line_end, ..} => *own_line || *line_end,
+        }
+    }
+
+    /// Lays the document out within `width` columns where possible.
+    pub fn pretty(&self, width: usize) -> String {
+        let mut out = String::new();
+        let mut column = 0;
+        let mut stack: Vec<(usize, Mode, &Doc)> = vec![(0, Mode::Break, self)];
+        while let Some((indent, mode, doc)) = stack.pop() {
+            match doc {
+                Doc::Text(text) => {
+                    out.push_str(text);
+                    column += text.chars().count();
+                }
+                Doc::Break(b) => match (mode, b) {
+                    (Mode::Flat, Break::Line) => {
+                        out.push(' ');
+                        column += 1;
+                    }
+                    (Mode::Flat, Break::Soft) => {}
+                    _ => column = newline(&mut out, indent),
+                },
+                Doc::Nest(n, doc) => stack.push((indent + n, mode, doc)),
+                Doc::Group(doc) => {
+                    let flat = !doc.forces_break() && fits(width as isize - column as isize, &stack, (indent, Mode::Flat, doc));
+                    stack.push((indent, if flat {Mode::Flat} else {Mode::Break}, doc));
+                }
+                Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc))),
+                Doc::Comment{text, own_line, line_end} => {
+                    if *own_line && column > indent {
+                        column = newline(&mut out, indent);
+                    } else if column > indent {
+                        out.push(' ');
+                        column += 1;
+                    }
+                    out.push_str(text);
+                    column += text.chars().count();
+                    if *line_end {
+                        column = newline(&mut out, indent);
+                    }
+                }
+            }
+        }
+        trim_line_end(&mut out);
+        out
+    }
+}
+
+fn trim_line_end(out: &mut String) {
+    out.truncate(out.trim_end_matches(' ').len());
+}
+
+/// Starts a new line, unless a comment has just ended one.
+fn newline(out: &mut String, indent: usize) -> usize {
+    trim_line_end(out);
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.extend(std::iter::repeat_n(' ', indent));
+    indent
+}
+
+/// Whether `next` in flat mode, followed by the rest up to its first line break, fits in `width`.
+fn fits(mut width: isize, rest: &[(usize, Mode, &Doc)], next: (usize, Mode, &Doc)) -> bool {
+    let mut stack = vec![next];
+    let mut rest = rest.iter().rev();
+    while width >= 0 {
+        let (indent, mode, doc) = match stack.pop() {
+            Some(item) => item,
+            None => match rest.next() {
+                Some(item) => *item,
+                None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(text) => width -= text.chars().count() as isize,
+            Doc::Break(b) => match (mode, b) {
+                (Mode::Flat, Break::Line) => width -= 1,
+                (Mode::Flat, Break::Soft) => {}
+                _ => return true,
+            },
+            Doc::Nest(n, doc) => stack.push((indent + n, mode, doc)),
+            Doc::Group(doc) => stack.push((indent, mode, doc)),
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc))),
+            Doc::Comment{text, line_end, ..} => {
+                width -= text.chars().count() as isize + 1;
+                if *line_end {
+                    return width >= 0;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Skipped text is printed as its canonical form, except for comments, which are kept.
+fn skipped(text: &str, canonical: Option<&str>) -> Doc {
+    let significant = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    let canonical = canonical.unwrap_or("");
+    if significant(text) == significant(canonical) {
+        return Doc::Text(canonical.to_string());
+    }
+    let mut docs = vec![];
+    let mut own_line = false;
+    let lines: Vec<&str> = text.split('\n').collect();
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.trim();
+        if !line.is_empty() {
+            docs.push(Doc::Comment{text: line.to_string(), own_line, line_end: i + 1 < lines.len()});
+        }
+        own_line = true;
+    }
+    Doc::concat(docs)
+}
+
+fn layout(node: Layout, doc: Doc) -> Doc {
+    match node {
+        Layout::Group => Doc::Group(Box::new(doc)),
+        Layout::Nest(n) => Doc::Nest(n, Box::new(doc)),
+        Layout::Before(b) => Doc::concat(vec![Doc::Break(b), doc]),
+        Layout::After(b) => Doc::concat(vec![doc, Doc::Break(b)]),
+    }
+}
+
+/// Parses like `parser.func` but returns the layout document of the consumed text.
+fn to_doc(parser: &Parser, root: &Parser, s: &str, i: i32) -> Result<(i32, Doc), Failure> {
+    match &*parser.node {
+        Node::Regex{group, canonical, ..} => {
+            let success = (parser.func)(root, s, i)?;
+            let text = &s[i as usize..success.position as usize];
+            let doc = if *group < 0 {skipped(text, canonical.as_deref())} else {Doc::Text(text.to_string())};
+            Ok((success.position, doc))
+        }
+        Node::And(a, b) => {
+            let (i, doc1) = to_doc(a, root, s, i)?;
+            let (i, doc2) = to_doc(b, root, s, i)?;
+            Ok((i, Doc::concat(vec![doc1, doc2])))
+        }
+        Node::Or(a, b) => to_doc(a, root, s, i).or_else(|e1| to_doc(b, root, s, i).map_err(|e2| Parser::merge_errs(e1, e2))),
+        Node::Repeat(p) => {
+            let mut docs = vec![];
+            let mut i = i;
+            while let Ok((next, doc)) = to_doc(p, root, s, i) {
+                i = next;
+                docs.push(doc);
+            }
+            Ok((i, Doc::concat(docs)))
+        }
+        Node::List(p) | Node::Flat(p) | Node::Memo(p) => to_doc(p, root, s, i),
+        Node::Layout(node, p) => to_doc(p, root, s, i).map(|(i, doc)| (i, layout(*node, doc))),
+        Node::Recursive(body) => to_doc(body, root, s, i),
+        Node::Root => to_doc(root, root, s, i),
+        Node::Rule(_, slot) => match slot.get() {
+            Some(body) => to_doc(&body, root, s, i),
+            None => Err(Failure{position: i, expected: vec![]}),
+        },
+        Node::Custom => {
+            let success = (parser.func)(root, s, i)?;
+            Ok((success.position, Doc::Text(s[i as usize..success.position as usize].to_string())))
+        }
+    }
+}
+
+impl Parser {
+    pub fn layout(self, layout: Layout) -> Self {
+        let node = Arc::new(Node::Layout(layout, self.clone()));
+        Parser{node, func:Arc::new(move |root:&Self, s:&str, i:i32| (self.func)(root, s, i))}
+    }
+
+    pub fn group(self) -> Self {
+        self.layout(Layout::Group)
+    }
+
+    pub fn nest(self, indent: usize) -> Self {
+        self.layout(Layout::Nest(indent))
+    }
+
+    pub fn break_before(self, b: Break) -> Self {
+        self.layout(Layout::Before(b))
+    }
+
+    pub fn break_after(self, b: Break) -> Self {
+        self.layout(Layout::After(b))
+    }
+
+    pub fn to_doc(&self, s:&str) -> Result<Doc, Failure> {
+        self.parse(s)?;
+        to_doc(self, self, s, 0).map(|(_, doc)| doc)
+    }
+
+    /// Reprints `s` using the layout annotations of this grammar, keeping comments.
+    pub fn format(&self, s:&str, width:usize) -> Result<String, Failure> {
+        Ok(self.to_doc(s)?.pretty(width))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json() -> Parser {
+        let ws = Parser::skip_as("(\\s|//[^\\n]*)*", "");
+        let item = Parser::regex("true|false|-?(0|[1-9][0-9]*)|\"[^\"]*\"", 0);
+        let comma = ws.clone().and(Parser::skip(",")).and(ws.clone()).break_after(Break::Line);
+        let array = Parser::new(Box::new(move |root:&Parser|
+            Parser::skip("\\[").and(ws.clone())
+            .and(root.clone().and(comma.clone().and(root.clone()).repeat()).or(Parser::skip("")).break_before(Break::Soft).nest(2))
+            .and(ws.clone().and(Parser::skip("]")).break_before(Break::Soft))
+            .group()
+        ));
+        item.or(array)
+    }
+
+    #[test]
+    fn format_ok() {
+        let parser = json();
+        assert_eq!(parser.format("[ 1,2 ,[true,  false],[] ]", 80).unwrap(), "[1, 2, [true, false], []]");
+        assert_eq!(parser.format("[1,2,[true,false]]", 16).unwrap(), "[\n  1,\n  2,\n  [true, false]\n]");
+        assert_eq!(
+            parser.format("[1, // one\n2,\n// last\n3]", 80).unwrap(),
+            "[\n  1, // one\n  2,\n  // last\n  3\n]",
+        );
+    }
+
+    #[test]
+    fn format_error() {
+        let result = json().format("[1,,2]", 80);
+        assert_eq!(result.err().unwrap().position, 2);
+    }
+
+    #[test]
+    fn pretty_ok() {
+        let doc = Doc::Group(Box::new(Doc::concat(vec![
+            Doc::Text("f(".to_string()),
+            Doc::Nest(4, Box::new(Doc::concat(vec![Doc::Break(Break::Soft), Doc::Text("a,".to_string()), Doc::Break(Break::Line), Doc::Text("b".to_string())]))),
+            Doc::Break(Break::Soft),
+            Doc::Text(")".to_string()),
+        ])));
+        assert_eq!(doc.pretty(10), "f(a, b)");
+        assert_eq!(doc.pretty(5), "f(\n    a,\n    b\n)");
+    }
+}
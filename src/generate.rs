@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+
+use regex_syntax::hir::{Class, Hir, HirKind, Literal, RepetitionKind, RepetitionRange};
+
+use crate::{key, GenerateError, Node, Parser};
+
+const INFINITE: u64 = u64::MAX;
+
+/// A small splitmix64 generator, so sentences are reproducible from a seed.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`; `n` must not be zero.
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// Produces random sentences of a grammar. Each sentence is parsed before it is returned,
+/// so every sentence is valid; candidates the grammar rejects are dropped and retried.
+pub struct Generator {
+    root: Parser,
+    rng: Rng,
+    max_depth: usize,
+    max_len: usize,
+    max_repeat: usize,
+    attempts: usize,
+    weights: HashMap<String, u32>,
+    /// Fewest terminals each rule (and the root) needs.
+    costs: HashMap<usize, u64>,
+    patterns: HashMap<String, Option<Hir>>,
+}
+
+impl Generator {
+    pub fn new(parser: &Parser) -> Self {
+        let mut generator = Generator{
+            root: parser.clone(),
+            rng: Rng::new(0),
+            max_depth: 12,
+            max_len: 256,
+            max_repeat: 3,
+            attempts: 100,
+            weights: HashMap::new(),
+            costs: HashMap::new(),
+            patterns: HashMap::new(),
+        };
+        let mut rules = vec![parser.clone()];
+        match &*parser.node {
+            Node::Rule(_, slot) => {
+                if let Some(body) = slot.get() {
+                    generator.collect(&body, &mut rules);
+                }
+            }
+            _ => generator.collect(parser, &mut rules),
+        }
+        loop {
+            let mut changed = false;
+            for rule in rules.iter() {
+                let cost = match generator.body(rule) {
+                    Some(body) => generator.expand_cost(&body).saturating_add(1),
+                    None => INFINITE,
+                };
+                if cost < generator.costs.get(&key(rule)).copied().unwrap_or(INFINITE) {
+                    generator.costs.insert(key(rule), cost);
+                    changed = true;
+                }
+            }
+            if !changed {
+                return generator;
+            }
+        }
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Rng::new(seed);
+        self
+    }
+
+    /// Rule nesting beyond which only the shortest alternatives are taken.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Sentence length in bytes beyond which only the shortest alternatives are taken.
+    pub fn max_len(mut self, len: usize) -> Self {
+        self.max_len = len;
+        self
+    }
+
+    /// Most items produced for a `repeat`.
+    pub fn max_repeat(mut self, count: usize) -> Self {
+        self.max_repeat = count;
+        self
+    }
+
+    pub fn attempts(mut self, attempts: usize) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// How often an alternative that is the rule `name` is chosen relative to others,
+    /// which weigh 1. A weight of 0 leaves the alternative out unless nothing else fits.
+    pub fn weight(mut self, name: &str, weight: u32) -> Self {
+        self.weights.insert(name.to_string(), weight);
+        self
+    }
+
+    /// The next sentence.
+    pub fn generate(&mut self) -> Result<String, GenerateError> {
+        let root = self.root.clone();
+        for _ in 0..self.attempts {
+            let mut out = String::new();
+            self.rule(&root, 0, &mut out)?;
+            if self.root.parse(&out).is_ok() {
+                return Ok(out);
+            }
+        }
+        Err(GenerateError::Attempts(self.attempts))
+    }
+
+    fn collect(&mut self, parser: &Parser, rules: &mut Vec<Parser>) {
+        match &*parser.node {
+            Node::Rule(_, slot) => {
+                if rules.iter().any(|rule| key(rule) == key(parser)) {
+                    return;
+                }
+                rules.push(parser.clone());
+                if let Some(body) = slot.get() {
+                    self.collect(&body, rules);
+                }
+            }
+            Node::And(a, b) | Node::Or(a, b) => {
+                self.collect(a, rules);
+                self.collect(b, rules);
+            }
+            Node::Repeat(p) | Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => self.collect(p, rules),
+            Node::Recursive(body) => self.collect(body, rules),
+            Node::Regex{..} | Node::Root | Node::Custom => (),
+        }
+    }
+
+    /// What generating the rule or root `parser` means.
+    fn body(&self, parser: &Parser) -> Option<Parser> {
+        match &*parser.node {
+            Node::Rule(_, slot) => slot.get(),
+            Node::Recursive(body) => Some(body.clone()),
+            _ => Some(parser.clone()),
+        }
+    }
+
+    fn is_rule(&self, parser: &Parser) -> bool {
+        matches!(&*parser.node, Node::Rule(..)) || key(parser) == key(&self.root)
+    }
+
+    fn cost(&self, parser: &Parser) -> u64 {
+        match self.is_rule(parser) {
+            true => self.costs.get(&key(parser)).copied().unwrap_or(INFINITE),
+            false => self.expand_cost(parser),
+        }
+    }
+
+    /// Like `cost`, but looks inside `parser` even if it is the root.
+    fn expand_cost(&self, parser: &Parser) -> u64 {
+        match &*parser.node {
+            Node::Regex{..} => 1,
+            Node::And(a, b) => self.cost(a).saturating_add(self.cost(b)),
+            Node::Or(a, b) => self.cost(a).min(self.cost(b)),
+            Node::Repeat(_) => 0,
+            Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => self.cost(p),
+            Node::Recursive(body) => self.cost(body),
+            Node::Root => self.cost(&self.root),
+            Node::Rule(..) => self.costs.get(&key(parser)).copied().unwrap_or(INFINITE),
+            Node::Custom => INFINITE,
+        }
+    }
+
+    fn rule(&mut self, parser: &Parser, depth: usize, out: &mut String) -> Result<(), GenerateError> {
+        match self.body(parser) {
+            Some(body) => self.expand(&body, depth + 1, out),
+            None => Err(GenerateError::Undefined(parser.name().unwrap_or("").to_string())),
+        }
+    }
+
+    fn expr(&mut self, parser: &Parser, depth: usize, out: &mut String) -> Result<(), GenerateError> {
+        match self.is_rule(parser) {
+            true => self.rule(parser, depth, out),
+            false => self.expand(parser, depth, out),
+        }
+    }
+
+    /// Like `expr`, but generates the inside of `parser` even if it is the root.
+    fn expand(&mut self, parser: &Parser, depth: usize, out: &mut String) -> Result<(), GenerateError> {
+        let minimal = depth > self.max_depth || out.len() >= self.max_len;
+        match &*parser.node {
+            Node::Regex{pattern, group, canonical, regex, ..} => {
+                let text = match canonical {
+                    Some(canonical) if *group < 0 && (minimal || self.rng.below(2) == 0) => canonical.clone(),
+                    _ => self.sample(pattern, regex, canonical.as_deref().filter(|_| *group < 0)),
+                };
+                out.push_str(&text);
+                Ok(())
+            }
+            Node::And(a, b) => {
+                self.expr(a, depth, out)?;
+                self.expr(b, depth, out)
+            }
+            Node::Or(..) => {
+                let alternative = self.choose(parser, minimal);
+                self.expr(&alternative, depth, out)
+            }
+            Node::Repeat(item) => {
+                let count = if minimal {0} else {self.rng.below(self.max_repeat as u64 + 1)};
+                for _ in 0..count {
+                    self.expr(item, depth, out)?;
+                }
+                Ok(())
+            }
+            Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => self.expr(p, depth, out),
+            Node::Recursive(body) => self.expr(body, depth, out),
+            Node::Root => {
+                let root = self.root.clone();
+                self.rule(&root, depth, out)
+            }
+            Node::Rule(..) => self.rule(parser, depth, out),
+            Node::Custom => Err(GenerateError::Custom),
+        }
+    }
+
+    /// Picks one alternative of the `or` chain at `parser` by weight, or one of the
+    /// cheapest when `minimal`.
+    fn choose(&mut self, parser: &Parser, minimal: bool) -> Parser {
+        let mut alternatives = vec![];
+        let mut chain = parser.clone();
+        while let Node::Or(a, b) = &*chain.node {
+            alternatives.push(b.clone());
+            chain = a.clone();
+        }
+        alternatives.push(chain);
+        alternatives.reverse();
+        let costs: Vec<u64> = alternatives.iter().map(|a| self.cost(a)).collect();
+        let cheapest = costs.iter().copied().min().unwrap_or(INFINITE);
+        let weights: Vec<u64> = alternatives.iter().zip(costs.iter()).map(|(alternative, &cost)| {
+            let weight = alternative.name().and_then(|name| self.weights.get(name)).map_or(1, |&w| w as u64);
+            match cost {
+                INFINITE => 0,
+                _ if minimal && cost > cheapest => 0,
+                _ => weight,
+            }
+        }).collect();
+        let total: u64 = weights.iter().sum();
+        if total == 0 {
+            let index = costs.iter().position(|&cost| cost == cheapest).unwrap_or(0);
+            return alternatives.swap_remove(index);
+        }
+        let mut pick = self.rng.below(total);
+        for (alternative, weight) in alternatives.iter().zip(weights) {
+            if pick < weight {
+                return alternative.clone();
+            }
+            pick -= weight;
+        }
+        unreachable!()
+    }
+
+    /// Random text matched in full by the anchored terminal `regex`.
+    fn sample(&mut self, pattern: &str, regex: &regex::Regex, fallback: Option<&str>) -> String {
+        let hir = self.patterns.entry(pattern.to_string())
+            .or_insert_with(|| regex_syntax::Parser::new().parse(pattern).ok())
+            .clone();
+        let mut text = String::new();
+        if let Some(hir) = hir {
+            for _ in 0..10 {
+                text.clear();
+                self.hir(&hir, &mut text);
+                if regex.find(&text).map(|m| m.end()) == Some(text.len()) {
+                    return text;
+                }
+            }
+        }
+        fallback.map_or(text, str::to_string)
+    }
+
+    fn hir(&mut self, hir: &Hir, out: &mut String) {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => (),
+            HirKind::Literal(Literal::Unicode(c)) => out.push(*c),
+            HirKind::Literal(Literal::Byte(b)) => out.push(*b as char),
+            HirKind::Class(Class::Unicode(class)) => {
+                let ranges: Vec<(u32, u32)> = class.ranges().iter().map(|r| (r.start() as u32, r.end() as u32)).collect();
+                out.push(self.pick(&ranges));
+            }
+            HirKind::Class(Class::Bytes(class)) => {
+                let ranges: Vec<(u32, u32)> = class.ranges().iter().map(|r| (r.start() as u32, r.end().min(0x7f) as u32)).filter(|r| r.0 <= r.1).collect();
+                out.push(self.pick(&ranges));
+            }
+            HirKind::Repetition(repetition) => {
+                let (min, max) = match &repetition.kind {
+                    RepetitionKind::ZeroOrOne => (0, Some(1)),
+                    RepetitionKind::ZeroOrMore => (0, None),
+                    RepetitionKind::OneOrMore => (1, None),
+                    RepetitionKind::Range(RepetitionRange::Exactly(n)) => (*n, Some(*n)),
+                    RepetitionKind::Range(RepetitionRange::AtLeast(n)) => (*n, None),
+                    RepetitionKind::Range(RepetitionRange::Bounded(m, n)) => (*m, Some(*n)),
+                };
+                let max = max.unwrap_or(u32::MAX).min(min.saturating_add(self.max_repeat as u32));
+                let count = min + self.rng.below((max - min) as u64 + 1) as u32;
+                for _ in 0..count {
+                    self.hir(&repetition.hir, out);
+                }
+            }
+            HirKind::Group(group) => self.hir(&group.hir, out),
+            HirKind::Concat(hirs) => hirs.iter().for_each(|hir| self.hir(hir, out)),
+            HirKind::Alternation(hirs) => {
+                let index = self.rng.below(hirs.len() as u64) as usize;
+                self.hir(&hirs[index], out);
+            }
+        }
+    }
+
+    /// A character from `ranges`, preferring printable ASCII, tab and newline.
+    fn pick(&mut self, ranges: &[(u32, u32)]) -> char {
+        let preferred: Vec<(u32, u32)> = ranges.iter()
+            .flat_map(|&(start, end)| [(start.max(0x20), end.min(0x7e)), (start.max(0x09), end.min(0x0a))])
+            .filter(|r| r.0 <= r.1)
+            .collect();
+        let ranges = if preferred.is_empty() {ranges} else {&preferred[..]};
+        let total: u64 = ranges.iter().map(|r| (r.1 - r.0) as u64 + 1).sum();
+        if total == 0 {
+            return ' ';
+        }
+        let mut pick = self.rng.below(total);
+        for &(start, end) in ranges {
+            let size = (end - start) as u64 + 1;
+            if pick < size {
+                return char::from_u32(start + pick as u32).unwrap_or(' ');
+            }
+            pick -= size;
+        }
+        ' '
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::Grammar;
+
+    const JSON: &str = r#"
+        value  = ws (object | array | string | number | "true" | "false" | "null") ws ;
+        object = ~"{" ws flat(list(pair) (~"," list(pair))*)? ~"}" ;
+        pair   = ws string ws ~":" value ;
+        array  = ~"[" ws flat(value (~"," value)*)? ~"]" ;
+        string = /"((\\.|[^\\"\n])*)"/1 ;
+        number = /-?(0|[1-9][0-9]*)(\.[0-9]+)?/ ;
+        ws     = ~/[ \n]*/ ;
+    "#;
+
+    #[test]
+    fn generate_ok() {
+        let parser = Grammar::parse(JSON).unwrap().parser(None).unwrap();
+        let mut generator = Generator::new(&parser).seed(7).max_depth(6);
+        let sentences: Vec<String> = (0..50).map(|_| generator.generate().unwrap()).collect();
+        assert!(sentences.iter().all(|s| parser.parse(s).is_ok()));
+        assert!(sentences.iter().any(|s| s.contains('[')) && sentences.iter().any(|s| s.contains('{')));
+        assert!(sentences.iter().all(|s| s.len() < 2000));
+
+        let mut again = Generator::new(&parser).seed(7).max_depth(6);
+        assert_eq!((0..50).map(|_| again.generate().unwrap()).collect::<Vec<_>>(), sentences);
+
+        let alias = Grammar::parse("top = item ; item = /[0-9]/ | \"(\" top \")\" ;").unwrap().parser(None).unwrap();
+        let mut generator = Generator::new(&alias).seed(2);
+        assert!((0..10).all(|_| alias.parse(&generator.generate().unwrap()).is_ok()));
+
+        let mut flat = Generator::new(&parser).seed(1).weight("object", 0).weight("array", 0);
+        assert!((0..20).all(|_| !flat.generate().unwrap().contains(['[', '{'])));
+
+        let mut shallow = Generator::new(&parser).seed(3).max_depth(0);
+        assert!((0..20).all(|_| !shallow.generate().unwrap().trim().starts_with(['[', '{'])));
+
+        let nested = Parser::new(Box::new(|root: &Parser| Parser::regex("x", 0).or(Parser::skip("<").and(root.clone()).and(Parser::skip(">")))));
+        let mut generator = Generator::new(&nested).seed(5).max_depth(4);
+        for _ in 0..20 {
+            let sentence = generator.generate().unwrap();
+            assert!(sentence.ends_with('x') || sentence.ends_with('>'));
+            assert!(nested.parse(&sentence).is_ok());
+        }
+    }
+
+    #[test]
+    fn generate_error() {
+        let custom = Parser::from_func(Arc::new(|_: &Parser, _: &str, position: i32| Err(crate::Failure{position, expected: vec![]})));
+        assert_eq!(Generator::new(&custom).generate(), Err(GenerateError::Custom));
+
+        let greedy = Parser::regex("a*", 0).and(Parser::regex("a", 0));
+        assert_eq!(Generator::new(&greedy).attempts(5).generate(), Err(GenerateError::Attempts(5)));
+        assert_eq!(Generator::new(&Parser::rule("later")).generate(), Err(GenerateError::Undefined("later".to_string())));
+    }
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::Parser;
+
+/// Snapshot files sit next to their input, named `INPUT.expected`.
+const EXTENSION: &str = "expected";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GoldenStatus {
+    Passed,
+    /// The snapshot was missing or different and has been rewritten.
+    Updated,
+    Missing,
+    /// The snapshot differs; holds a line diff from expected (`-`) to actual (`+`).
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoldenCase {
+    pub input: PathBuf,
+    pub status: GoldenStatus,
+}
+
+/// Outcome of a corpus run, one case per input file in path order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GoldenReport {
+    pub cases: Vec<GoldenCase>,
+}
+
+impl GoldenReport {
+    /// True if no case failed or lacked a snapshot.
+    pub fn passed(&self) -> bool {
+        self.cases.iter().all(|case| matches!(case.status, GoldenStatus::Passed | GoldenStatus::Updated))
+    }
+
+    fn count(&self, status: fn(&GoldenStatus) -> bool) -> usize {
+        self.cases.iter().filter(|case| status(&case.status)).count()
+    }
+}
+
+impl fmt::Display for GoldenReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for case in self.cases.iter() {
+            match &case.status {
+                GoldenStatus::Passed => (),
+                GoldenStatus::Updated => writeln!(f, "updated {}", case.input.display())?,
+                GoldenStatus::Missing => writeln!(f, "missing {}.{}", case.input.display(), EXTENSION)?,
+                GoldenStatus::Failed(diff) => write!(f, "FAILED {}\n{}", case.input.display(), diff)?,
+            }
+        }
+        writeln!(f, "{} passed, {} failed, {} updated",
+            self.count(|status| *status == GoldenStatus::Passed),
+            self.count(|status| matches!(status, GoldenStatus::Failed(_) | GoldenStatus::Missing)),
+            self.count(|status| *status == GoldenStatus::Updated))
+    }
+}
+
+impl Parser {
+    /// Parses every file under `dir` and compares the result with its snapshot: the
+    /// `Value::to_tree` rendering, or the `ParseError` line for inputs that fail.
+    /// With `update`, differing or missing snapshots are rewritten instead.
+    pub fn golden(&self, dir: impl AsRef<Path>, update: bool) -> io::Result<GoldenReport> {
+        let mut inputs = vec![];
+        collect(dir.as_ref(), &mut inputs)?;
+        inputs.sort();
+        let mut report = GoldenReport::default();
+        for input in inputs {
+            let actual = self.snapshot(&fs::read_to_string(&input)?);
+            let path = snapshot_path(&input);
+            let expected = match fs::read_to_string(&path) {
+                Ok(expected) => Some(expected),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            let status = match expected {
+                Some(expected) if expected == actual => GoldenStatus::Passed,
+                _ if update => {
+                    fs::write(&path, &actual)?;
+                    GoldenStatus::Updated
+                }
+                Some(expected) => GoldenStatus::Failed(diff(&expected, &actual)),
+                None => GoldenStatus::Missing,
+            };
+            report.cases.push(GoldenCase{input, status});
+        }
+        Ok(report)
+    }
+
+    fn snapshot(&self, source: &str) -> String {
+        match self.parse(source) {
+            Ok(success) => success.value.to_tree(),
+            Err(failure) => format!("{}\n", failure.to_error(source)),
+        }
+    }
+}
+
+fn snapshot_path(input: &Path) -> PathBuf {
+    let mut name = input.as_os_str().to_owned();
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+fn collect(dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(&path, out)?;
+        } else if path.extension().is_none_or(|extension| extension != EXTENSION) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Unchanged lines kept around each change.
+const CONTEXT: usize = 2;
+
+/// A line diff from the longest common subsequence, with `...` for elided unchanged runs.
+fn diff(expected: &str, actual: &str) -> String {
+    let (a, b): (Vec<&str>, Vec<&str>) = (expected.lines().collect(), actual.lines().collect());
+    let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {common[i + 1][j + 1] + 1} else {common[i + 1][j].max(common[i][j + 1])};
+        }
+    }
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(('-', a[i]));
+            i += 1;
+        } else {
+            lines.push(('+', b[j]));
+            j += 1;
+        }
+    }
+    let changed: Vec<usize> = (0..lines.len()).filter(|&k| lines[k].0 != ' ').collect();
+    let near = |k: usize| changed.iter().any(|&c| c.abs_diff(k) <= CONTEXT);
+    let mut out = String::new();
+    let mut elided = false;
+    for (k, (mark, line)) in lines.iter().enumerate() {
+        if near(k) {
+            out.push_str(&format!("{} {}\n", mark, line));
+            elided = false;
+        } else if !elided {
+            out.push_str("  ...\n");
+            elided = true;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn corpus(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pcc2-golden-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        for (path, text) in files {
+            fs::write(dir.join(path), text).unwrap();
+        }
+        dir
+    }
+
+    fn parser() -> Parser {
+        Grammar::parse("list = ~\"(\" flat(item (~\",\" item)*) ~\")\" ;\nitem = /[a-z]+/ | list ;\n").unwrap().parser(None).unwrap()
+    }
+
+    #[test]
+    fn golden_ok() {
+        let dir = corpus("ok", &[
+            ("a.txt", "(a,(b,c))"),
+            ("a.txt.expected", "list\n  \"a\"\n  list\n    \"b\"\n    \"c\"\n"),
+            ("nested/bad.txt", "(a,\nb;)"),
+            ("nested/bad.txt.expected", "parse error at line 1, column 3: expected \"\\\\)\"\n"),
+        ]);
+        let report = parser().golden(&dir, false).unwrap();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.cases.len(), 2);
+        assert_eq!(report.to_string(), "2 passed, 0 failed, 0 updated\n");
+
+        fs::write(dir.join("new.txt"), "(x)").unwrap();
+        fs::write(dir.join("a.txt"), "(a)").unwrap();
+        let report = parser().golden(&dir, true).unwrap();
+        assert!(report.passed());
+        assert_eq!(report.cases.iter().filter(|case| case.status == GoldenStatus::Updated).count(), 2);
+        assert_eq!(fs::read_to_string(dir.join("new.txt.expected")).unwrap(), "list\n  \"x\"\n");
+        assert!(parser().golden(&dir, false).unwrap().cases.iter().all(|case| case.status == GoldenStatus::Passed));
+    }
+
+    #[test]
+    fn golden_error() {
+        let dir = corpus("error", &[
+            ("a.txt", "(a,(b,d),e)"),
+            ("a.txt.expected", "list\n  \"a\"\n  list\n    \"b\"\n    \"c\"\n  \"e\"\n"),
+            ("b.txt", "(b)"),
+        ]);
+        let report = parser().golden(&dir, false).unwrap();
+        assert!(!report.passed());
+        assert_eq!(report.cases[0].status, GoldenStatus::Failed("  ...\n    list\n      \"b\"\n-     \"c\"\n+     \"d\"\n    \"e\"\n".to_string()));
+        assert_eq!(report.cases[1].status, GoldenStatus::Missing);
+        assert!(report.to_string().ends_with("missing {}.expected\n0 passed, 2 failed, 0 updated\n".replace("{}", &dir.join("b.txt").display().to_string()).as_str()));
+        assert!(parser().golden(dir.join("nonexistent"), false).is_err());
+    }
+}
@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{GrammarError, GrammarErrorKind, Parser};
+
+/// A grammar expression as written in a grammar file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// `/pattern/` or `/pattern/group`; `~/pattern/` is skipped (group -1).
+    Regex { pattern: String, group: isize, canonical: Option<String> },
+    /// `"text"`; `~"text"` is skipped.
+    Literal { text: String, skip: bool, canonical: Option<String> },
+    Ref(String),
+    Seq(Vec<Expr>),
+    Alt(Vec<Expr>),
+    Repeat(Box<Expr>),
+    Optional(Box<Expr>),
+    List(Box<Expr>),
+    Flat(Box<Expr>),
+    Memo(Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub expr: Expr,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Named rules loaded from text such as `pair = list(key ~"=" value) ;`.
+/// The first rule is the start rule.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Grammar {
+    pub rules: Vec<Rule>,
+}
+
+struct Scanner {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Scanner {
+    fn new(text: &str) -> Self {
+        Scanner{chars: text.chars().collect(), pos: 0, line: 1, column: 1}
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, GrammarError> {
+        Err(GrammarError{line: self.line, column: self.column, kind: GrammarErrorKind::Syntax(message.into())})
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while !matches!(self.peek(), None | Some('\n')) {
+                    self.bump();
+                }
+            } else if c.is_whitespace() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), GrammarError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.error(format!("expected {:?}", c))
+        }
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        self.skip_space();
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' || (c == '-' && self.pos > start) {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        match self.pos > start && !self.chars[start].is_ascii_digit() {
+            true => Some(self.chars[start..self.pos].iter().collect()),
+            false => None,
+        }
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.bump();
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+
+    /// The body of `/.../`; `\/` stands for `/`, other escapes are kept for the regex.
+    fn regex(&mut self) -> Result<String, GrammarError> {
+        let mut pattern = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return self.error("unterminated pattern"),
+                Some('/') => return Ok(pattern),
+                Some('\\') if self.peek() == Some('/') => {
+                    self.bump();
+                    pattern.push('/');
+                }
+                Some('\\') => {
+                    pattern.push('\\');
+                    if let Some(c) = self.bump() {
+                        pattern.push(c);
+                    }
+                }
+                Some(c) => pattern.push(c),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, GrammarError> {
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                None => return self.error("unterminated string"),
+                Some('"') => return Ok(text),
+                Some('\\') => match self.bump() {
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some('r') => text.push('\r'),
+                    Some(c) => text.push(c),
+                    None => return self.error("unterminated string"),
+                },
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    fn canonical(&mut self) -> Result<Option<String>, GrammarError> {
+        self.skip_space();
+        let save = (self.pos, self.line, self.column);
+        if self.ident().as_deref() == Some("as") {
+            self.expect('"')?;
+            return self.string().map(Some);
+        }
+        (self.pos, self.line, self.column) = save;
+        Ok(None)
+    }
+
+    fn alt(&mut self) -> Result<Expr, GrammarError> {
+        let mut alts = vec![self.seq()?];
+        while self.eat('|') {
+            alts.push(self.seq()?);
+        }
+        Ok(if alts.len() == 1 {alts.pop().unwrap()} else {Expr::Alt(alts)})
+    }
+
+    fn seq(&mut self) -> Result<Expr, GrammarError> {
+        let mut items = vec![];
+        loop {
+            self.skip_space();
+            match self.peek() {
+                Some('|') | Some(')') | Some(';') | Some(',') | None => break,
+                _ => items.push(self.postfix()?),
+            }
+        }
+        match items.len() {
+            0 => self.error("expected an expression"),
+            1 => Ok(items.pop().unwrap()),
+            _ => Ok(Expr::Seq(items)),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, GrammarError> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat('*') {
+                expr = Expr::Repeat(Box::new(expr));
+            } else if self.eat('?') {
+                expr = Expr::Optional(Box::new(expr));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, GrammarError> {
+        self.skip_space();
+        let skip = self.eat('~');
+        match self.peek() {
+            Some('/') => {
+                self.bump();
+                let pattern = self.regex()?;
+                let group = match self.number() {
+                    Some(_) if skip => return self.error("a skipped pattern has no group"),
+                    Some(group) => group as isize,
+                    None => if skip {-1} else {0},
+                };
+                Ok(Expr::Regex{pattern, group, canonical: self.canonical()?})
+            }
+            Some('"') => {
+                self.bump();
+                let text = self.string()?;
+                Ok(Expr::Literal{text, skip, canonical: self.canonical()?})
+            }
+            _ if skip => self.error("expected a pattern or string after '~'"),
+            Some('(') => {
+                self.bump();
+                let expr = self.alt()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            _ => {
+                let name = match self.ident() {
+                    Some(name) => name,
+                    None => return self.error("expected an expression"),
+                };
+                let wrap: Option<fn(Box<Expr>) -> Expr> = match name.as_str() {
+                    "list" => Some(Expr::List),
+                    "flat" => Some(Expr::Flat),
+                    "memo" => Some(Expr::Memo),
+                    _ => None,
+                };
+                match wrap {
+                    Some(wrap) if self.eat('(') => {
+                        let expr = self.alt()?;
+                        self.expect(')')?;
+                        Ok(wrap(Box::new(expr)))
+                    }
+                    _ => Ok(Expr::Ref(name)),
+                }
+            }
+        }
+    }
+
+    fn rule(&mut self) -> Result<Rule, GrammarError> {
+        self.skip_space();
+        let (line, column) = (self.line, self.column);
+        let name = match self.ident() {
+            Some(name) => name,
+            None => return self.error("expected a rule name"),
+        };
+        self.expect('=')?;
+        let expr = self.alt()?;
+        self.expect(';')?;
+        Ok(Rule{name, expr, line, column})
+    }
+}
+
+impl Grammar {
+    pub fn parse(text: &str) -> Result<Grammar, GrammarError> {
+        let mut scanner = Scanner::new(text);
+        let mut grammar = Grammar::default();
+        loop {
+            scanner.skip_space();
+            if scanner.peek().is_none() {
+                return Ok(grammar);
+            }
+            let rule = scanner.rule()?;
+            if grammar.rule(&rule.name).is_some() {
+                return Err(GrammarError{line: rule.line, column: rule.column, kind: GrammarErrorKind::DuplicateRule(rule.name)});
+            }
+            grammar.rules.push(rule);
+        }
+    }
+
+    /// Parses the rules in `text` and adds them, replacing rules with the same name.
+    pub fn extend(&mut self, text: &str) -> Result<Vec<String>, GrammarError> {
+        let rules = Grammar::parse(text)?.rules;
+        let names = rules.iter().map(|rule| rule.name.clone()).collect();
+        for rule in rules {
+            self.define(rule);
+        }
+        Ok(names)
+    }
+
+    pub fn rule(&self, name: &str) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.name == name)
+    }
+
+    /// Adds `rule`, or replaces the rule with the same name.
+    pub fn define(&mut self, rule: Rule) {
+        match self.rules.iter_mut().find(|r| r.name == rule.name) {
+            Some(r) => *r = rule,
+            None => self.rules.push(rule),
+        }
+    }
+
+    /// Builds one parser per rule, in order. Rule references are bound after all rules exist.
+    /// Each parser keeps the whole grammar alive; the rules do not keep each other alive.
+    pub fn compile(&self) -> Result<Vec<Parser>, GrammarError> {
+        let parsers: HashMap<&str, Parser> = self.rules.iter().map(|rule| (rule.name.as_str(), Parser::rule(&rule.name))).collect();
+        let mut bodies = vec![];
+        for rule in self.rules.iter() {
+            let body = compile(&rule.expr, &parsers).map_err(|kind| GrammarError{line: rule.line, column: rule.column, kind})?;
+            parsers[rule.name.as_str()].bind(&body);
+            bodies.push(body);
+        }
+        let bodies = Arc::new(bodies);
+        Ok(self.rules.iter().map(|rule| parsers[rule.name.as_str()].clone().owning(bodies.clone())).collect())
+    }
+
+    /// The index of rule `start`, or of the first rule.
+    pub(crate) fn start(&self, start: Option<&str>) -> Result<usize, GrammarError> {
+        let index = match start {
+            Some(name) => self.rules.iter().position(|rule| rule.name == name),
+            None => if self.rules.is_empty() {None} else {Some(0)},
+        };
+        index.ok_or_else(|| GrammarError{line: 0, column: 0, kind: GrammarErrorKind::UndefinedStart(start.map(str::to_string))})
+    }
+
+    /// The parser for rule `start`, or for the first rule.
+    pub fn parser(&self, start: Option<&str>) -> Result<Parser, GrammarError> {
+        let index = self.start(start)?;
+        Ok(self.compile()?.swap_remove(index))
+    }
+}
+
+fn compile(expr: &Expr, rules: &HashMap<&str, Parser>) -> Result<Parser, GrammarErrorKind> {
+    let all = |exprs: &[Expr]| exprs.iter().map(|expr| compile(expr, rules)).collect::<Result<Vec<Parser>, GrammarErrorKind>>();
+    Ok(match expr {
+        Expr::Regex{pattern, group, canonical} => Parser::try_terminal(pattern, *group, canonical.as_deref()).map_err(GrammarErrorKind::Pattern)?,
+        Expr::Literal{text, skip, canonical} => {
+            Parser::try_terminal(&regex::escape(text), if *skip {-1} else {0}, canonical.as_deref()).map_err(GrammarErrorKind::Pattern)?
+        }
+        Expr::Ref(name) => rules.get(name.as_str()).cloned().ok_or_else(|| GrammarErrorKind::UndefinedRule(name.clone()))?,
+        Expr::Seq(exprs) => all(exprs)?.into_iter().reduce(Parser::and).unwrap(),
+        Expr::Alt(exprs) => all(exprs)?.into_iter().reduce(Parser::or).unwrap(),
+        Expr::Repeat(expr) => compile(expr, rules)?.repeat(),
+        Expr::Optional(expr) => compile(expr, rules)?.or(Parser::skip("")),
+        Expr::List(expr) => compile(expr, rules)?.list(),
+        Expr::Flat(expr) => compile(expr, rules)?.flat(),
+        Expr::Memo(expr) => compile(expr, rules)?.memo(),
+    })
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            '\r' => f.write_str("\\r")?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+fn write_canonical(f: &mut fmt::Formatter, canonical: &Option<String>) -> fmt::Result {
+    match canonical {
+        Some(canonical) => {
+            f.write_str(" as ")?;
+            write_string(f, canonical)
+        }
+        None => Ok(()),
+    }
+}
+
+impl Expr {
+    /// Writes the expression, parenthesized if it binds looser than `precedence`
+    /// (0: alternative, 1: sequence item, 2: operand of `*` or `?`).
+    fn write(&self, f: &mut fmt::Formatter, precedence: u8) -> fmt::Result {
+        let own = match self {
+            Expr::Alt(_) => 0,
+            Expr::Seq(_) => 1,
+            _ => 2,
+        };
+        if own < precedence {
+            f.write_str("(")?;
+            self.write(f, 0)?;
+            return f.write_str(")");
+        }
+        match self {
+            Expr::Regex{pattern, group, canonical} => {
+                f.write_str(if *group < 0 {"~/"} else {"/"})?;
+                f.write_str(&pattern.replace('/', "\\/"))?;
+                f.write_str("/")?;
+                if *group > 0 {
+                    write!(f, "{}", group)?;
+                }
+                write_canonical(f, canonical)
+            }
+            Expr::Literal{text, skip, canonical} => {
+                if *skip {
+                    f.write_str("~")?;
+                }
+                write_string(f, text)?;
+                write_canonical(f, canonical)
+            }
+            Expr::Ref(name) => f.write_str(name),
+            Expr::Seq(exprs) | Expr::Alt(exprs) => {
+                let separator = if own == 0 {" | "} else {" "};
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(separator)?;
+                    }
+                    expr.write(f, own + 1)?;
+                }
+                Ok(())
+            }
+            Expr::Repeat(expr) | Expr::Optional(expr) => {
+                expr.write(f, 2)?;
+                f.write_str(if let Expr::Repeat(_) = self {"*"} else {"?"})
+            }
+            Expr::List(expr) | Expr::Flat(expr) | Expr::Memo(expr) => {
+                let name = match self {
+                    Expr::List(_) => "list",
+                    Expr::Flat(_) => "flat",
+                    _ => "memo",
+                };
+                write!(f, "{}(", name)?;
+                expr.write(f, 0)?;
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} = {} ;", self.name, self.expr)
+    }
+}
+
+impl fmt::Display for Grammar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.rules.iter().try_for_each(|rule| writeln!(f, "{}", rule))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    const JSON: &str = r#"
+        # JSON, with arrays and objects as lists
+        value  = ws (object | array | string | number | /true|false|null/) ws ;
+        object = ~"{" ws flat(list(pair) (~"," pair)*)? ~"}" ;
+        pair   = ws string ws ~":" value ;
+        array  = ~"[" ws flat((value ~",")* value?) ~"]" ;
+        string = /"((\\.|[^\\"])*)"/1 as "\"{}\"" ;
+        number = /-?(0|[1-9][0-9]*)(\.[0-9]+)?/ ;
+        ws     = ~/\s*/ ;
+    "#;
+
+    #[test]
+    fn grammar_ok() {
+        let grammar = Grammar::parse(JSON).unwrap();
+        assert_eq!(grammar.rules.len(), 7);
+        assert_eq!(grammar.rules[1].line, 4);
+        let parser = grammar.parser(None).unwrap();
+        assert_eq!(parser.name(), Some("value"));
+        let result = parser.parse(r#" {"a": [1, "x\"y"], "b": {}} "#).unwrap();
+        assert_eq!(
+            result.value,
+            Value::List(vec![
+                Value::List(vec![
+                    Value::Some("a".to_string()),
+                    Value::List(vec![Value::Some("1".to_string()), Value::Some("x\\\"y".to_string())]),
+                ]),
+                Value::Some("b".to_string()),
+            ]),
+        );
+        let number = grammar.parser(Some("number")).unwrap();
+        assert_eq!(number.parse("-1.5").unwrap().value, Value::Some("-1.5".to_string()));
+
+        let printed = grammar.to_string();
+        assert_eq!(grammar.rules[3].to_string(), r#"array = ~"[" ws flat((value ~",")* value?) ~"]" ;"#);
+        assert_eq!(grammar.rules[4].to_string(), r#"string = /"((\\.|[^\\"])*)"/1 as "\"{}\"" ;"#);
+        assert_eq!(Grammar::parse(&printed).unwrap().rules.iter().map(|r| &r.expr).collect::<Vec<_>>(),
+                   grammar.rules.iter().map(|r| &r.expr).collect::<Vec<_>>());
+
+        let mut grammar = Grammar::parse("a = /x/ ; b = a a ;").unwrap();
+        assert_eq!(grammar.extend("a = /y/ | /a\\/b/ ;").unwrap(), vec!["a".to_string()]);
+        assert_eq!(grammar.to_string(), "a = /y/ | /a\\/b/ ;\nb = a a ;\n");
+        assert_eq!(grammar.parser(Some("b")).unwrap().parse("a/by").unwrap().value,
+                   Value::List(vec![Value::Some("a/b".to_string()), Value::Some("y".to_string())]));
+
+        // Recursive rules are freed with the last of their parsers.
+        let value = Grammar::parse(JSON).unwrap().parser(None).unwrap();
+        let node = Arc::downgrade(&value.node);
+        assert!(value.parse("[[1], {}]").is_ok());
+        drop(value);
+        assert!(node.upgrade().is_none());
+    }
+
+    #[test]
+    fn grammar_error() {
+        let error = Grammar::parse("a = /x/ ;\nb = a |\n  ;").err().unwrap();
+        assert_eq!((error.line, error.column), (3, 3));
+        assert_eq!(error.to_string(), "line 3, column 3: expected an expression");
+
+        let error = Grammar::parse("a = /x ;").err().unwrap();
+        assert_eq!(error.kind, GrammarErrorKind::Syntax("unterminated pattern".to_string()));
+
+        let error = Grammar::parse("a = /x/ ;\na = /y/ ;").err().unwrap();
+        assert_eq!((error.line, error.kind), (2, GrammarErrorKind::DuplicateRule("a".to_string())));
+
+        let error = Grammar::parse("a = /x/ ;\n  b = a c ;").unwrap().compile().err().unwrap();
+        assert_eq!(error, GrammarError{line: 2, column: 3, kind: GrammarErrorKind::UndefinedRule("c".to_string())});
+
+        let error = Grammar::parse("a = /[x/ ;").unwrap().compile().err().unwrap();
+        assert!(matches!(error.kind, GrammarErrorKind::Pattern(_)));
+
+        let error = Grammar::parse("a = /x/ ;").unwrap().parser(Some("z")).err().unwrap();
+        assert_eq!(error.kind, GrammarErrorKind::UndefinedStart(Some("z".to_string())));
+        assert_eq!(error.to_string(), "no rule \"z\" to start from");
+        assert_eq!(Grammar::default().parser(None).err().unwrap().to_string(), "the grammar has no rules");
+    }
+}
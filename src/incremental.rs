@@ -0,0 +1,383 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
+
+use regex::Regex;
+
+use crate::analysis::{self, PatternInfo};
+use crate::instrument::{self, Wrap};
+use crate::{key, EditError, Failure, Node, Parser, ParserFunc, Success, Value};
+
+#[derive(Clone)]
+struct Entry {
+    result: Result<Success, Failure>,
+    /// Exclusive end of the input this result depended on.
+    examined: i32,
+    fresh: bool,
+}
+
+struct MemoState {
+    table: HashMap<(usize, i32), Entry>,
+    examined: i32,
+    reused: usize,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<MemoState>> = const { RefCell::new(None) };
+}
+
+/// Records that the parse looked at `source` up to `examined` (exclusive).
+fn examine(examined: i32) {
+    STATE.with(|state| {
+        if let Some(state) = state.borrow_mut().as_mut() {
+            state.examined = state.examined.max(examined);
+        }
+    })
+}
+
+/// What a terminal's result depends on besides the text it matched.
+struct Lookahead {
+    pattern: String,
+    info: PatternInfo,
+    /// Matches the texts some match of the pattern starts with; built on first use.
+    prefixes: OnceLock<Option<Regex>>,
+}
+
+impl Lookahead {
+    fn new(pattern: &str) -> Self {
+        Lookahead{pattern: pattern.to_string(), info: PatternInfo::new(pattern), prefixes: OnceLock::new()}
+    }
+
+    /// The length of the longest start of `text` that a match could begin with, given
+    /// that its first `known` bytes are one. The input after it cannot change the match.
+    fn longest(&self, text: &str, known: usize) -> usize {
+        let prefixes = self.prefixes.get_or_init(|| {
+            analysis::prefixes(&self.pattern).and_then(|prefixes| Regex::new(&format!("^(?:{})$", prefixes)).ok())
+        });
+        let prefixes = match prefixes {
+            Some(prefixes) => prefixes,
+            None => return text.len(),
+        };
+        let boundary = |mut k: usize| {
+            k = k.min(text.len());
+            while !text.is_char_boundary(k) {
+                k += 1;
+            }
+            k
+        };
+        // Widen the step until a start no match begins with, then narrow down on the last one.
+        let (mut good, mut bad) = (known, None);
+        let mut step = 1;
+        while bad.is_none() {
+            let k = boundary(good + step);
+            if k == good {
+                return good;
+            }
+            if prefixes.is_match(&text[..k]) {
+                good = k;
+                step *= 2;
+            } else {
+                bad = Some(k);
+            }
+        }
+        let mut bad = bad.unwrap();
+        loop {
+            let mid = boundary(good + (bad - good) / 2).max(boundary(good + 1));
+            if mid >= bad {
+                return good;
+            }
+            if prefixes.is_match(&text[..mid]) {
+                good = mid;
+            } else {
+                bad = mid;
+            }
+        }
+    }
+}
+
+/// How far a terminal attempt at `position` depended on the input: up to and including the
+/// first character that no match of its pattern can continue with.
+fn terminal_examined(lookahead: &Lookahead, source: &str, position: i32, result: &Result<Success, Failure>) -> i32 {
+    let info = &lookahead.info;
+    let bounded = info.max_len.map(|len| position + len as i32 + 1);
+    let rest = &source[position as usize..];
+    match result {
+        Ok(success) => match bounded {
+            Some(bounded) => bounded.max(success.position + 1),
+            None => position + lookahead.longest(rest, (success.position - position) as usize) as i32 + 1,
+        },
+        Err(_) => match rest.chars().next() {
+            None => position + 1,
+            Some(c) if !info.nullable && !info.can_start(c) => position + 1,
+            Some(_) => bounded.unwrap_or_else(|| position + lookahead.longest(rest, 0) as i32 + 1),
+        },
+    }
+}
+
+fn memoized(id: usize, position: i32, f: impl FnOnce() -> Result<Success, Failure>) -> Result<Success, Failure> {
+    let outer = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut()?;
+        match state.table.get(&(id, position)) {
+            Some(entry) => {
+                state.examined = state.examined.max(entry.examined);
+                if !entry.fresh {
+                    state.reused += 1;
+                }
+                Some(Err(entry.result.clone()))
+            }
+            None => {
+                let outer = state.examined;
+                state.examined = position;
+                Some(Ok(outer))
+            }
+        }
+    });
+    let outer = match outer {
+        None => return f(),
+        Some(Err(result)) => return result,
+        Some(Ok(outer)) => outer,
+    };
+    let result = f();
+    STATE.with(|state| {
+        if let Some(state) = state.borrow_mut().as_mut() {
+            let examined = state.examined.max(position + 1);
+            state.table.insert((id, position), Entry{result: result.clone(), examined, fresh: true});
+            state.examined = outer.max(examined);
+        }
+    });
+    result
+}
+
+/// Records what terminals examined and memoizes `memo` parsers by their original node,
+/// which is the same in every reparse. Custom parsers may have looked at anything.
+fn tracked(original: &Parser, rebuilt: Parser) -> Parser {
+    let inner = rebuilt.func.clone();
+    let func: ParserFunc = match &*original.node {
+        Node::Regex{pattern, ..} => {
+            let lookahead = Lookahead::new(pattern);
+            Arc::new(move |root: &Parser, s: &str, i: i32| {
+                let result = inner(root, s, i);
+                examine(terminal_examined(&lookahead, s, i, &result));
+                result
+            })
+        }
+        Node::Memo(_) => {
+            let id = key(original);
+            Arc::new(move |root: &Parser, s: &str, i: i32| memoized(id, i, || inner(root, s, i)))
+        }
+        Node::Custom => Arc::new(move |root: &Parser, s: &str, i: i32| {
+            examine(s.len() as i32 + 1);
+            inner(root, s, i)
+        }),
+        _ => return rebuilt,
+    };
+    Parser{node: rebuilt.node, func}
+}
+
+impl Parser {
+    /// Marks this parser's results to be cached by position in incremental parses,
+    /// so `reparse` can reuse them after an edit. Other parses are unaffected.
+    pub fn memo(self) -> Self {
+        Parser{func: self.func.clone(), node: Arc::new(Node::Memo(self))}
+    }
+
+    pub fn parse_incremental(&self, s:&str) -> IncrementalParse {
+        self.run_incremental(s.to_string(), HashMap::new(), None)
+    }
+
+    /// Applies `edit` to the source of `previous` and parses it again, reusing
+    /// memoized results whose examined input lies entirely outside the edit.
+    pub fn reparse(&self, previous:&IncrementalParse, edit:&Edit) -> Result<IncrementalParse, EditError> {
+        let len = previous.source.len();
+        if edit.start > edit.end || edit.end > len {
+            return Err(EditError::Range{start: edit.start, end: edit.end, len});
+        }
+        if let Some(&position) = [edit.start, edit.end].iter().find(|&&k| !previous.source.is_char_boundary(k)) {
+            return Err(EditError::CharBoundary{position});
+        }
+        let mut source = previous.source[..edit.start].to_string();
+        source.push_str(&edit.text);
+        source.push_str(&previous.source[edit.end..]);
+        let delta = edit.text.len() as i32 - (edit.end - edit.start) as i32;
+        let (start, end) = (edit.start as i32, edit.end as i32);
+        let mut table = HashMap::new();
+        // Results are keyed by the nodes of the parser that produced them.
+        let same = Arc::ptr_eq(&previous.parser.node, &self.node);
+        for (&(id, position), entry) in previous.table.iter().filter(|_| same) {
+            if entry.examined <= start {
+                table.insert((id, position), Entry{fresh: false, ..entry.clone()});
+            } else if position >= end {
+                let result = match &entry.result {
+                    Ok(success) => Ok(Success{position: success.position + delta, value: success.value.clone()}),
+                    Err(failure) => Err(Failure{position: failure.position + delta, expected: failure.expected.clone()}),
+                };
+                table.insert((id, position + delta), Entry{result, examined: entry.examined + delta, fresh: false});
+            }
+        }
+        Ok(self.run_incremental(source, table, Some((previous, edit, delta))))
+    }
+
+    fn run_incremental(&self, source:String, table:HashMap<(usize, i32), Entry>, previous:Option<(&IncrementalParse, &Edit, i32)>) -> IncrementalParse {
+        let wrap: Wrap = Arc::new(tracked);
+        let parser = instrument::rebuild(self, &wrap);
+        let saved = STATE.with(|state| state.replace(Some(MemoState{table, examined: 0, reused: 0})));
+        let result = parser.parse(&source);
+        let state = STATE.with(|state| state.replace(saved)).unwrap();
+        let changed = match previous {
+            Some((previous, edit, delta)) => changed_ranges(&state.table, &previous.table, edit, delta),
+            None => std::iter::once(0..source.len()).collect(),
+        };
+        IncrementalParse{parser: self.clone(), source, result, table: state.table, changed, reused: state.reused}
+    }
+}
+
+/// Innermost spans of freshly parsed nodes whose result differs from the node at the
+/// corresponding position before the edit.
+fn changed_ranges(table:&HashMap<(usize, i32), Entry>, old:&HashMap<(usize, i32), Entry>, edit:&Edit, delta:i32) -> Vec<Range<usize>> {
+    let inserted_end = (edit.start + edit.text.len()) as i32;
+    let mut spans: Vec<Range<usize>> = vec![];
+    for (&(id, position), entry) in table.iter() {
+        let success = match (&entry.result, entry.fresh) {
+            (Ok(success), true) => success,
+            _ => continue,
+        };
+        let old_position = if position < edit.start as i32 {
+            Some(position)
+        } else if position >= inserted_end {
+            Some(position - delta)
+        } else {
+            None
+        };
+        let unchanged = old_position.is_some_and(|p| match old.get(&(id, p)).map(|old| &old.result) {
+            Some(Ok(old)) => old.value == success.value && old.position - p == success.position - position,
+            _ => false,
+        });
+        if !unchanged && success.position > position {
+            spans.push(position as usize..success.position as usize);
+        }
+    }
+    let mut innermost: Vec<Range<usize>> = spans.iter()
+        .filter(|span| !spans.iter().any(|other| other != *span && span.start <= other.start && other.end <= span.end))
+        .cloned().collect();
+    innermost.sort_by_key(|span| (span.start, span.end));
+    let mut merged: Vec<Range<usize>> = vec![];
+    for span in innermost {
+        match merged.last_mut() {
+            Some(last) if span.start <= last.end => last.end = last.end.max(span.end),
+            _ => merged.push(span),
+        }
+    }
+    merged
+}
+
+/// A text edit: the byte range `start..end` of the old source is replaced by `text`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+pub struct IncrementalParse {
+    /// Keeps the nodes that key `table` alive, so their addresses are not reused.
+    parser: Parser,
+    source: String,
+    result: Result<Success, Failure>,
+    table: HashMap<(usize, i32), Entry>,
+    changed: Vec<Range<usize>>,
+    reused: usize,
+}
+
+impl IncrementalParse {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn result(&self) -> &Result<Success, Failure> {
+        &self.result
+    }
+
+    pub fn value(&self) -> Option<&Value> {
+        self.result.as_ref().ok().map(|success| &success.value)
+    }
+
+    /// Byte ranges of the new source whose parse differs from the previous one.
+    pub fn changed_ranges(&self) -> &[Range<usize>] {
+        &self.changed
+    }
+
+    /// Number of memoized results taken over from the previous parse.
+    pub fn reused(&self) -> usize {
+        self.reused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Reply;
+
+    fn json() -> Parser {
+        let item = Parser::regex("true|false", 0)
+            .or(Parser::regex("-?(0|[1-9][0-9]*)", 0))
+            .or(Parser::skip("\"").and(Parser::regex("[^\"]*", 0)).and(Parser::skip("\"")));
+        let array = Parser::new(Box::new(|root:&Parser|
+            Parser::skip("\\[")
+            .and(root.clone().and(Parser::skip(",")).repeat().and(root.clone().or(Parser::skip(""))).flat())
+            .and(Parser::skip("]"))
+        ));
+        item.or(array).memo()
+    }
+
+    fn edit(start: usize, end: usize, text: &str) -> Edit {
+        Edit{start, end, text: text.to_string()}
+    }
+
+    #[test]
+    fn reparse_ok() {
+        let parser = json();
+        let first = parser.parse_incremental("[1,[2,3],\"x\"]");
+        assert_eq!(first.value(), parser.parse("[1,[2,3],\"x\"]").value().as_ref());
+        assert_eq!(first.reused(), 0);
+
+        let second = parser.reparse(&first, &edit(6, 7, "33")).unwrap();
+        assert_eq!(second.source(), "[1,[2,33],\"x\"]");
+        assert_eq!(second.value(), parser.parse("[1,[2,33],\"x\"]").value().as_ref());
+        assert!(second.reused() >= 2);
+        assert_eq!(second.changed_ranges().to_vec(), vec![(6..8)]);
+
+        let third = parser.reparse(&second, &edit(0, 0, "[\"a\",")).unwrap();
+        let third = parser.reparse(&third, &edit(third.source().len(), third.source().len(), "]")).unwrap();
+        assert_eq!(third.source(), "[\"a\",[1,[2,33],\"x\"]]");
+        assert_eq!(third.value(), parser.parse(third.source()).value().as_ref());
+
+        // The longer alternative of an unbounded pattern can match once the edit lands past its match.
+        let parser = Parser::regex("a+b|a", 0).memo().and(Parser::regex("[a-z]*", 0));
+        let first = parser.parse_incremental("aaac");
+        let second = parser.reparse(&first, &edit(3, 4, "b")).unwrap();
+        assert_eq!(second.value(), parser.parse("aaab").value().as_ref());
+        assert_eq!(second.reused(), 0);
+    }
+
+    #[test]
+    fn reparse_error() {
+        let parser = json();
+        let first = parser.parse_incremental("[true,trux]");
+        assert_eq!(first.result().err_position(), Some(6));
+
+        let second = parser.reparse(&first, &edit(9, 10, "e")).unwrap();
+        assert_eq!(second.value(), Some(&Value::List(vec![Value::Some("true".to_string()), Value::Some("true".to_string())])));
+
+        let third = parser.reparse(&second, &edit(10, 11, "")).unwrap();
+        assert_eq!(third.result().err_position(), parser.parse("[true,true").err_position());
+        assert!(third.result().is_err());
+
+        let error = parser.reparse(&third, &edit(4, 3, "")).err();
+        assert_eq!(error, Some(EditError::Range{start: 4, end: 3, len: 10}));
+        assert!(parser.reparse(&third, &edit(10, 11, "")).is_err());
+        let accented = parser.parse_incremental("[\"é\"]");
+        assert_eq!(parser.reparse(&accented, &edit(3, 3, "x")).err(), Some(EditError::CharBoundary{position: 3}));
+    }
+}
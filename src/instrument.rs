@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{key, Node, Parser};
+
+/// Wraps one rebuilt parser; gets the original parser and its rebuilt copy.
+pub(crate) type Wrap = Arc<dyn Fn(&Parser, Parser) -> Parser + Send + Sync>;
+
+/// How rules and terminals are named in reports: the rule name, `/pattern/` or `~/pattern/`.
+pub(crate) fn label(parser: &Parser) -> Option<String> {
+    match &*parser.node {
+        Node::Rule(name, _) => Some(name.clone()),
+        Node::Regex{pattern, group, ..} if *group < 0 => Some(format!("~/{}/", pattern)),
+        Node::Regex{pattern, ..} => Some(format!("/{}/", pattern)),
+        _ => None,
+    }
+}
+
+/// The parser in grammar-file notation, with rules shown by name.
+pub(crate) fn describe(parser: &Parser) -> String {
+    describe_with(parser, 0)
+}
+
+/// `precedence` as in `Expr`: 0 alternative, 1 sequence item, 2 operand of `*`.
+fn describe_with(parser: &Parser, precedence: u8) -> String {
+    let (own, text) = match &*parser.node {
+        Node::Rule(..) | Node::Regex{..} => (2, label(parser).unwrap()),
+        Node::And(a, b) => (1, format!("{} {}", describe_with(a, 1), describe_with(b, 1))),
+        Node::Or(a, b) => (0, format!("{} | {}", describe_with(a, 0), describe_with(b, 1))),
+        Node::Repeat(p) => (2, format!("{}*", describe_with(p, 2))),
+        Node::List(p) => (2, format!("list({})", describe_with(p, 0))),
+        Node::Flat(p) => (2, format!("flat({})", describe_with(p, 0))),
+        Node::Memo(p) => (2, format!("memo({})", describe_with(p, 0))),
+        Node::Layout(_, p) => return describe_with(p, precedence),
+        Node::Recursive(_) => (2, "<recursive>".to_string()),
+        Node::Root => (2, "<root>".to_string()),
+        Node::Custom => (2, "<custom>".to_string()),
+    };
+    if own < precedence {format!("({})", text)} else {text}
+}
+
+/// Copies the parser graph with `wrap` applied to every node, leaving `parser` untouched,
+/// so instrumentation costs nothing for parsers that were not rebuilt.
+/// The copy keeps the bodies of its rules alive.
+pub(crate) fn rebuild(parser: &Parser, wrap: &Wrap) -> Parser {
+    let mut bodies = vec![];
+    let rebuilt = rebuild_with(parser, wrap, &mut HashMap::new(), &mut bodies);
+    rebuilt.owning(Arc::new(bodies))
+}
+
+fn rebuild_with(parser: &Parser, wrap: &Wrap, done: &mut HashMap<usize, Parser>, bodies: &mut Vec<Parser>) -> Parser {
+    if let Some(rebuilt) = done.get(&key(parser)) {
+        return rebuilt.clone();
+    }
+    let mut child = |p: &Parser| rebuild_with(p, wrap, done, bodies);
+    let rebuilt = match &*parser.node {
+        Node::Rule(name, slot) => {
+            let rule = Parser::rule(name);
+            let wrapped = wrap(parser, rule.clone());
+            done.insert(key(parser), wrapped.clone());
+            if let Some(body) = slot.get() {
+                let body = rebuild_with(&body, wrap, done, bodies);
+                rule.bind(&body);
+                bodies.push(body);
+            }
+            return wrapped;
+        }
+        Node::Regex{..} | Node::Root | Node::Custom => parser.clone(),
+        Node::And(a, b) => child(a).and(child(b)),
+        Node::Or(a, b) => child(a).or(child(b)),
+        Node::Repeat(p) => child(p).repeat(),
+        Node::List(p) => child(p).list(),
+        Node::Flat(p) => child(p).flat(),
+        Node::Memo(p) => child(p).memo(),
+        Node::Layout(layout, p) => child(p).layout(*layout),
+        Node::Recursive(body) => Parser::recursive(child(body)),
+    };
+    let wrapped = wrap(parser, rebuilt);
+    done.insert(key(parser), wrapped.clone());
+    wrapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Reply, Value};
+
+    #[test]
+    fn rebuild_ok() {
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = count.clone();
+        let wrap: Wrap = Arc::new(move |original: &Parser, rebuilt: Parser| {
+            if label(original).is_none() {
+                return rebuilt;
+            }
+            let counter = counter.clone();
+            let inner = rebuilt.func.clone();
+            Parser{node: rebuilt.node, func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                inner(root, s, i)
+            })}
+        });
+        let item = Parser::rule("item");
+        item.define(Parser::regex("[a-z]", 0).or(Parser::skip("\\(").and(item.clone().repeat()).and(Parser::skip("\\)"))));
+        let parser = rebuild(&item, &wrap);
+        assert_eq!(parser.name(), Some("item"));
+        assert_eq!(parser.parse("(ab)").value(), item.parse("(ab)").value());
+        assert_eq!(count.load(std::sync::atomic::Ordering::Relaxed), 11);
+
+        let nested = Parser::new(Box::new(|root: &Parser| Parser::regex("x", 0).or(Parser::skip("\\[").and(root.clone()).and(Parser::skip("]")))));
+        assert_eq!(rebuild(&nested, &wrap).parse("[[x]]").value(), Some(Value::Some("x".to_string())));
+    }
+}
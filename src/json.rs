@@ -0,0 +1,399 @@
+use crate::{JsonError, Value};
+
+pub(crate) fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// A parsed JSON document, each node with the byte offset it starts at.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    /// The number as written.
+    Number(String),
+    String(String),
+    Array(Vec<(usize, Json)>),
+    Object(Vec<(String, (usize, Json))>),
+}
+
+impl Json {
+    fn name(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(_) => "a boolean",
+            Json::Number(_) => "a number",
+            Json::String(_) => "a string",
+            Json::Array(_) => "an array",
+            Json::Object(_) => "an object",
+        }
+    }
+}
+
+fn error(position: usize, message: impl Into<String>) -> JsonError {
+    JsonError{position, message: message.into()}
+}
+
+/// How deeply arrays and objects may nest, so hostile input cannot exhaust the stack.
+const MAX_DEPTH: usize = 256;
+
+/// Whether `text` is a number by the JSON grammar: `-? (0 | [1-9][0-9]*) (.[0-9]+)? ([eE][+-]?[0-9]+)?`.
+fn is_number(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while bytes.get(*i).is_some_and(u8::is_ascii_digit) {
+            *i += 1;
+        }
+        *i > start
+    };
+    if bytes.get(i) == Some(&b'-') {
+        i += 1;
+    }
+    match bytes.get(i) {
+        Some(b'0') => i += 1,
+        Some(b'1'..=b'9') => {
+            digits(&mut i);
+        }
+        _ => return false,
+    }
+    if bytes.get(i) == Some(&b'.') {
+        i += 1;
+        if !digits(&mut i) {
+            return false;
+        }
+    }
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        i += 1;
+        if matches!(bytes.get(i), Some(b'+' | b'-')) {
+            i += 1;
+        }
+        if !digits(&mut i) {
+            return false;
+        }
+    }
+    i == bytes.len()
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn document(text: &'a str) -> Result<(usize, Json), JsonError> {
+        let mut reader = Reader{text, position: 0, depth: 0};
+        let value = reader.value()?;
+        reader.space();
+        match reader.peek() {
+            None => Ok(value),
+            Some(_) => Err(error(reader.position, "trailing characters")),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn space(&mut self) {
+        let rest = &self.text[self.position..];
+        self.position += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.space();
+        let found = self.peek() == Some(c);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), JsonError> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(error(self.position, format!("expected {:?}", c))),
+        }
+    }
+
+    /// Enters an array or object starting at `start`.
+    fn nest(&mut self, start: usize) -> Result<(), JsonError> {
+        self.depth += 1;
+        match self.depth > MAX_DEPTH {
+            true => Err(error(start, format!("nesting deeper than {}", MAX_DEPTH))),
+            false => Ok(()),
+        }
+    }
+
+    fn value(&mut self) -> Result<(usize, Json), JsonError> {
+        self.space();
+        let start = self.position;
+        let rest = &self.text[start..];
+        let json = match self.peek() {
+            Some('"') => Json::String(self.string()?),
+            Some('[') => {
+                self.nest(start)?;
+                self.position += 1;
+                let mut items = vec![];
+                if !self.eat(']') {
+                    loop {
+                        items.push(self.value()?);
+                        if self.eat(']') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                self.depth -= 1;
+                Json::Array(items)
+            }
+            Some('{') => {
+                self.nest(start)?;
+                self.position += 1;
+                let mut members = vec![];
+                if !self.eat('}') {
+                    loop {
+                        self.space();
+                        if self.peek() != Some('"') {
+                            return Err(error(self.position, "expected a member name"));
+                        }
+                        let name = self.string()?;
+                        self.expect(':')?;
+                        members.push((name, self.value()?));
+                        if self.eat('}') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                self.depth -= 1;
+                Json::Object(members)
+            }
+            _ if rest.starts_with("null") => self.word("null", Json::Null),
+            _ if rest.starts_with("true") => self.word("true", Json::Bool(true)),
+            _ if rest.starts_with("false") => self.word("false", Json::Bool(false)),
+            Some('-' | '0'..='9') => {
+                let length = rest.find(|c: char| !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9')).unwrap_or(rest.len());
+                let number = &rest[..length];
+                if !is_number(number) {
+                    return Err(error(start, format!("invalid number {:?}", number)));
+                }
+                self.position += length;
+                Json::Number(number.to_string())
+            }
+            Some(_) => return Err(error(start, "expected a value")),
+            None => return Err(error(start, "unexpected end of input")),
+        };
+        Ok((start, json))
+    }
+
+    fn word(&mut self, word: &str, json: Json) -> Json {
+        self.position += word.len();
+        json
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        let start = self.position;
+        self.position += 1;
+        let mut out = String::new();
+        loop {
+            let c = self.peek().ok_or_else(|| error(start, "unterminated string"))?;
+            self.position += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escape = self.peek().ok_or_else(|| error(start, "unterminated string"))?;
+                    self.position += escape.len_utf8();
+                    out.push(match escape {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode()?,
+                        _ => return Err(error(self.position - 2, format!("invalid escape \\{}", escape))),
+                    });
+                }
+                c if (c as u32) < 0x20 => return Err(error(self.position - 1, "control character in string")),
+                c => out.push(c),
+            }
+        }
+    }
+
+    /// The code point of a `\uXXXX` escape, joining UTF-16 surrogate pairs.
+    fn unicode(&mut self) -> Result<char, JsonError> {
+        let start = self.position - 2;
+        let high = self.hex()?;
+        let code = match high {
+            0xd800..=0xdbff if self.text[self.position..].starts_with("\\u") => {
+                self.position += 2;
+                let low = self.hex()?;
+                if !(0xdc00..=0xdfff).contains(&low) {
+                    return Err(error(start, "invalid surrogate pair"));
+                }
+                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+            }
+            code => code,
+        };
+        char::from_u32(code).ok_or_else(|| error(start, "invalid unicode escape"))
+    }
+
+    fn hex(&mut self) -> Result<u32, JsonError> {
+        let digits = self.text.get(self.position..self.position + 4).filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()));
+        let code = digits.ok_or_else(|| error(self.position, "expected four hex digits"))?;
+        self.position += 4;
+        Ok(u32::from_str_radix(code, 16).unwrap())
+    }
+}
+
+/// Natural encoding: `null`, strings and arrays; numbers and booleans become their text.
+fn natural((position, json): (usize, Json)) -> Result<Value, JsonError> {
+    match json {
+        Json::Null => Ok(Value::None),
+        Json::Bool(b) => Ok(Value::Some(b.to_string())),
+        Json::Number(n) | Json::String(n) => Ok(Value::Some(n)),
+        Json::Array(items) => items.into_iter().map(natural).collect::<Result<_, _>>().map(Value::List),
+        Json::Object(_) => Err(error(position, "objects have no natural Value encoding")),
+    }
+}
+
+/// Tagged encoding: `{"none": null}`, `{"some": "text"}` or `{"list": [...]}`.
+fn tagged((position, json): (usize, Json)) -> Result<Value, JsonError> {
+    let mut members = match json {
+        Json::Object(members) if members.len() == 1 => members,
+        json => return Err(error(position, format!("expected an object with one tag, found {}", json.name()))),
+    };
+    let (tag, (position, json)) = members.pop().unwrap();
+    match (tag.as_str(), json) {
+        ("none", Json::Null) => Ok(Value::None),
+        ("some", Json::String(s)) => Ok(Value::Some(s)),
+        ("list", Json::Array(items)) => items.into_iter().map(tagged).collect::<Result<_, _>>().map(Value::List),
+        ("none" | "some" | "list", json) => Err(error(position, format!("tag {:?} cannot hold {}", tag, json.name()))),
+        _ => Err(error(position, format!("unknown tag {:?}", tag))),
+    }
+}
+
+impl Value {
+    /// JSON with lists as arrays, strings as strings and `None` as `null`.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Value::None => out.push_str("null"),
+            Value::Some(s) => write_string(out, s),
+            Value::List(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write_json(out);
+                }
+                out.push(']');
+            }
+        }
+    }
+
+    /// JSON naming each variant: `{"none":null}`, `{"some":"text"}` and `{"list":[...]}`.
+    pub fn to_tagged_json(&self) -> String {
+        let mut out = String::new();
+        self.write_tagged_json(&mut out);
+        out
+    }
+
+    fn write_tagged_json(&self, out: &mut String) {
+        match self {
+            Value::None => out.push_str("{\"none\":null}"),
+            Value::Some(s) => {
+                out.push_str("{\"some\":");
+                write_string(out, s);
+                out.push('}');
+            }
+            Value::List(items) => {
+                out.push_str("{\"list\":[");
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write_tagged_json(out);
+                }
+                out.push_str("]}");
+            }
+        }
+    }
+
+    /// Reads the natural encoding of `to_json`. Numbers and booleans are read as their text.
+    pub fn from_json(text: &str) -> Result<Value, JsonError> {
+        natural(Reader::document(text)?)
+    }
+
+    /// Reads the encoding of `to_tagged_json`.
+    pub fn from_tagged_json(text: &str) -> Result<Value, JsonError> {
+        tagged(Reader::document(text)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_json_ok() {
+        let value = Value::List(vec![Value::Some("a\"\n".to_string()), Value::None, Value::List(vec![])]);
+        assert_eq!(value.to_json(), "[\"a\\\"\\n\",null,[]]");
+        assert_eq!(value.to_tagged_json(), "{\"list\":[{\"some\":\"a\\\"\\n\"},{\"none\":null},{\"list\":[]}]}");
+        assert_eq!(Value::from_json(&value.to_json()), Ok(value.clone()));
+        assert_eq!(Value::from_tagged_json(&value.to_tagged_json()), Ok(value));
+
+        let value = Value::from_json(" [ \"\\u00e9\\ud83d\\ude00\\/\" , 12.5e3, true, [ ] ] ").unwrap();
+        assert_eq!(value, Value::List(vec![Value::Some("\u{e9}\u{1f600}/".to_string()), Value::Some("12.5e3".to_string()), Value::Some("true".to_string()), Value::List(vec![])]));
+        assert_eq!(Value::from_tagged_json("{ \"some\" : \"\u{e9}\" }"), Ok(Value::Some("\u{e9}".to_string())));
+    }
+
+    #[test]
+    fn to_json_error() {
+        let failure = |result: Result<Value, JsonError>| result.map_err(|e| (e.position, e.message)).unwrap_err();
+        assert_eq!(failure(Value::from_json("[\"a\",]")), (5, "expected a value".to_string()));
+        assert_eq!(failure(Value::from_json("[1 2]")), (3, "expected ','".to_string()));
+        assert_eq!(failure(Value::from_json("\"abc")), (0, "unterminated string".to_string()));
+        assert_eq!(failure(Value::from_json("null x")), (5, "trailing characters".to_string()));
+        assert_eq!(failure(Value::from_json("[{\"a\":1}]")), (1, "objects have no natural Value encoding".to_string()));
+        assert_eq!(failure(Value::from_json("\"\\x\"")), (1, "invalid escape \\x".to_string()));
+        assert_eq!(failure(Value::from_json("-")), (0, "invalid number \"-\"".to_string()));
+        assert_eq!(failure(Value::from_json("")), (0, "unexpected end of input".to_string()));
+        for number in ["01", "1.", "1.e5", "-01", "-.5", "1e", "1e+", "1-2"] {
+            assert_eq!(failure(Value::from_json(number)), (0, format!("invalid number {:?}", number)));
+        }
+        for number in ["0", "-0", "10", "0.5", "1e5", "1E-5", "-12.5e+3"] {
+            assert_eq!(Value::from_json(number), Ok(Value::Some(number.to_string())));
+        }
+        assert_eq!(failure(Value::from_json(&"[".repeat(100_000))), (256, "nesting deeper than 256".to_string()));
+        let nested = "[".repeat(256) + &"]".repeat(256);
+        assert!(Value::from_json(&nested).is_ok());
+
+        assert_eq!(failure(Value::from_tagged_json("[]")), (0, "expected an object with one tag, found an array".to_string()));
+        assert_eq!(failure(Value::from_tagged_json("{\"list\":[{\"some\":1}]}")), (17, "tag \"some\" cannot hold a number".to_string()));
+        assert_eq!(failure(Value::from_tagged_json("{\"text\":\"a\"}")), (8, "unknown tag \"text\"".to_string()));
+        assert_eq!(Value::from_json("[1").unwrap_err().to_string(), "invalid JSON at byte 2: expected ','");
+    }
+}
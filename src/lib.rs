@@ -0,0 +1,907 @@
+use std::sync::{Arc, OnceLock, Weak};
+use regex::Regex;
+
+
+mod analysis;
+mod batch;
+mod builder;
+mod codegen;
+mod coverage;
+mod cst;
+mod diagram;
+mod error;
+mod format;
+mod generate;
+mod golden;
+mod grammar;
+mod json;
+mod mutate;
+mod optimize;
+mod profile;
+mod query;
+mod repl;
+mod select;
+mod syntax;
+mod trace;
+mod tree;
+mod incremental;
+mod instrument;
+mod unparse;
+mod visit;
+mod vm;
+
+pub use builder::GrammarBuilder;
+pub use coverage::{ChoiceCoverage, Coverage, CoverageRecorder, RepeatCoverage};
+pub use cst::{Cst, CstTree, Trivia};
+pub use error::{BuildError, CompileError, EditError, GenerateError, GrammarError, GrammarErrorKind, JsonError, ParseError, PatternError, QueryError, RewriteError, SelectorError, UnparseError};
+pub use format::{Break, Doc, Layout};
+pub use generate::Generator;
+pub use golden::{GoldenCase, GoldenReport, GoldenStatus};
+pub use grammar::{Expr, Grammar, Rule};
+pub use incremental::{Edit, IncrementalParse};
+pub use mutate::Mutator;
+pub use profile::{Profile, ProfileEntry, Profiler};
+pub use query::{Query, QueryMatch};
+pub use repl::Repl;
+pub use select::{Selection, Selector};
+pub use syntax::SyntaxNode;
+pub use trace::{Trace, TraceEvent, TraceKind};
+pub use visit::{Bindings, Pattern, Rewriter, Visitor};
+pub use vm::Program;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,
+    Some(String),
+    List(Vec<Value>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Success {
+    pub position: i32,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub position: i32,
+    pub expected: Vec<String>,
+}
+pub trait Reply {
+    fn position(&self) -> i32;
+    fn err_position(&self) -> Option<i32>;
+    fn value(&self) -> Option<Value>;
+    fn expected(&self) -> Option<Vec<String>>;
+}
+
+impl Reply for Result<Success, Failure> {
+    fn position(&self) -> i32 {
+        match self {
+            Ok(success) => success.position,
+            Err(failure) => failure.position,
+        }
+    }
+
+    fn err_position(&self) -> Option<i32> {
+        match self {
+            Ok(_) => None,
+            Err(failure) => Some(failure.position),
+        }
+    }
+
+    fn value(&self) -> Option<Value> {
+        match self {
+            Ok(success) => Some(success.value.clone()),
+            Err(_) => None,
+        }
+    }
+
+    fn expected(&self) -> Option<Vec<String>> {
+        match self {
+            Ok(_) => None,
+            Err(failure) => Some(failure.expected.to_vec()),
+        }
+    }
+}
+
+pub type ParserFunc = Arc<dyn Fn(&Parser, &str, i32) -> Result<Success, Failure> + Send + Sync>;
+
+type WeakParserFunc = Weak<dyn Fn(&Parser, &str, i32) -> Result<Success, Failure> + Send + Sync>;
+
+pub type ParserBuilder = Arc<dyn Fn(&Parser) -> Parser + Send + Sync>;
+
+/// What a parser was built from, so a grammar can be inspected after construction.
+pub enum Node {
+    Regex{pattern:String, group:isize, canonical:Option<String>, regex:Box<Regex>, func:TerminalFunc},
+    And(Parser, Parser),
+    Or(Parser, Parser),
+    Repeat(Parser),
+    List(Parser),
+    Flat(Parser),
+    Memo(Parser),
+    /// Formatting annotation; parses exactly like the inner parser.
+    Layout(Layout, Parser),
+    /// A parser made by `Parser::new`, with the body its builder returned.
+    Recursive(Parser),
+    /// The parser a parse started from, as passed to the builder of `Parser::new`.
+    Root,
+    /// A named rule whose body is bound later with `define`, so rules can refer to each other.
+    Rule(String, Arc<Slot>),
+    /// A parser built directly from a function.
+    Custom,
+}
+
+/// The body of a rule, bound once.
+#[derive(Default)]
+pub struct Slot(OnceLock<Binding>);
+
+enum Binding {
+    Strong(Parser),
+    /// A body kept alive by the parsers of a compiled grammar or a rebuilt copy, so rules
+    /// referring to each other do not form a cycle.
+    Weak(WeakParserFunc, Weak<Node>),
+}
+
+impl Slot {
+    /// The body, unless it is unbound or its owner is gone.
+    pub fn get(&self) -> Option<Parser> {
+        match self.0.get()? {
+            Binding::Strong(body) => Some(body.clone()),
+            Binding::Weak(func, node) => Some(Parser{func: func.upgrade()?, node: node.upgrade()?}),
+        }
+    }
+}
+
+/// A parser's identity: the address of its node, which its clones share.
+pub(crate) fn key(parser: &Parser) -> usize {
+    Arc::as_ptr(&parser.node) as *const () as usize
+}
+
+/// The function `try_terminal` built for a terminal, to tell it apart from wrappers of it.
+pub struct TerminalFunc(WeakParserFunc);
+
+impl TerminalFunc {
+    /// Whether `func` is the terminal's own function.
+    pub fn is(&self, func: &ParserFunc) -> bool {
+        Weak::ptr_eq(&self.0, &Arc::downgrade(func))
+    }
+}
+
+#[derive(Clone)]
+pub struct Parser
+{
+    pub func:ParserFunc,
+    pub node:Arc<Node>,
+}
+
+
+impl Parser {
+    /// A parser built once by `p2p`, which gets a stand-in for the parser each parse starts from.
+    pub fn new(p2p:Box<dyn Fn(&Parser) -> Parser + Send + Sync>)->Self {
+        let root = Parser{node: Arc::new(Node::Root), func: Arc::new(|root:&Parser, source: &str, position: i32| (root.func)(root, source, position))};
+        Parser::recursive(p2p(&root))
+    }
+    pub(crate) fn recursive(body:Parser)->Self {
+        let func = body.func.clone();
+        Parser{node: Arc::new(Node::Recursive(body)), func}
+    }
+    pub fn from_func(func:ParserFunc)->Self {
+        Parser{func, node:Arc::new(Node::Custom)}
+    }
+    pub fn rule(name:&str)->Self {
+        let slot = Arc::new(Slot::default());
+        let body = slot.clone();
+        let node = Arc::new(Node::Rule(name.to_string(), slot));
+        Parser{node, func:Arc::new(move |root:&Parser, source: &str, position: i32| match body.0.get() {
+            Some(Binding::Strong(parser)) => (parser.func)(root, source, position),
+            Some(Binding::Weak(func, _)) => match func.upgrade() {
+                Some(func) => func(root, source, position),
+                None => Err(Failure{position, expected: vec![]}),
+            },
+            None => Err(Failure{position, expected: vec![]}),
+        })}
+    }
+    /// Binds the body of a rule made by `Parser::rule`. Returns false if it is not an unbound rule.
+    pub fn define(&self, body:Parser)->bool {
+        match &*self.node {
+            Node::Rule(_, slot) => slot.0.set(Binding::Strong(body)).is_ok(),
+            _ => false,
+        }
+    }
+    /// Like `define`, but without keeping `body` alive; the caller has to, with `owning`.
+    pub(crate) fn bind(&self, body:&Parser)->bool {
+        match &*self.node {
+            Node::Rule(_, slot) => slot.0.set(Binding::Weak(Arc::downgrade(&body.func), Arc::downgrade(&body.node))).is_ok(),
+            _ => false,
+        }
+    }
+    /// This parser, keeping `owned` alive as long as it is.
+    pub(crate) fn owning(self, owned:Arc<Vec<Parser>>)->Self {
+        let func = self.func.clone();
+        Parser{node: self.node, func:Arc::new(move |root:&Parser, source: &str, position: i32| {
+            let _ = &owned;
+            func(root, source, position)
+        })}
+    }
+    pub fn name(&self)->Option<&str> {
+        match &*self.node {
+            Node::Rule(name, _) => Some(name),
+            _ => None,
+        }
+    }
+    pub fn parse(&self, s:&str)->Result<Success, Failure> {
+        let success = (self.func)(self, s, 0)?;
+        // Positions are byte offsets, so the whole input is consumed at `s.len()`, not at its char count.
+        if success.position < s.len() as i32 {
+            return Err(Failure{position: success.position, expected:vec!["no length".to_string()]});
+        }
+        Ok(success)
+    }
+    pub fn try_parse(&self, s:&str)->Result<Value, ParseError> {
+        self.parse(s).map(|success| success.value).map_err(|failure| failure.to_error(s))
+    }
+    pub fn and(self, p:Self)->Self {
+        let node = Arc::new(Node::And(self.clone(), p.clone()));
+        Parser{node, func:Arc::new(move |root:&Self, s:&str, i:i32| {
+            let result1 = (self.func)(root, s, i)?;
+            let result2 = (p.func)(root, s, result1.position)?;
+            let mut v = Vec::<Value>::new();
+            if result1.value != Value::None {
+                v.push(result1.value);
+            }
+            if result2.value != Value::None {
+                v.push(result2.value);
+            }
+            Ok(Success{position: result2.position, value: 
+                match v.len() {
+                    0 => Value::None,
+                    1 => v[0].clone(),
+                    _ => Value::List(v),
+                }})
+        })}
+    }
+
+    pub fn list(self)->Self {
+        let node = Arc::new(Node::List(self.clone()));
+        Parser{node, func:Arc::new(move |root:&Self, s:&str, i:i32| {
+            let mut result1 = (self.func)(root, s, i)?;
+            if result1.value != Value::None {
+                result1.value = Value::List(vec![result1.value]);
+            }
+            Ok(result1)
+        })}
+    }
+
+    pub fn flat(self)->Self {
+        let node = Arc::new(Node::Flat(self.clone()));
+        Parser{node, func:Arc::new(move |root:&Self, s:&str, i:i32| {
+            let mut result1 = (self.func)(root, s, i)?;
+            if let Value::List(results) = result1.value {
+                let mut v = Vec::<Value>::new();
+                for result in results {
+                    if let Value::List(result_each) = result {
+                        v.extend(result_each)
+                    } else if result != Value::None {
+                        v.push(result)
+                    }
+                }
+                result1.value = match v.len() {0=>Value::None, _=>Value::List(v)};
+            };
+            Ok(result1)
+        })}
+    }
+    pub fn repeat(self)->Self {
+        let node = Arc::new(Node::Repeat(self.clone()));
+        Parser{node, func:Arc::new(move |root:&Self, s:&str, pi:i32| {
+            let mut v = Vec::<Value>::new();
+            let mut i = pi;
+            let pos = loop {
+                let result = (self.func)(root, s, i);
+                match result {
+                    Err(_) => break i,
+                    Ok(success) =>{
+                        i = success.position;
+                        if success.value != Value::None {
+                            v.push(success.value);
+                        }
+                    }
+                }
+            };
+            Ok(Success{position: pos, value: Value::List(v)})
+        })}
+    }
+
+    fn merge_errs(e1:Failure, e2:Failure)-> Failure {
+        let mut pos = e1.position;
+        let mut e = Vec::<String>::new();
+        if e1.position >= e2.position {
+            e.extend(e1.expected);
+        }
+        if e1.position <= e2.position {
+            e.extend(e2.expected);
+            pos = e2.position;
+        }
+        Failure{position:pos, expected: e}
+    }
+
+    pub fn or(self, p:Self)->Self {
+        let node = Arc::new(Node::Or(self.clone(), p.clone()));
+        Parser{node, func:Arc::new(move |root:&Self, s:&str, i:i32| {
+            match (self.func)(root, s, i) {
+                Err(e1) => 
+                    match (p.func)(root, s, i){
+                        Err(e2) => Err(Parser::merge_errs(e1, e2)),
+                        ok => ok,
+                    },
+                ok => ok,
+            }
+        })}
+    }
+
+    pub fn skip(pattern: &str) -> Self {
+        Parser::regex(pattern, -1)
+    }
+
+    pub fn try_skip(pattern: &str) -> Result<Self, BuildError> {
+        Parser::try_regex(pattern, -1)
+    }
+
+    /// A skipped terminal printed as `canonical` by `unparse`.
+    pub fn skip_as(pattern: &str, canonical: &str) -> Self {
+        Parser::try_skip_as(pattern, canonical).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_skip_as(pattern: &str, canonical: &str) -> Result<Self, BuildError> {
+        Parser::try_terminal(pattern, -1, Some(canonical))
+    }
+
+    pub fn regex(pattern: &str, group: isize) -> Self {
+        Parser::try_regex(pattern, group).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_regex(pattern: &str, group: isize) -> Result<Self, BuildError> {
+        Parser::try_terminal(pattern, group, None)
+    }
+
+    /// A terminal printed by `unparse` as `template` with `{}` replaced by the value.
+    pub fn regex_as(pattern: &str, group: isize, template: &str) -> Self {
+        Parser::try_terminal(pattern, group, Some(template)).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub(crate) fn try_terminal(pattern: &str, group: isize, canonical: Option<&str>) -> Result<Self, BuildError> {
+        let s = pattern.to_string();
+        // Validate the pattern alone: wrapping it in "^(...)" can make a broken pattern such as "a)|(b" compile.
+        // Group 0 is the whole match, so explicit groups are counted from 1.
+        let groups = Regex::new(&s).map_err(|e| BuildError::Regex{pattern: s.clone(), message: e.to_string()})?.captures_len() - 1;
+        if group > groups as isize {
+            return Err(BuildError::Group{pattern: s, group, groups});
+        }
+        let ptn = "^(".to_string()+s.as_str()+")";
+        let regex = Regex::new(&ptn).map_err(|e| BuildError::Regex{pattern: s.clone(), message: e.to_string()})?;
+        let canonical = match canonical {
+            Some(canonical) if group < 0 => {
+                if regex.find(canonical).map(|m| m.end()) != Some(canonical.len()) {
+                    return Err(BuildError::Canonical{pattern: s, canonical: canonical.to_string()});
+                }
+                Some(canonical.to_string())
+            }
+            Some(template) => Some(template.to_string()),
+            None if group < 0 => analysis::literal(&s).or_else(|| regex.find("").map(|_| String::new())),
+            None => None,
+        };
+        let node_regex = Box::new(regex.clone());
+        let pattern = s.clone();
+        let func: ParserFunc = Arc::new(move |_root:&Self, source: &str, position: i32| -> Result<Success, Failure> {
+            let src = &source[position as usize..source.len()];
+            let captures = regex.captures(src);
+            match captures {
+                Some(caps) => {
+                    let text = if group < 0 {""}else{caps.get(group as usize + 1).map_or("", |m| m.as_str())};
+                    let mat = caps.get(0).unwrap();
+                    Ok(Success {
+                        position: position + (mat.end() - mat.start()) as i32,
+                        value: if group < 0 {Value::None}else{Value::Some(text.to_string())},
+                    })
+                }
+                None => Err(Failure {
+                    position,
+                    expected: vec![s.clone()],
+                })
+            }
+        });
+        let node = Arc::new(Node::Regex{pattern, group, canonical, regex: node_regex, func: TerminalFunc(Arc::downgrade(&func))});
+        Ok(Parser{node, func})
+    }
+}
+
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn and_ok() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser = string("key").and(string(":")).and(string("value"));
+        let result = parser.parse("key:value");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::List(vec![
+                    Value::Some("key".to_string()),
+                    Value::Some(":".to_string()),
+                ]),
+                Value::Some("value".to_string()),
+            ])),
+        );
+    }
+
+    #[test]
+    fn and_error() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser = string("key").and(string(":")).and(string("value"));
+        let result = parser.parse("key:valu");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(4));
+    }
+
+    #[test]
+    fn or_ok() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser = string("x").or(string("y")).or(string("z"));
+        let result = parser.parse("x");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("x".to_string())));
+    }
+
+    #[test]
+    fn or_error() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser = string("x").or(string("y")).or(string("z"));
+        let result = parser.parse("w");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(0));
+    }
+
+    #[test]
+    fn many_ok() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser = string("xy").repeat().flat();
+        let result = parser.parse("xyxyxyxy");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("xy".to_string()),
+                Value::Some("xy".to_string()),
+                Value::Some("xy".to_string()),
+                Value::Some("xy".to_string()),
+            ])),
+        );
+
+        let parser = string("xy").repeat().flat();
+        let result = parser.parse("");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::None),
+        );
+    }
+
+    #[test]
+    fn many_error() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser = string("x").repeat();
+        let result = parser.parse("xxxxxy");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(5));
+    }
+
+    #[test]
+    fn regex_ok() {
+        let parser = Parser::regex(r"([0-9]+)([a-z]+)", 1);
+        let result = parser.parse("123abc");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("123".to_string())));
+
+        let parser = Parser::regex(r"[0-9]+", 0);
+        let result = parser.parse("123");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("123".to_string())));
+    }
+
+    #[test]
+    fn regex_error() {
+        let parser = Parser::regex(r"[0-9]+", 0);
+        let result = parser.parse("12a");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(2));
+
+        assert_eq!(Parser::regex("a", 0).parse("aé").err_position(), Some(1));
+        assert_eq!(Parser::regex("é", 0).parse("éa").err_position(), Some(2));
+        assert_eq!(Parser::regex("é", 0).parse("é").value(), Some(Value::Some("é".to_string())));
+    }
+
+    #[test]
+    fn sep_by1_ok() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser_val = string("val");
+        let parser = parser_val.clone().and(Parser::skip(",").and(parser_val.clone()).repeat()).flat();
+
+        let result = parser.parse("val");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("val".to_string()),
+            ])),
+        );
+
+        let result = parser.parse("val,val,val");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("val".to_string()),
+                Value::Some("val".to_string()),
+                Value::Some("val".to_string()),
+            ])),
+        );
+    }
+
+    #[test]
+    fn sep_by1_error() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser_val = string("val");
+        let parser = parser_val.clone().and(Parser::skip(",").and(parser_val.clone()).repeat()).flat();
+
+        let result = parser.parse("");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(0));
+
+        let result = parser.parse("val,");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(3));
+    }
+
+    #[test]
+    fn sep_by_ok() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser_val = string("val");
+        let parser = parser_val.clone().and(Parser::skip(",").and(string("val")).repeat()).flat().or(Parser::skip(""));
+
+        let result = parser.parse("");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::None),
+        );
+
+        let result = parser.parse("val");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("val".to_string()),
+            ])),
+        );
+
+        let result = parser.parse("val,val,val");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("val".to_string()),
+                Value::Some("val".to_string()),
+                Value::Some("val".to_string()),
+            ])),
+        );
+    }
+
+    #[test]
+    fn sep_by_error() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser_val = string("val");
+        let parser = parser_val.clone().and(Parser::skip(",").and(string("val")).repeat()).flat().or(Parser::skip(""));
+        let result = parser.parse("val,");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(3));
+    }
+
+    #[test]
+    fn skip_ok() {
+        let parser = Parser::regex("x", 0).and(Parser::skip("y"));
+        let result = parser.parse("xy");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("x".to_string())));
+    }
+
+    #[test]
+    fn skip_error() {
+        let parser = Parser::regex("xxx", 0).and(Parser::skip("yyy"));
+        let result = parser.parse("xxxxyy");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(3));
+    }
+
+    #[test]
+    fn string_ok() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser = string("source");
+        let result = parser.parse("source");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("source".to_string())));
+    }
+
+    #[test]
+    fn string_error() {
+        let string = |p:&str| Parser::regex(p, 0);
+        let parser = string("source");
+        let result = parser.parse("other");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(0));
+    }
+
+    #[test]
+    fn then_ok() {
+        let parser = Parser::skip("x").and(Parser::regex("y", 0));
+        let result = parser.parse("xy");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("y".to_string())));
+    }
+
+    #[test]
+    fn then_error() {
+        let parser = Parser::skip("xxx").and(Parser::regex("yyy", 0));
+        let result = parser.parse("xxxxyy");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(3));
+    }
+
+
+    #[test]
+    fn json_ok() {
+        let json_boolean = Parser::regex("true", 0).or(Parser::regex("false", 0));
+        let json_quot = Parser::skip("\"");
+        let json_string = json_quot.clone().and(Parser::regex("([^\\\\\"]*(\\\\.)?)+", 0)).and(json_quot.clone());
+        let json_number = Parser::regex("-?(0|[1-9][0-9]*)", 0);
+        let json_item = json_boolean.clone().or(json_string.clone()).or(json_number.clone());
+
+        let json_array = Parser::new(Box::new(move |root:&Parser|
+                Parser::skip("\\[")
+                .and(root.clone().and(Parser::skip(",")).repeat().and(root.clone().or(Parser::skip(""))).flat())
+                .and(Parser::skip("]"))
+            ));
+
+        let json_string_for_object = json_string.clone();
+        let json_object = Parser::new(Box::new(move |root:&Parser|{
+            let json_pair = json_string_for_object.clone().and(Parser::skip(":")).and(root.clone());
+            let json_comma = Parser::skip(",");
+            Parser::skip("\\{")
+            .and(
+                json_pair.clone().list()
+                .and(json_comma.clone().and(json_pair.clone()).repeat()).flat()
+                .and(json_comma.clone().or(Parser::skip(""))))
+            .and(Parser::skip("}"))
+            }));
+    
+        let json_elements = json_item.clone()
+                        .or(json_array.clone())
+                        .or(json_object.clone());
+
+
+        let result = json_boolean.parse("true");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("true".to_string())));
+
+        let result = json_boolean.parse("false");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("false".to_string())));
+                
+        let result = json_number.parse("-123");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("-123".to_string())));
+
+        let result = json_number.parse("1230");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("1230".to_string())));
+
+        let result = json_string.parse("\"foobar\"");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("foobar".to_string())));
+
+        let result = json_string.parse("\"\"");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(result.value(), Some(Value::Some("".to_string())));
+
+        let result = json_elements.parse("[\"foo\",\"bar\"]");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("foo".to_string()),
+                Value::Some("bar".to_string()),
+            ])),
+        );
+
+        let result = json_elements.parse("[]");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::None),
+        );
+
+        let result = json_elements.parse("[,]");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(1));
+
+        let result = json_elements.parse("[123]");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("123".to_string()),
+            ])),
+        );
+
+        let result = json_elements.parse("[123,]");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("123".to_string()),
+            ])),
+        );
+
+        let result = json_elements.parse("[123,456,]");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("123".to_string()),
+                Value::Some("456".to_string()),
+            ])),
+        );
+
+        let result = json_elements.parse("[123,456,789]");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::Some("123".to_string()),
+                Value::Some("456".to_string()),
+                Value::Some("789".to_string()),
+            ])),
+        );
+
+        let result = json_elements.parse("[123\"456\"]");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(4));
+
+        let result = json_elements.parse("{\"key1\":\"value\",\"key2\":123,}");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::List(vec![
+                    Value::Some("key1".to_string()),
+                    Value::Some("value".to_string()),
+                ]),
+                Value::List(vec![
+                    Value::Some("key2".to_string()),
+                    Value::Some("123".to_string()),
+                ]),
+            ])),
+        );
+
+        let result = json_elements.parse("{\"key1\":\"value\"}");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::List(vec![
+                    Value::Some("key1".to_string()),
+                    Value::Some("value".to_string()),
+                ]),
+            ])),
+        );
+
+        let result = json_elements.parse("{\"key1\":\"value\",}");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::List(vec![
+                    Value::Some("key1".to_string()),
+                    Value::Some("value".to_string()),
+                ]),
+            ])),
+        );
+
+        let result = json_elements.parse("{\"key1\":\"value\",\"key2\":123,\"key3\":true,}");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::List(vec![
+                    Value::Some("key1".to_string()),
+                    Value::Some("value".to_string()),
+                ]),
+                Value::List(vec![
+                    Value::Some("key2".to_string()),
+                    Value::Some("123".to_string()),
+                ]),
+                Value::List(vec![
+                    Value::Some("key3".to_string()),
+                    Value::Some("true".to_string()),
+                ]),
+            ])),
+        );
+
+        let result = json_elements.parse("{}");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(1));
+
+        let result = json_elements.parse("{,}");
+        assert_eq!(result.is_ok(), false);
+        assert_eq!(result.err_position(), Some(1));
+
+        let result = json_elements.parse("{\"arr\":[123,\"4\\\"56\",789],\"obj\":{\"key\":\"value\",\"key\":123},}");
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            result.value(),
+            Some(Value::List(vec![
+                Value::List(vec![
+                    Value::Some("arr".to_string()),
+                    Value::List(vec![
+                        Value::Some("123".to_string()),
+                        Value::Some("4\\\"56".to_string()),
+                        Value::Some("789".to_string()),
+                    ]),
+                ]),
+                Value::List(vec![
+                    Value::Some("obj".to_string()),
+                    Value::List(vec![
+                        Value::List(vec![
+                            Value::Some("key".to_string()),
+                            Value::Some("value".to_string()),
+                        ]),
+                        Value::List(vec![
+                            Value::Some("key".to_string()),
+                            Value::Some("123".to_string()),
+                        ]),
+                    ]),
+                ]),
+            ])),
+        );
+      
+
+    }
+
+    #[test]
+    fn recursive_ok() {
+        let builds = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = builds.clone();
+        let array = Parser::new(Box::new(move |root:&Parser| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Parser::skip("\\[").and(root.clone().repeat()).and(Parser::skip("]"))
+        }));
+        assert_eq!(builds.load(std::sync::atomic::Ordering::Relaxed), 1);
+        let node = Arc::downgrade(&array.node);
+        let empty = || Value::List(vec![]);
+        for _ in 0..3 {
+            assert_eq!(array.parse("[[[]][]]").value(), Some(Value::List(vec![Value::List(vec![empty()]), empty()])));
+        }
+        assert_eq!(builds.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let item = Parser::regex("x", 0).or(array);
+        assert_eq!(item.parse("[x[x]]").value(), Some(Value::List(vec![Value::Some("x".to_string()), Value::List(vec![Value::Some("x".to_string())])])));
+        assert_eq!(item.parse("[x]").value(), Some(Value::List(vec![Value::Some("x".to_string())])));
+        assert_eq!(builds.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        drop(item);
+        assert!(node.upgrade().is_none());
+    }
+}
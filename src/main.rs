@@ -1,668 +1,411 @@
-use std::rc::Rc;
-use regex::Regex;
-
-#[derive(Debug, Clone, PartialEq)]
-enum Value {
-    None,
-    Some(String),
-    List(Vec<Value>),
-}
-
-#[derive(Debug)]
-struct Success {
-    pub position: i32,
-    pub value: Value,
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+use pcc2::{Failure, Generator, Grammar, GrammarError, GrammarErrorKind, Parser, Repl, Selector, Value};
+
+const USAGE: &str = "usage: pcc2 parse --grammar FILE [--start RULE] [--format json|sexp|tree] [--profile] [--optimize] [FILE... | -]\n       pcc2 coverage --grammar FILE [--start RULE] [--format text|json] [FILE... | -]\n       pcc2 diagram --grammar FILE [--start RULE] [--format svg|dot] [--out DIR]\n       pcc2 generate --grammar FILE [--start RULE] [--count N] [--seed N] [--max-depth N]\n       pcc2 select --grammar FILE [--start RULE] [--format json|sexp] SELECTOR [FILE... | -]\n       pcc2 test --grammar FILE [--start RULE] [--update] DIR...\n       pcc2 repl [--grammar FILE]";
+
+const EXIT_PARSE: i32 = 1;
+const EXIT_GRAMMAR: i32 = 2;
+const EXIT_USAGE: i32 = 3;
+
+struct Options {
+    command: String,
+    grammar: Option<String>,
+    start: Option<String>,
+    format: String,
+    profile: bool,
+    optimize: bool,
+    update: bool,
+    out: String,
+    count: usize,
+    seed: u64,
+    max_depth: usize,
+    selector: Option<Selector>,
+    inputs: Vec<String>,
 }
 
-#[derive(Debug)]
-struct Failure {
-    pub position: i32,
-    pub expected: Vec<String>,
-}
-trait Reply {
-    fn position(&self) -> i32;
-    fn err_position(&self) -> i32;
-    fn value(&self) -> Value;
-    fn expected(&self) -> Vec<String>;
-}
-
-impl Reply for Result<Success, Failure> {
-    fn position(&self) -> i32 {
-        match self {
-            Ok(success) => success.position,
-            Err(failure) => failure.position,
+fn options(args: &[String]) -> Result<Options, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(String::as_str) {
+        Some(command @ ("parse" | "coverage" | "diagram" | "generate" | "select" | "test" | "repl")) => command.to_string(),
+        Some(command) => return Err(format!("unknown command {:?}", command)),
+        None => return Err("missing command".to_string()),
+    };
+    let takes_inputs = command == "parse" || command == "coverage" || command == "select";
+    let mut grammar = None;
+    let mut start = None;
+    let formats: &[&str] = match command.as_str() {
+        "coverage" => &["text", "json"],
+        "diagram" => &["svg", "dot"],
+        "select" => &["json", "sexp"],
+        _ => &["json", "sexp", "tree"],
+    };
+    let mut format = formats[0].to_string();
+    let mut profile = false;
+    let mut optimize = false;
+    let mut update = false;
+    let mut out = ".".to_string();
+    let (mut count, mut seed, mut max_depth) = (1, 0, 12);
+    let mut selector = None;
+    let mut inputs = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().cloned().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--grammar" | "-g" => grammar = Some(value(arg)?),
+            "--start" | "-s" => start = Some(value(arg)?),
+            "--format" | "-f" => format = value(arg)?,
+            "--profile" if command == "parse" => profile = true,
+            "--optimize" if command == "parse" => optimize = true,
+            "--update" if command == "test" => update = true,
+            "--out" | "-o" if command == "diagram" => out = value(arg)?,
+            "--count" | "-n" if command == "generate" => count = number(arg, value(arg)?)?,
+            "--seed" if command == "generate" => seed = number(arg, value(arg)?)?,
+            "--max-depth" if command == "generate" => max_depth = number(arg, value(arg)?)?,
+            "-" if takes_inputs => inputs.push(arg.clone()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {:?}", arg)),
+            _ if command == "select" && selector.is_none() => selector = Some(Selector::parse(arg).map_err(|e| e.to_string())?),
+            _ if takes_inputs || command == "test" => inputs.push(arg.clone()),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
         }
     }
-
-    fn err_position(&self) -> i32 {
-        match self {
-            Ok(_) => -1,
-            Err(failure) => failure.position,
-        }
+    if !formats.contains(&format.as_str()) {
+        return Err(format!("unknown format {:?}", format));
     }
-
-    fn value(&self) -> Value {
-        match self {
-            Ok(success) => success.value.clone(),
-            Err(_) => panic!(),
-        }
+    if grammar.is_none() && command != "repl" {
+        return Err("missing --grammar".to_string());
     }
-
-    fn expected(&self) -> Vec<String> {
-        match self {
-            Ok(_) => panic!(),
-            Err(failure) => failure.expected.to_vec(),
-        }
+    if selector.is_none() && command == "select" {
+        return Err("missing selector".to_string());
+    }
+    if inputs.is_empty() && command == "test" {
+        return Err("missing corpus directory".to_string());
     }
+    if inputs.is_empty() {
+        inputs.push("-".to_string());
+    }
+    Ok(Options{command, grammar, start, format, profile, optimize, update, out, count, seed, max_depth, selector, inputs})
 }
 
-type ParserFunc = Rc<dyn Fn(&Parser, &str, i32) -> Result<Success, Failure>>;
-
-#[derive(Clone)]
-struct Parser
-{
-    pub func:ParserFunc,
+fn number<T: std::str::FromStr>(name: &str, text: String) -> Result<T, String> {
+    text.parse().map_err(|_| format!("{} needs a number, got {:?}", name, text))
 }
 
+fn render(value: &Value, format: &str) -> String {
+    match format {
+        "sexp" => value.to_sexp() + "\n",
+        "tree" => value.to_tree(),
+        _ => value.to_json() + "\n",
+    }
+}
 
-impl<'b> Parser {
-    fn new(p2p:Box<dyn Fn(&Parser) -> Parser>)->Self {
-        Parser{func:Rc::new(move |root:&Parser, source: &str, position: i32|(p2p(root).func)(root, source, position))}
+/// A grammar error located in `file`, or only naming it when the error has no location.
+fn grammar_error(file: &str, e: &GrammarError) -> String {
+    match e.kind {
+        GrammarErrorKind::UndefinedStart(_) => format!("{}: grammar error: {}", file, e),
+        _ => format!("{}:{}:{}: grammar error: {}", file, e.line, e.column, e.kind),
     }
-    fn parse(&self, s:&str)->Result<Success, Failure> {
-        let success = (self.func)(self, s, 0)?;
-        if success.position < s.chars().count() as i32 {
-            return Err(Failure{position: success.position, expected:vec!["no length".to_string()]});
+}
+
+/// Runs the tool and returns the exit code: 0 on success, 1 if an input failed to parse,
+/// 2 for an invalid grammar and 3 for usage or I/O errors.
+fn run(args: &[String], stdin: &mut dyn BufRead, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+    let options = match options(args) {
+        Ok(options) => options,
+        Err(message) => {
+            let _ = writeln!(stderr, "pcc2: {}\n{}", message, USAGE);
+            return EXIT_USAGE;
         }
-        Ok(success)
-    }
-    fn and(self, p:Self)->Self {
-        Parser{func:Rc::new(move |root:&Self, s:&str, i:i32| {
-            let result1 = (self.func)(root, s, i)?;
-            let result2 = (p.func)(root, s, result1.position)?;
-            let mut v = Vec::<Value>::new();
-            if result1.value != Value::None {
-                v.push(result1.value);
-            }
-            if result2.value != Value::None {
-                v.push(result2.value);
+    };
+    let file = options.grammar.as_deref().unwrap_or("");
+    let text = match &options.grammar {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                let _ = writeln!(stderr, "pcc2: {}: {}", path, e);
+                return EXIT_USAGE;
             }
-            Ok(Success{position: result2.position, value: 
-                match v.len() {
-                    0 => Value::None,
-                    1 => v[0].clone(),
-                    _ => Value::List(v),
-                }})
-        })}
-    }
-
-    fn list(self)->Self {
-        Parser{func:Rc::new(move |root:&Self, s:&str, i:i32| {
-            let mut result1 = (self.func)(root, s, i)?;
-            if result1.value != Value::None {
-                result1.value = Value::List(vec![result1.value]);
+        },
+        None => String::new(),
+    };
+    let grammar = Grammar::parse(&text);
+    if options.command == "repl" {
+        return match grammar {
+            Ok(grammar) => match Repl::new(grammar).run(stdin, stdout) {
+                Ok(()) => 0,
+                Err(e) => {
+                    let _ = writeln!(stderr, "pcc2: {}", e);
+                    EXIT_USAGE
+                }
+            },
+            Err(e) => {
+                let _ = writeln!(stderr, "{}", grammar_error(file, &e));
+                EXIT_GRAMMAR
             }
-            Ok(result1)
-        })}
+        };
     }
-
-    fn flat(self)->Self {
-        Parser{func:Rc::new(move |root:&Self, s:&str, i:i32| {
-            let mut result1 = (self.func)(root, s, i)?;
-            if let Value::List(results) = result1.value {
-                let mut v = Vec::<Value>::new();
-                for result in results {
-                    if let Value::List(result_each) = result {
-                        v.extend(result_each)
-                    } else if result != Value::None {
-                        v.push(result)
-                    }
+    let parser = match grammar.and_then(|grammar| grammar.parser(options.start.as_deref())) {
+        Ok(parser) => parser,
+        Err(e) => {
+            let _ = writeln!(stderr, "{}", grammar_error(file, &e));
+            return EXIT_GRAMMAR;
+        }
+    };
+    if options.command == "diagram" {
+        return diagram(&options, &parser, stdout, stderr);
+    }
+    if options.command == "generate" {
+        let mut generator = Generator::new(&parser).seed(options.seed).max_depth(options.max_depth);
+        for _ in 0..options.count {
+            match generator.generate() {
+                Ok(sentence) => {
+                    let _ = writeln!(stdout, "{}", sentence);
                 }
-                result1.value = match v.len() {0=>Value::None, _=>Value::List(v)};
-            };
-            Ok(result1)
-        })}
-    }
-    fn repeat(self)->Self {
-        Parser{func:Rc::new(move |root:&Self, s:&str, pi:i32| {
-            let mut v = Vec::<Value>::new();
-            let mut i = pi;
-            let pos = loop {
-                let result = (self.func)(root, s, i);
-                match result {
-                    Err(_) => break i,
-                    Ok(success) =>{
-                        i = success.position;
-                        if success.value != Value::None {
-                            v.push(success.value);
-                        }
-                    }
+                Err(e) => {
+                    let _ = writeln!(stderr, "pcc2: {}", e);
+                    return EXIT_PARSE;
                 }
-            };
-            Ok(Success{position: pos, value: Value::List(v)})
-        })}
-    }
-
-    fn merge_errs(e1:Failure, e2:Failure)-> Failure {
-        let mut pos = e1.position;
-        let mut e = Vec::<String>::new();
-        if e1.position >= e2.position {
-            e.extend(e1.expected.into_iter());
-        }
-        if e1.position <= e2.position {
-            e.extend(e2.expected.into_iter());
-            pos = e2.position;
+            }
         }
-        Failure{position:pos, expected: e}
-    }
-
-    fn or(self, p:Self)->Self {
-        Parser{func:Rc::new(move |root:&Self, s:&str, i:i32| {
-            match (self.func)(root, s, i) {
-                Err(e1) => 
-                    match (p.func)(root, s, i){
-                        Err(e2) => Err(Parser::merge_errs(e1, e2)),
-                        ok => ok,
-                    },
-                ok => ok,
+        return 0;
+    }
+    if options.command == "test" {
+        return golden(&options, &parser, stdout, stderr);
+    }
+    if options.command == "coverage" {
+        let recorder = parser.coverage();
+        let code = each_input(&options, stdin, stderr, |_, source| recorder.parse(source).map(|_| ()));
+        let coverage = recorder.coverage();
+        let report = match options.format.as_str() {
+            "json" => coverage.to_json() + "\n",
+            _ => coverage.to_string(),
+        };
+        let _ = stdout.write_all(report.as_bytes());
+        return code;
+    }
+    if let Some(selector) = &options.selector {
+        return each_input(&options, stdin, stderr, |name, source| {
+            for selection in parser.select(source, selector)? {
+                let value = render(&selection.value, &options.format);
+                let _ = match selection.span {
+                    Some(span) => {
+                        let (line, column) = location(source, span.start);
+                        write!(stdout, "{}:{}:{}: {}", name, line, column, value)
+                    }
+                    None => write!(stdout, "{}: {}", name, value),
+                };
             }
-        })}
-    }
+            Ok(())
+        });
+    }
+    let parser = if options.optimize {parser.optimize()} else {parser};
+    let profiler = options.profile.then(|| parser.profiler());
+    let code = each_input(&options, stdin, stderr, |_, source| {
+        let result = match &profiler {
+            Some(profiler) => profiler.parse(source),
+            None => parser.parse(source),
+        };
+        result.map(|success| {
+            let _ = stdout.write_all(render(&success.value, &options.format).as_bytes());
+        })
+    });
+    if let Some(profiler) = profiler {
+        let _ = write!(stderr, "{}", profiler.profile());
+    }
+    code
+}
 
-    fn skip(pattern: &str) -> Self {
-        Parser::regex(pattern, -1)
+/// Prints a DOT rule graph, or writes one `RULE.svg` railroad diagram per rule to `--out`.
+fn diagram(options: &Options, parser: &Parser, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+    if options.format == "dot" {
+        let _ = stdout.write_all(parser.to_dot().as_bytes());
+        return 0;
+    }
+    for (name, svg) in parser.railroad() {
+        let path = std::path::Path::new(&options.out).join(format!("{}.svg", name));
+        if let Err(e) = fs::write(&path, svg) {
+            let _ = writeln!(stderr, "pcc2: {}: {}", path.display(), e);
+            return EXIT_USAGE;
+        }
+        let _ = writeln!(stdout, "{}", path.display());
     }
+    0
+}
 
-    fn regex(pattern: &str, group: isize) -> Self {
-        let s = pattern.to_string();
-        let ptn = "^(".to_string()+s.as_str()+")";
-        let regex = Regex::new(&ptn).unwrap();
-        Parser{func:Rc::new(move |_root:&Self, source: &str, position: i32| -> Result<Success, Failure> {
-            let src = &source[position as usize..source.len()];
-            let captures = regex.captures(src);
-            match captures {
-                Some(caps) => {
-                    let text = if group < 0 {""}else{caps.get(group as usize + 1).unwrap().as_str()};
-                    let mat = caps.get(0).unwrap();
-                    Ok(Success {
-                        position: position + (mat.end() - mat.start()) as i32,
-                        value: if group < 0 {Value::None}else{Value::Some(text.to_string())},
-                    })
+/// Checks each corpus directory against its snapshots, or rewrites them with `--update`.
+fn golden(options: &Options, parser: &Parser, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+    let mut code = 0;
+    for dir in options.inputs.iter() {
+        match parser.golden(dir, options.update) {
+            Ok(report) => {
+                let _ = stdout.write_all(report.to_string().as_bytes());
+                if !report.passed() {
+                    code = code.max(EXIT_PARSE);
                 }
-                None => Err(Failure {
-                    position: position,
-                    expected: vec![s.clone()],
-                })
             }
-        })}
+            Err(e) => {
+                let _ = writeln!(stderr, "pcc2: {}: {}", dir, e);
+                code = EXIT_USAGE;
+            }
+        }
     }
+    code
 }
 
+/// 1-based line and column (in characters) of a byte offset.
+fn location(source: &str, position: usize) -> (usize, usize) {
+    let before = &source[..position];
+    (before.matches('\n').count() + 1, before.rsplit('\n').next().unwrap_or("").chars().count() + 1)
+}
 
+/// Reads each input and hands its name and text to `parse`, reporting failures on `stderr`.
+/// Returns the exit code for the inputs.
+fn each_input(options: &Options, stdin: &mut dyn BufRead, stderr: &mut dyn Write, mut parse: impl FnMut(&str, &str) -> Result<(), Failure>) -> i32 {
+    let mut code = 0;
+    for input in options.inputs.iter() {
+        let (name, source) = if input == "-" {
+            let mut source = String::new();
+            ("<stdin>", stdin.read_to_string(&mut source).map(|_| source))
+        } else {
+            (input.as_str(), fs::read_to_string(input))
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(e) => {
+                let _ = writeln!(stderr, "pcc2: {}: {}", name, e);
+                code = EXIT_USAGE;
+                continue;
+            }
+        };
+        if let Err(failure) = parse(name, &source) {
+            let e = failure.to_error(&source);
+            let expected: Vec<String> = e.expected.iter().map(|e| format!("{:?}", e)).collect();
+            let _ = write!(stderr, "{}:{}:{}: parse error", name, e.line, e.column);
+            let _ = match expected.is_empty() {
+                true => writeln!(stderr),
+                false => writeln!(stderr, ": expected {}", expected.join(" or ")),
+            };
+            code = code.max(EXIT_PARSE);
+        }
+    }
+    code
+}
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = run(&args, &mut io::stdin().lock(), &mut io::stdout(), &mut io::stderr());
+    process::exit(code);
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn and_ok() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser = string("key").and(string(":")).and(string("value"));
-        let result = parser.parse("key:value");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::List(vec![
-                    Value::Some("key".to_string()),
-                    Value::Some(":".to_string()),
-                ]),
-                Value::Some("value".to_string()),
-            ]),
-        );
-    }
-
-    #[test]
-    fn and_error() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser = string("key").and(string(":")).and(string("value"));
-        let result = parser.parse("key:valu");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 4);
-    }
-
-    #[test]
-    fn or_ok() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser = string("x").or(string("y")).or(string("z"));
-        let result = parser.parse("x");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("x".to_string()));
-    }
-
-    #[test]
-    fn or_error() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser = string("x").or(string("y")).or(string("z"));
-        let result = parser.parse("w");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 0);
-    }
-
-    #[test]
-    fn many_ok() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser = string("xy").repeat().flat();
-        let result = parser.parse("xyxyxyxy");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("xy".to_string()),
-                Value::Some("xy".to_string()),
-                Value::Some("xy".to_string()),
-                Value::Some("xy".to_string()),
-            ]),
-        );
-
-        let parser = string("xy").repeat().flat();
-        let result = parser.parse("");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::None,
-        );
-    }
-
-    #[test]
-    fn many_error() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser = string("x").repeat();
-        let result = parser.parse("xxxxxy");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 5);
-    }
-
-    #[test]
-    fn regex_ok() {
-        let parser = Parser::regex(r"([0-9]+)([a-z]+)", 1);
-        let result = parser.parse("123abc");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("123".to_string()));
-
-        let parser = Parser::regex(r"[0-9]+", 0);
-        let result = parser.parse("123");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("123".to_string()));
-    }
-
-    #[test]
-    fn regex_error() {
-        let parser = Parser::regex(r"[0-9]+", 0);
-        let result = parser.parse("12a");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 2);
-    }
-
-    #[test]
-    fn sep_by1_ok() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser_val = string("val");
-        let parser = parser_val.clone().and(Parser::skip(",").and(parser_val.clone()).repeat()).flat();
-
-        let result = parser.parse("val");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("val".to_string()),
-            ]),
-        );
-
-        let result = parser.parse("val,val,val");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("val".to_string()),
-                Value::Some("val".to_string()),
-                Value::Some("val".to_string()),
-            ]),
-        );
-    }
-
-    #[test]
-    fn sep_by1_error() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser_val = string("val");
-        let parser = parser_val.clone().and(Parser::skip(",").and(parser_val.clone()).repeat()).flat();
-
-        let result = parser.parse("");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 0);
-
-        let result = parser.parse("val,");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 3);
-    }
-
-    #[test]
-    fn sep_by_ok() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser_val = string("val");
-        let parser = parser_val.clone().and(Parser::skip(",").and(string("val")).repeat()).flat().or(Parser::skip(""));
-
-        let result = parser.parse("");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::None,
-        );
-
-        let result = parser.parse("val");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("val".to_string()),
-            ]),
-        );
-
-        let result = parser.parse("val,val,val");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("val".to_string()),
-                Value::Some("val".to_string()),
-                Value::Some("val".to_string()),
-            ]),
-        );
-    }
-
-    #[test]
-    fn sep_by_error() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser_val = string("val");
-        let parser = parser_val.clone().and(Parser::skip(",").and(string("val")).repeat()).flat().or(Parser::skip(""));
-        let result = parser.parse("val,");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 3);
-    }
-
-    #[test]
-    fn skip_ok() {
-        let parser = Parser::regex("x", 0).and(Parser::skip("y"));
-        let result = parser.parse("xy");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("x".to_string()));
-    }
-
-    #[test]
-    fn skip_error() {
-        let parser = Parser::regex("xxx", 0).and(Parser::skip("yyy"));
-        let result = parser.parse("xxxxyy");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 3);
+    fn call(args: &[&str], input: &str) -> (i32, String, String) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        let code = run(&args, &mut input.as_bytes(), &mut stdout, &mut stderr);
+        (code, String::from_utf8(stdout).unwrap(), String::from_utf8(stderr).unwrap())
     }
 
-    #[test]
-    fn string_ok() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser = string("source");
-        let result = parser.parse("source");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("source".to_string()));
+    fn grammar_file(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!("pcc2-{}-{}.peg", name, process::id()));
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
     }
 
-    #[test]
-    fn string_error() {
-        let string = |p:&str| Parser::regex(p, 0);
-        let parser = string("source");
-        let result = parser.parse("other");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 0);
-    }
+    const GRAMMAR: &str = "list = ~\"(\" flat(item (~\",\" item)*) ~\")\" ;\nitem = ~/\\s*/ (/[a-z]+/ | list) ;\n";
 
     #[test]
-    fn then_ok() {
-        let parser = Parser::skip("x").and(Parser::regex("y", 0));
-        let result = parser.parse("xy");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("y".to_string()));
+    fn run_ok() {
+        let grammar = grammar_file("ok", GRAMMAR);
+        assert_eq!(call(&["parse", "--grammar", &grammar], "(a,(b,c))"), (0, "[\"a\",[\"b\",\"c\"]]\n".to_string(), String::new()));
+        assert_eq!(call(&["parse", "-g", &grammar, "-f", "sexp", "-"], "(a,b)").1, "(\"a\" \"b\")\n");
+        assert_eq!(call(&["parse", "-g", &grammar, "--format", "tree"], "(a,(b))").1, "list\n  \"a\"\n  list\n    \"b\"\n");
+        assert_eq!(call(&["parse", "-g", &grammar, "--start", "item"], "abc").1, "\"abc\"\n");
+
+        assert_eq!(call(&["repl", "-g", &grammar], ":parse item x\n"), (0, "> \"x\"\n> ".to_string(), String::new()));
+
+        let input = grammar_file("input", "(x)");
+        assert_eq!(call(&["parse", "-g", &grammar, &input], "").1, "[\"x\"]\n");
+
+        let (code, stdout, _) = call(&["coverage", "-g", &grammar, "-f", "json"], "(a,(b))");
+        assert_eq!(code, 0);
+        assert!(stdout.starts_with("{\"choices\":[{\"rule\":\"item\",\"alternatives\":[{\"expr\":\"/[a-z]+/\",\"count\":2},"));
+        let (code, stdout, _) = call(&["coverage", "-g", &grammar, &input], "");
+        assert_eq!(code, 0);
+        assert!(stdout.starts_with("choice item\n"));
+        assert!(stdout.contains("uncovered\n  item: alternative 2 `list`\n"));
+
+        let (code, stdout, _) = call(&["diagram", "-g", &grammar, "-f", "dot"], "");
+        assert_eq!((code, stdout.as_str()), (0, "digraph grammar {\n  node [shape=box];\n  \"list\";\n  \"list\" -> \"item\";\n  \"item\";\n  \"item\" -> \"list\";\n}\n"));
+        let dir = std::env::temp_dir().join(format!("pcc2-diagram-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (code, stdout, _) = call(&["diagram", "-g", &grammar, "--out", &dir.to_string_lossy()], "");
+        assert_eq!((code, stdout.lines().count()), (0, 2));
+        assert!(fs::read_to_string(dir.join("item.svg")).unwrap().starts_with("<svg "));
+
+        let (code, stdout, _) = call(&["generate", "-g", &grammar, "-n", "5", "--seed", "9", "--max-depth", "3"], "");
+        assert_eq!(code, 0);
+        assert!(stdout.starts_with('(') && stdout.ends_with(")\n"));
+        assert_eq!(call(&["generate", "-g", &grammar, "-n", "5", "--seed", "9", "--max-depth", "3"], "").1, stdout);
+
+        let corpus = std::env::temp_dir().join(format!("pcc2-corpus-{}", process::id()));
+        fs::create_dir_all(&corpus).unwrap();
+        fs::write(corpus.join("one.txt"), "(a,b)").unwrap();
+        let corpus = corpus.to_string_lossy().into_owned();
+        assert_eq!(call(&["test", "-g", &grammar, "--update", &corpus], ""), (0, format!("updated {}/one.txt\n0 passed, 0 failed, 1 updated\n", corpus), String::new()));
+        assert_eq!(call(&["test", "-g", &grammar, &corpus], "").1, "1 passed, 0 failed, 0 updated\n");
+
+        assert_eq!(call(&["select", "-g", &grammar, "//*[0=\"b\"]", "-"], "(a,\n (b, c))").1, "<stdin>:2:3: [\"b\",\"c\"]\n");
+        assert_eq!(call(&["select", "-g", &grammar, "-f", "sexp", "/*", &input], "").1, format!("{}:1:2: \"x\"\n", input));
+
+        let (code, stdout, stderr) = call(&["parse", "-g", &grammar, "--profile"], "(a)");
+        assert_eq!((code, stdout.as_str()), (0, "[\"a\"]\n"));
+        assert!(stderr.starts_with("rule ") && stderr.contains("\nlist ") && stderr.contains("\nitem "));
+        assert_eq!(call(&["parse", "-g", &grammar, "--optimize"], "(a,(b,c))").1, "[\"a\",[\"b\",\"c\"]]\n");
     }
 
     #[test]
-    fn then_error() {
-        let parser = Parser::skip("xxx").and(Parser::regex("yyy", 0));
-        let result = parser.parse("xxxxyy");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 3);
-    }
-
-
-    #[test]
-    fn json_ok() {
-        let json_boolean = Parser::regex("true", 0).or(Parser::regex("false", 0));
-        let json_quot = Parser::skip("\"");
-        let json_string = json_quot.clone().and(Parser::regex("([^\\\\\"]*(\\\\.)?)+", 0)).and(json_quot.clone());
-        let json_number = Parser::regex("-?(0|[1-9][0-9]*)", 0);
-        let json_item = json_boolean.clone().or(json_string.clone()).or(json_number.clone());
-
-        let json_array = Parser::new(Box::new(move |root:&Parser|
-                Parser::skip("\\[")
-                .and(root.clone().and(Parser::skip(",")).repeat().and(root.clone().or(Parser::skip(""))).flat())
-                .and(Parser::skip("]"))
-            ));
-
-        let json_string_for_object = json_string.clone();
-        let json_object = Parser::new(Box::new(move |root:&Parser|{
-            let json_pair = json_string_for_object.clone().and(Parser::skip(":")).and(root.clone());
-            let json_comma = Parser::skip(",");
-            Parser::skip("\\{")
-            .and(
-                json_pair.clone().list()
-                .and(json_comma.clone().and(json_pair.clone()).repeat()).flat()
-                .and(json_comma.clone().or(Parser::skip(""))))
-            .and(Parser::skip("}"))
-            }));
-    
-        let json_elements = json_item.clone()
-                        .or(json_array.clone())
-                        .or(json_object.clone());
-
-
-        let result = json_boolean.parse("true");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("true".to_string()));
-
-        let result = json_boolean.parse("false");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("false".to_string()));
-                
-        let result = json_number.parse("-123");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("-123".to_string()));
-
-        let result = json_number.parse("1230");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("1230".to_string()));
-
-        let result = json_string.parse("\"foobar\"");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("foobar".to_string()));
-
-        let result = json_string.parse("\"\"");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.value(), Value::Some("".to_string()));
-
-        let result = json_elements.parse("[\"foo\",\"bar\"]");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("foo".to_string()),
-                Value::Some("bar".to_string()),
-            ]),
-        );
-
-        let result = json_elements.parse("[]");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::None,
-        );
-
-        let result = json_elements.parse("[,]");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 1);
-
-        let result = json_elements.parse("[123]");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("123".to_string()),
-            ]),
-        );
-
-        let result = json_elements.parse("[123,]");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("123".to_string()),
-            ]),
-        );
-
-        let result = json_elements.parse("[123,456,]");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("123".to_string()),
-                Value::Some("456".to_string()),
-            ]),
-        );
-
-        let result = json_elements.parse("[123,456,789]");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::Some("123".to_string()),
-                Value::Some("456".to_string()),
-                Value::Some("789".to_string()),
-            ]),
-        );
-
-        let result = json_elements.parse("[123\"456\"]");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(),4);
-
-        let result = json_elements.parse("{\"key1\":\"value\",\"key2\":123,}");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::List(vec![
-                    Value::Some("key1".to_string()),
-                    Value::Some("value".to_string()),
-                ]),
-                Value::List(vec![
-                    Value::Some("key2".to_string()),
-                    Value::Some("123".to_string()),
-                ]),
-            ]),
-        );
-
-        let result = json_elements.parse("{\"key1\":\"value\"}");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::List(vec![
-                    Value::Some("key1".to_string()),
-                    Value::Some("value".to_string()),
-                ]),
-            ]),
-        );
-
-        let result = json_elements.parse("{\"key1\":\"value\",}");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::List(vec![
-                    Value::Some("key1".to_string()),
-                    Value::Some("value".to_string()),
-                ]),
-            ]),
-        );
-
-        let result = json_elements.parse("{\"key1\":\"value\",\"key2\":123,\"key3\":true,}");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::List(vec![
-                    Value::Some("key1".to_string()),
-                    Value::Some("value".to_string()),
-                ]),
-                Value::List(vec![
-                    Value::Some("key2".to_string()),
-                    Value::Some("123".to_string()),
-                ]),
-                Value::List(vec![
-                    Value::Some("key3".to_string()),
-                    Value::Some("true".to_string()),
-                ]),
-            ]),
-        );
-
-        let result = json_elements.parse("{}");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 1);
-
-        let result = json_elements.parse("{,}");
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err_position(), 1);
-
-        let result = json_elements.parse("{\"arr\":[123,\"4\\\"56\",789],\"obj\":{\"key\":\"value\",\"key\":123},}");
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(
-            result.value(),
-            Value::List(vec![
-                Value::List(vec![
-                    Value::Some("arr".to_string()),
-                    Value::List(vec![
-                        Value::Some("123".to_string()),
-                        Value::Some("4\\\"56".to_string()),
-                        Value::Some("789".to_string()),
-                    ]),
-                ]),
-                Value::List(vec![
-                    Value::Some("obj".to_string()),
-                    Value::List(vec![
-                        Value::List(vec![
-                            Value::Some("key".to_string()),
-                            Value::Some("value".to_string()),
-                        ]),
-                        Value::List(vec![
-                            Value::Some("key".to_string()),
-                            Value::Some("123".to_string()),
-                        ]),
-                    ]),
-                ]),
-            ]),
-        );
-      
-
+    fn run_error() {
+        let grammar = grammar_file("error", GRAMMAR);
+        let (code, stdout, stderr) = call(&["parse", "-g", &grammar], "(a,b)\n;");
+        assert_eq!((code, stdout.as_str()), (1, ""));
+        assert_eq!(stderr, "<stdin>:1:6: parse error: expected \"no length\"\n");
+        assert_eq!(call(&["parse", "-g", &grammar], "(a,\n b;)").2, "<stdin>:2:3: parse error: expected \"\\\\)\"\n");
+
+        let bad = grammar_file("bad", "a = /x/ ;\nb = a c ;\n");
+        let (code, _, stderr) = call(&["parse", "-g", &bad], "x");
+        assert_eq!(code, 2);
+        assert_eq!(stderr, format!("{}:2:1: grammar error: undefined rule \"c\"\n", bad));
+        assert_eq!(call(&["parse", "-g", &grammar, "-s", "nope"], ""), (2, String::new(), format!("{}: grammar error: no rule \"nope\" to start from\n", grammar)));
+        let empty = grammar_file("empty", "");
+        assert_eq!(call(&["parse", "-g", &empty], "").2, format!("{}: grammar error: the grammar has no rules\n", empty));
+
+        assert_eq!(call(&["parse"], "").0, 3);
+        assert_eq!(call(&["parse", "-g", &grammar, "-f", "xml"], "").0, 3);
+        assert_eq!(call(&["parse", "-g", "/nonexistent/grammar"], "").0, 3);
+        assert_eq!(call(&["build"], "").0, 3);
+        assert_eq!(call(&["coverage", "-g", &grammar, "-f", "tree"], "").0, 3);
+        assert_eq!(call(&["coverage", "-g", &grammar], "(a").0, 1);
+        assert_eq!(call(&["repl", "extra"], "").0, 3);
+        assert_eq!(call(&["test", "-g", &grammar], "").0, 3);
+        assert_eq!(call(&["select", "-g", &grammar], "").0, 3);
+        assert!(call(&["select", "-g", &grammar, "/key"], "").2.starts_with("pcc2: invalid selector at 1: "));
+        assert_eq!(call(&["select", "-g", &grammar, "/*"], "(a,").0, 1);
+        assert_eq!(call(&["test", "-g", &grammar, "/nonexistent/corpus"], "").0, 3);
+        let corpus = std::env::temp_dir().join(format!("pcc2-stale-{}", process::id()));
+        fs::create_dir_all(&corpus).unwrap();
+        fs::write(corpus.join("one.txt"), "(a)").unwrap();
+        fs::write(corpus.join("one.txt.expected"), "list\n  \"b\"\n").unwrap();
+        let (code, stdout, _) = call(&["test", "-g", &grammar, &corpus.to_string_lossy()], "");
+        assert_eq!(code, 1);
+        assert!(stdout.contains("one.txt\n  list\n-   \"b\"\n+   \"a\"\n0 passed, 1 failed, 0 updated\n"));
+        assert_eq!(call(&["generate", "-g", &grammar, "-n", "x"], "").0, 3);
+        assert_eq!(call(&["diagram", "-g", &grammar, "input"], "").0, 3);
+        assert_eq!(call(&["diagram", "-g", &grammar, "-o", "/nonexistent/dir"], "").0, 3);
+        assert_eq!(call(&["repl", "-g", &bad], "").0, 0);
+        assert_eq!(call(&["repl", "-g", &grammar_file("broken", "a = ;")], "").0, 2);
     }
 }
-
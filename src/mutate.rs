@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use crate::generate::Rng;
+use crate::syntax::{self, Kind, Span};
+use crate::{Generator, Parser};
+
+/// Grammar-aware mutations for fuzzing: subtrees are swapped between inputs, repeated
+/// items deleted or duplicated, and rules regenerated, so most outputs still parse.
+pub struct Mutator {
+    parser: Parser,
+    rules: Arc<Mutex<HashMap<String, Parser>>>,
+    corpus: Vec<(String, Span)>,
+    generators: HashMap<String, Generator>,
+    rng: Rng,
+    seed: u64,
+}
+
+impl Mutator {
+    pub fn new(parser: &Parser) -> Self {
+        let rules = Arc::new(Mutex::new(HashMap::new()));
+        let named = rules.clone();
+        let parser = syntax::recorder(parser, move |name, rule| {
+            named.lock().unwrap().insert(name.to_string(), rule.clone());
+        });
+        Mutator{parser, rules, corpus: vec![], generators: HashMap::new(), rng: Rng::new(0), seed: 0}
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Rng::new(seed);
+        self.seed = seed;
+        self
+    }
+
+    /// Adds a valid input whose subtrees later mutations may borrow. Returns false if it does not parse.
+    pub fn add_seed(&mut self, input: &str) -> bool {
+        match self.spans(input) {
+            Some(spans) => {
+                self.corpus.push((input.to_string(), spans));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn spans(&self, input: &str) -> Option<Span> {
+        let children = syntax::record(&self.parser, input).ok()?;
+        Some(Span{kind: Kind::Repeat, span: 0..input.len(), children})
+    }
+
+    /// One mutation of `input`, or of a corpus entry if `input` does not parse.
+    pub fn mutate(&mut self, input: &str) -> String {
+        let (text, spans) = match self.spans(input) {
+            Some(spans) => (input.to_string(), spans),
+            None if self.corpus.is_empty() => return input.to_string(),
+            None => self.corpus[self.rng.below(self.corpus.len() as u64) as usize].clone(),
+        };
+        let mut nodes = vec![];
+        spans.walk(&mut nodes);
+        for _ in 0..16 {
+            let node = nodes[self.rng.below(nodes.len() as u64) as usize];
+            let range = node.span.clone();
+            let replacement = match (&node.kind, self.rng.below(2)) {
+                (Kind::Item, 0) => Some(String::new()),
+                (Kind::Item, _) => Some(text[range.clone()].repeat(2)),
+                (Kind::Rule(name), 0) => self.donor(name, &text, &spans, &range),
+                (Kind::Rule(name), _) => self.regenerate(name),
+                (Kind::Repeat | Kind::Token(_), _) => None,
+            };
+            match replacement {
+                Some(replacement) if replacement != text[range.clone()] => {
+                    return text[..range.start].to_string() + &replacement + &text[range.end..];
+                }
+                _ => (),
+            }
+        }
+        text
+    }
+
+    /// libFuzzer-style entry point: mutates `data` and keeps the result within `max_size` bytes.
+    pub fn mutate_bytes(&mut self, data: &[u8], max_size: usize, seed: u32) -> Vec<u8> {
+        self.rng = Rng::new(self.seed ^ ((seed as u64) << 32 | seed as u64));
+        let input = String::from_utf8_lossy(data).into_owned();
+        let mut output = self.mutate(&input);
+        for _ in 0..4 {
+            if output.len() <= max_size {
+                break;
+            }
+            output = self.mutate(&input);
+        }
+        let mut bytes = output.into_bytes();
+        bytes.truncate(max_size);
+        bytes
+    }
+
+    /// The text of another match of rule `name`, from `text` itself or the corpus.
+    fn donor(&mut self, name: &str, text: &str, spans: &Span, range: &Range<usize>) -> Option<String> {
+        let mut donors: Vec<&str> = vec![];
+        let mut nodes = vec![];
+        spans.walk(&mut nodes);
+        donors.extend(nodes.iter().filter(|n| n.kind == Kind::Rule(name.to_string()) && n.span != *range).map(|n| &text[n.span.clone()]));
+        for (seed, spans) in self.corpus.iter() {
+            let mut nodes = vec![];
+            spans.walk(&mut nodes);
+            donors.extend(nodes.iter().filter(|n| n.kind == Kind::Rule(name.to_string())).map(|n| &seed[n.span.clone()]));
+        }
+        match donors.len() {
+            0 => None,
+            n => Some(donors[self.rng.below(n as u64) as usize].to_string()),
+        }
+    }
+
+    fn regenerate(&mut self, name: &str) -> Option<String> {
+        let rule = self.rules.lock().unwrap().get(name)?.clone();
+        let seed = self.rng.next_u64();
+        let generator = self.generators.entry(name.to_string()).or_insert_with(|| Generator::new(&rule).seed(seed).max_depth(4).attempts(10));
+        generator.generate().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn grammar() -> Parser {
+        Grammar::parse(r#"
+            value  = array | number | string ;
+            array  = ~"[" flat(value (~"," value)*)? ~"]" ;
+            number = /[0-9]+/ ;
+            string = ~"'" /[a-z]*/ ~"'" ;
+        "#).unwrap().parser(None).unwrap()
+    }
+
+    #[test]
+    fn mutate_ok() {
+        let parser = grammar();
+        let mut mutator = Mutator::new(&parser).seed(11);
+        assert!(mutator.add_seed("[1,'ab',[2,3]]"));
+        assert!(mutator.add_seed("['x',[],44]"));
+        let mut input = "[5,6,7]".to_string();
+        let mut valid = 0;
+        let mut changed = 0;
+        for _ in 0..200 {
+            let output = mutator.mutate(&input);
+            changed += (output != input) as usize;
+            if parser.parse(&output).is_ok() {
+                valid += 1;
+                input = output;
+            }
+        }
+        assert!(valid >= 150, "{} valid", valid);
+        assert!(changed >= 180, "{} changed", changed);
+
+        let mut again = Mutator::new(&parser).seed(11);
+        again.add_seed("[1,'ab',[2,3]]");
+        again.add_seed("['x',[],44]");
+        let mut first = Mutator::new(&parser).seed(11);
+        first.add_seed("[1,'ab',[2,3]]");
+        first.add_seed("['x',[],44]");
+        assert_eq!(again.mutate("[5,6,7]"), first.mutate("[5,6,7]"));
+    }
+
+    #[test]
+    fn mutate_error() {
+        let parser = grammar();
+        let mut mutator = Mutator::new(&parser).seed(3);
+        assert!(!mutator.add_seed("[1,"));
+        assert_eq!(mutator.mutate("[1,"), "[1,");
+        assert!(mutator.add_seed("[1,22,333]"));
+        assert_ne!(mutator.mutate("not json"), "not json");
+        for seed in 0..20 {
+            assert!(mutator.mutate_bytes(b"[1,22,333]", 6, seed).len() <= 6);
+        }
+        assert!(!mutator.mutate_bytes(b"\xff[1]", 100, 1).is_empty());
+    }
+}
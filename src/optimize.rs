@@ -0,0 +1,333 @@
+use std::borrow::Cow;
+use std::sync::{Arc, OnceLock};
+
+use regex::Regex;
+
+use crate::analysis::PatternInfo;
+use crate::instrument::{self, Wrap};
+use crate::{key, Failure, Node, Parser, Success, Value};
+
+/// The characters a parser's non-empty matches can start with, and whether it can succeed
+/// without consuming input. Anything unknown, such as custom parsers, counts as both.
+struct First {
+    ranges: Vec<(char, char)>,
+    nullable: bool,
+}
+
+impl First {
+    fn any() -> Self {
+        First{ranges: vec![('\0', char::MAX)], nullable: true}
+    }
+
+    fn new(parser: &Parser, visiting: &mut Vec<usize>) -> Self {
+        match &*parser.node {
+            Node::Regex{pattern, ..} => {
+                let info = PatternInfo::new(pattern);
+                First{ranges: info.first, nullable: info.nullable}
+            }
+            Node::And(a, b) => {
+                let mut first = First::new(a, visiting);
+                if first.nullable {
+                    let second = First::new(b, visiting);
+                    first.ranges.extend(second.ranges);
+                    first.nullable = second.nullable;
+                }
+                first
+            }
+            Node::Or(a, b) => {
+                let mut first = First::new(a, visiting);
+                let second = First::new(b, visiting);
+                first.ranges.extend(second.ranges);
+                first.nullable |= second.nullable;
+                first
+            }
+            Node::Repeat(p) => First{nullable: true, ..First::new(p, visiting)},
+            Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => First::new(p, visiting),
+            // Left recursion: the rule's first characters depend on themselves.
+            Node::Rule(..) | Node::Recursive(_) if visiting.contains(&key(parser)) => First::any(),
+            Node::Rule(_, slot) => match slot.get() {
+                Some(body) => {
+                    visiting.push(key(parser));
+                    let first = First::new(&body, visiting);
+                    visiting.pop();
+                    first
+                }
+                None => First{ranges: vec![], nullable: false},
+            },
+            Node::Recursive(body) => {
+                visiting.push(key(parser));
+                let first = First::new(body, visiting);
+                visiting.pop();
+                first
+            }
+            // Whatever the parse started from.
+            Node::Root | Node::Custom => First::any(),
+        }
+    }
+
+    fn can_start(&self, c: char) -> bool {
+        self.nullable || self.ranges.iter().any(|&(start, end)| start <= c && c <= end)
+    }
+}
+
+/// What a parser that cannot start at a position expects there, when that does not depend
+/// on the input: the patterns of the terminals it fails on, merged as `or` would.
+fn expected(parser: &Parser, visiting: &mut Vec<usize>) -> Option<Vec<String>> {
+    match &*parser.node {
+        Node::Regex{pattern, ..} => Some(vec![pattern.clone()]),
+        // Only the first part is tried when it cannot match the empty string.
+        Node::And(a, _) if !First::new(a, &mut vec![]).nullable => expected(a, visiting),
+        Node::Or(a, b) => {
+            let mut first = expected(a, visiting)?;
+            first.extend(expected(b, visiting)?);
+            Some(first)
+        }
+        Node::List(p) | Node::Flat(p) | Node::Memo(p) | Node::Layout(_, p) => expected(p, visiting),
+        Node::Rule(..) | Node::Recursive(_) if visiting.contains(&key(parser)) => None,
+        Node::Rule(_, slot) => match slot.get() {
+            Some(body) => {
+                visiting.push(key(parser));
+                let expected = expected(&body, visiting);
+                visiting.pop();
+                expected
+            }
+            None => Some(vec![]),
+        },
+        Node::Recursive(body) => {
+            visiting.push(key(parser));
+            let expected = expected(body, visiting);
+            visiting.pop();
+            expected
+        }
+        Node::And(..) | Node::Repeat(_) | Node::Root | Node::Custom => None,
+    }
+}
+
+/// Which alternatives of a choice can match at a position, by its next character.
+struct Table {
+    firsts: Vec<First>,
+    ascii: Vec<Vec<usize>>,
+    end: Vec<usize>,
+    /// What each alternative expects where it cannot start, if known without running it.
+    expected: Vec<Option<Vec<String>>>,
+}
+
+impl Table {
+    fn new(alternatives: &[Parser]) -> Self {
+        let firsts: Vec<First> = alternatives.iter().map(|p| First::new(p, &mut vec![])).collect();
+        let ascii = (0..128u8).map(|c| (0..firsts.len()).filter(|&k| firsts[k].can_start(c as char)).collect()).collect();
+        let end = (0..firsts.len()).filter(|&k| firsts[k].nullable).collect();
+        let expected = alternatives.iter().map(|p| expected(p, &mut vec![])).collect();
+        Table{firsts, ascii, end, expected}
+    }
+
+    fn candidates(&self, next: Option<char>) -> Cow<'_, [usize]> {
+        match next {
+            None => Cow::Borrowed(&self.end),
+            Some(c) if c.is_ascii() => Cow::Borrowed(&self.ascii[c as usize]),
+            Some(c) => Cow::Owned((0..self.firsts.len()).filter(|&k| self.firsts[k].can_start(c)).collect()),
+        }
+    }
+}
+
+/// The alternatives of a chain of `or`s, in the order they are tried.
+fn alternatives(parser: &Parser, out: &mut Vec<Parser>) {
+    match &*parser.node {
+        Node::Or(a, b) => {
+            alternatives(a, out);
+            alternatives(b, out);
+        }
+        _ => out.push(parser.clone()),
+    }
+}
+
+/// One terminal folded into an `Alternation`.
+struct Branch {
+    pattern: String,
+    group: isize,
+    /// The capture group of the whole branch in the combined regex.
+    base: usize,
+}
+
+/// Consecutive terminal alternatives matched by one regex. The regex crate prefers
+/// earlier alternatives, so the first branch that matches wins, as with `or`. Like `or`,
+/// it gives only that branch's result, not which branch it was.
+struct Alternation {
+    regex: Regex,
+    branches: Vec<Branch>,
+}
+
+impl Alternation {
+    fn new(terminals: &[Parser]) -> Option<Self> {
+        let mut combined = vec![];
+        let mut branches = vec![];
+        let mut base = 1;
+        for terminal in terminals {
+            if let Node::Regex{pattern, group, regex, ..} = &*terminal.node {
+                combined.push(format!("({})", pattern));
+                branches.push(Branch{pattern: pattern.clone(), group: *group, base});
+                // The terminal's own regex wraps the pattern in "^(...)" as well.
+                base += regex.captures_len() - 1;
+            }
+        }
+        let regex = Regex::new(&format!("^(?:{})", combined.join("|"))).ok()?;
+        Some(Alternation{regex, branches})
+    }
+
+    /// The result of the first branch that matches.
+    fn find(&self, source: &str, position: i32) -> Result<Success, Failure> {
+        let captures = self.regex.captures(&source[position as usize..]);
+        let matched = captures.as_ref().and_then(|captures| self.branches.iter().position(|branch| captures.get(branch.base).is_some()));
+        match (&captures, matched) {
+            (Some(captures), Some(k)) => {
+                let branch = &self.branches[k];
+                let end = position as usize + captures.get(0).unwrap().end();
+                let text = (branch.group >= 0).then(|| captures.get(branch.base + branch.group as usize).map_or("", |m| m.as_str()));
+                Ok(Success{position: end as i32, value: text.map_or(Value::None, |text| Value::Some(text.to_string()))})
+            }
+            _ => Err(Failure{position, expected: self.branches.iter().map(|branch| branch.pattern.clone()).collect()}),
+        }
+    }
+}
+
+/// The alternatives with each run of two or more terminals folded into one `Alternation`.
+/// Terminals whose function is not their own, such as instrumented ones, are left alone.
+fn fold_terminals(choices: Vec<Parser>) -> Vec<Parser> {
+    let mut out = vec![];
+    let mut run: Vec<Parser> = vec![];
+    for choice in choices.into_iter().map(Some).chain(std::iter::once(None)) {
+        if let Some(choice) = choice.as_ref().filter(|choice| matches!(&*choice.node, Node::Regex{func, ..} if func.is(&choice.func))) {
+            run.push(choice.clone());
+            continue;
+        }
+        match Alternation::new(&run).filter(|_| run.len() > 1) {
+            Some(alternation) => {
+                let node = run.drain(..).reduce(Parser::or).unwrap().node;
+                out.push(Parser{node, func: Arc::new(move |_: &Parser, s: &str, i: i32| alternation.find(s, i))});
+            }
+            None => out.append(&mut run),
+        }
+        out.extend(choice);
+    }
+    out
+}
+
+/// A choice that only tries the alternatives that can start with the next character.
+/// When all of those fail, the others' expected terminals are merged in from the table,
+/// so the failure is the same as from trying every alternative in order.
+fn dispatching(parser: Parser) -> Parser {
+    let mut choices = vec![];
+    alternatives(&parser, &mut choices);
+    let choices = fold_terminals(choices);
+    let table = OnceLock::new();
+    Parser{node: parser.node, func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+        let table = table.get_or_init(|| Table::new(&choices));
+        let mut failures: Vec<(usize, Failure)> = vec![];
+        for &k in table.candidates(s[i as usize..].chars().next()).iter() {
+            match (choices[k].func)(root, s, i) {
+                Err(failure) => failures.push((k, failure)),
+                ok => return ok,
+            }
+        }
+        let mut failures = failures.into_iter().peekable();
+        let mut merged: Option<Failure> = None;
+        for (k, choice) in choices.iter().enumerate() {
+            let failure = match failures.next_if(|(j, _)| *j == k) {
+                Some((_, failure)) => failure,
+                None => match &table.expected[k] {
+                    Some(expected) => Failure{position: i, expected: expected.clone()},
+                    None => match (choice.func)(root, s, i) {
+                        Err(failure) => failure,
+                        ok => return ok,
+                    },
+                },
+            };
+            merged = Some(match merged {
+                Some(merged) => Parser::merge_errs(merged, failure),
+                None => failure,
+            });
+        }
+        Err(merged.unwrap())
+    })}
+}
+
+impl Parser {
+    /// An equivalent parser with every chain of `or`s dispatching on the next character,
+    /// so alternatives that cannot start there are never tried, and with consecutive
+    /// terminal alternatives matched by a single regex.
+    pub fn optimize(&self) -> Parser {
+        let wrap: Wrap = Arc::new(|_: &Parser, rebuilt: Parser| match &*rebuilt.node {
+            Node::Or(..) => dispatching(rebuilt),
+            _ => rebuilt,
+        });
+        instrument::rebuild(self, &wrap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::Grammar;
+
+    #[test]
+    fn optimize_ok() {
+        let parser = Grammar::parse(r#"
+            block     = ~"{" statement* ~/\s*\}/ ;
+            statement = ~/\s*/ ("if" | "while" | "return" | "let" | name | number | block | "é" | empty) ~";" ;
+            name      = /[a-z_][a-z0-9_]*/ ;
+            number    = /[0-9]+/ | /0x[0-9a-f]+/ ;
+            empty     = ~"" ;
+        "#).unwrap().compile().unwrap().swap_remove(0);
+        let optimized = parser.optimize();
+        for input in ["{if; x1; 0x1f; {while;}; ;é;}", "{}", "{return", "{ let; 7 }", "{ ?; }", "{é", "", "{{{;}"] {
+            assert_eq!(optimized.parse(input), parser.parse(input), "{:?}", input);
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let keyword = Parser::skip("z");
+        let inner = keyword.func.clone();
+        let counted = Parser{node: keyword.node, func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            inner(root, s, i)
+        })};
+        let parser = Parser::regex("a", 0).or(counted).or(Parser::regex("b", 0)).repeat();
+        let value = parser.optimize().parse("abba").unwrap().value;
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 0);
+        assert_eq!(parser.parse("abba").unwrap().value, value);
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 3);
+        assert!(parser.optimize().parse("azb").is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn alternation_ok() {
+        let terminals = [Parser::skip("select"), Parser::regex("(f)(rom)", 2), Parser::regex("[a-z]+", 0), Parser::regex("x", 0)];
+        let alternation = Alternation::new(&terminals).unwrap();
+        let found = |s: &str| alternation.find(s, 0).map(|success| (success.position, success.value));
+        assert_eq!(found("from").unwrap(), (4, Value::Some("rom".to_string())));
+        assert_eq!(found("selection").unwrap(), (6, Value::None));
+        assert_eq!(found("xyz").unwrap(), (3, Value::Some("xyz".to_string())));
+        assert_eq!(found("9").unwrap_err().expected, vec!["select", "(f)(rom)", "[a-z]+", "x"]);
+
+        let parser = terminals.iter().cloned().reduce(Parser::or).unwrap().or(Parser::skip("[0-9]")).and(Parser::regex("(?i)Y|", 0));
+        for input in ["from", "selecty", "abY", "x", "7", "7y", "", "?"] {
+            assert_eq!(parser.optimize().parse(input), parser.parse(input), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn optimize_error() {
+        let item = Parser::rule("item");
+        item.define(item.clone().and(Parser::skip("\\+")).or(Parser::regex("[0-9]", 0)));
+        let left = Parser::regex("x", 0).or(item);
+        assert_eq!(left.optimize().parse("x").unwrap().value, left.parse("x").unwrap().value);
+
+        let undefined = Parser::rule("undefined");
+        let parser = Parser::skip("a").or(undefined).or(Parser::skip("b")).or(Parser::skip("c?").and(Parser::skip("d")));
+        for input in ["a", "b", "c", "cd", "d", "e", ""] {
+            assert_eq!(parser.optimize().parse(input), parser.parse(input), "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::instrument::{self, Wrap};
+use crate::{Failure, Parser, Success};
+
+/// Counters for one rule or terminal; identical terminals share an entry.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProfileEntry {
+    pub name: String,
+    pub calls: u64,
+    pub successes: u64,
+    pub failures: u64,
+    /// Calls at a position this rule was already tried at during the same parse.
+    pub retries: u64,
+    /// Input covered again by retries: consumed bytes, or bytes read before failing.
+    pub rescanned: u64,
+    /// Time including nested calls, counted once for recursive calls.
+    pub time: Duration,
+    /// Time excluding nested rules and terminals.
+    pub self_time: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Profile {
+    /// Sorted by self time, slowest first.
+    pub entries: Vec<ProfileEntry>,
+}
+
+/// The counters of one `ProfileEntry`, updated from any thread without locking.
+#[derive(Default)]
+struct Counters {
+    calls: AtomicU64,
+    successes: AtomicU64,
+    failures: AtomicU64,
+    retries: AtomicU64,
+    rescanned: AtomicU64,
+    /// In nanoseconds.
+    time: AtomicU64,
+    self_time: AtomicU64,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+static NEXT_PROFILER: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Active calls of this thread: profiler, entry index and time spent in nested calls.
+    static STACK: RefCell<Vec<(usize, usize, Duration)>> = const { RefCell::new(Vec::new()) };
+    /// Entries and positions each profiler has tried during its current parse.
+    static SEEN: RefCell<HashMap<usize, HashSet<(usize, i32)>>> = RefCell::new(HashMap::new());
+}
+
+/// A copy of a parser that collects a `ProfileEntry` per rule and terminal over all its parses.
+pub struct Profiler {
+    id: usize,
+    parser: Parser,
+    entries: Vec<(String, Arc<Counters>)>,
+}
+
+impl Parser {
+    pub fn profiler(&self) -> Profiler {
+        let profiler = NEXT_PROFILER.fetch_add(1, Ordering::Relaxed);
+        let entries = Arc::new(Mutex::new(Vec::<(String, Arc<Counters>)>::new()));
+        let stats = entries.clone();
+        let wrap: Wrap = Arc::new(move |original: &Parser, rebuilt: Parser| {
+            let name = match instrument::label(original) {
+                Some(name) => name,
+                None => return rebuilt,
+            };
+            let (id, counters) = {
+                let mut stats = stats.lock().unwrap();
+                match stats.iter().position(|(other, _)| *other == name) {
+                    Some(id) => (id, stats[id].1.clone()),
+                    None => {
+                        stats.push((name, Arc::new(Counters::default())));
+                        (stats.len() - 1, stats[stats.len() - 1].1.clone())
+                    }
+                }
+            };
+            let inner = rebuilt.func.clone();
+            Parser{node: rebuilt.node, func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+                let outermost = STACK.with(|stack| {
+                    let mut stack = stack.borrow_mut();
+                    let outermost = stack.iter().all(|&(other, active, _)| (other, active) != (profiler, id));
+                    stack.push((profiler, id, Duration::ZERO));
+                    outermost
+                });
+                let retry = !SEEN.with(|seen| seen.borrow_mut().entry(profiler).or_default().insert((id, i)));
+                let start = Instant::now();
+                let result = inner(root, s, i);
+                let elapsed = start.elapsed();
+                let nested = STACK.with(|stack| {
+                    let mut stack = stack.borrow_mut();
+                    let (_, _, nested) = stack.pop().unwrap();
+                    if let Some(parent) = stack.last_mut() {
+                        parent.2 += elapsed;
+                    }
+                    nested
+                });
+                Counters::add(&counters.calls, 1);
+                let end = match &result {
+                    Ok(success) => {
+                        Counters::add(&counters.successes, 1);
+                        success.position
+                    }
+                    Err(failure) => {
+                        Counters::add(&counters.failures, 1);
+                        failure.position
+                    }
+                };
+                if retry {
+                    Counters::add(&counters.retries, 1);
+                    Counters::add(&counters.rescanned, (end - i).max(0) as u64);
+                }
+                if outermost {
+                    Counters::add(&counters.time, elapsed.as_nanos() as u64);
+                }
+                Counters::add(&counters.self_time, elapsed.saturating_sub(nested).as_nanos() as u64);
+                result
+            })}
+        });
+        let parser = instrument::rebuild(self, &wrap);
+        let entries = entries.lock().unwrap().clone();
+        Profiler{id: profiler, parser, entries}
+    }
+}
+
+impl Profiler {
+    pub fn parse(&self, s: &str) -> Result<Success, Failure> {
+        SEEN.with(|seen| seen.borrow_mut().insert(self.id, HashSet::new()));
+        let result = self.parser.parse(s);
+        SEEN.with(|seen| seen.borrow_mut().remove(&self.id));
+        result
+    }
+
+    /// The entries collected so far.
+    pub fn profile(&self) -> Profile {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut entries: Vec<ProfileEntry> = self.entries.iter().map(|(name, counters)| ProfileEntry{
+            name: name.clone(),
+            calls: load(&counters.calls),
+            successes: load(&counters.successes),
+            failures: load(&counters.failures),
+            retries: load(&counters.retries),
+            rescanned: load(&counters.rescanned),
+            time: Duration::from_nanos(load(&counters.time)),
+            self_time: Duration::from_nanos(load(&counters.self_time)),
+        }).collect();
+        entries.sort_by(|a, b| b.self_time.cmp(&a.self_time).then_with(|| a.name.cmp(&b.name)));
+        Profile{entries}
+    }
+
+    pub fn reset(&self) {
+        for (_, counters) in self.entries.iter() {
+            for counter in [&counters.calls, &counters.successes, &counters.failures, &counters.retries, &counters.rescanned, &counters.time, &counters.self_time] {
+                counter.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.entries.iter().map(|e| e.name.chars().count()).max().unwrap_or(0).max(4);
+        writeln!(f, "{:width$} {:>8} {:>8} {:>8} {:>8} {:>10} {:>10} {:>10}", "rule", "calls", "ok", "fail", "retries", "rescanned", "time(us)", "self(us)", width = width)?;
+        for e in self.entries.iter() {
+            writeln!(f, "{:width$} {:>8} {:>8} {:>8} {:>8} {:>10} {:>10} {:>10}",
+                     e.name, e.calls, e.successes, e.failures, e.retries, e.rescanned, e.time.as_micros(), e.self_time.as_micros(), width = width)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn entry<'a>(profile: &'a Profile, name: &str) -> &'a ProfileEntry {
+        profile.entries.iter().find(|e| e.name == name).unwrap()
+    }
+
+    #[test]
+    fn profile_ok() {
+        let grammar = Grammar::parse("value = call | name ; call = name ~\"(\" value ~\")\" ; name = /[a-z]+/ ;").unwrap();
+        let profiler = grammar.parser(None).unwrap().profiler();
+        assert!(profiler.parse("f(g(x))").is_ok());
+        let profile = profiler.profile();
+        let value = entry(&profile, "value");
+        assert_eq!((value.calls, value.successes, value.failures, value.retries), (3, 3, 0, 0));
+        let name = entry(&profile, "name");
+        assert_eq!((name.calls, name.successes, name.failures, name.retries, name.rescanned), (4, 4, 0, 1, 1));
+        let call = entry(&profile, "call");
+        assert_eq!((call.calls, call.failures), (3, 1));
+        assert!(value.time >= call.time && value.time >= value.self_time);
+        assert!(profile.entries.windows(2).all(|w| w[0].self_time >= w[1].self_time));
+
+        let report = profile.to_string();
+        assert!(report.starts_with("rule ") && report.lines().next().unwrap().ends_with("self(us)"));
+        assert_eq!(report.lines().count(), profile.entries.len() + 1);
+
+        assert!(profiler.parse("f(x)").is_ok());
+        assert_eq!(entry(&profiler.profile(), "value").calls, 5);
+        profiler.reset();
+        assert_eq!(entry(&profiler.profile(), "value").calls, 0);
+    }
+
+    #[test]
+    fn profile_error() {
+        let grammar = Grammar::parse("value = call | name ; call = name ~\"(\" value ~\")\" ; name = /[a-z]+/ ;").unwrap();
+        let profiler = grammar.parser(None).unwrap().profiler();
+        assert_eq!(profiler.parse("f(g(x").err().map(|f| f.position), Some(1));
+        let profile = profiler.profile();
+        let call = entry(&profile, "call");
+        assert_eq!((call.calls, call.failures), (3, 3));
+        assert_eq!(entry(&profile, "~/\\)/").failures, 2);
+        assert_eq!(entry(&profile, "value").retries, 0);
+        assert!(entry(&profile, "name").rescanned >= 3);
+    }
+
+    #[test]
+    fn profile_nested_ok() {
+        let grammar = Grammar::parse("value = call | name ; call = name ~\"(\" value ~\")\" ; name = /[a-z]+/ ;").unwrap();
+        let parser = grammar.parser(None).unwrap();
+        let inner = Arc::new(parser.profiler());
+        let nested = inner.clone();
+        let first = Parser::from_func(Arc::new(move |_: &Parser, s: &str, i: i32| {
+            nested.parse(s).map(|_| Success{position: i, value: crate::Value::None})
+        }));
+        let outer = first.and(parser).profiler();
+        assert!(outer.parse("f(x)").is_ok());
+        assert_eq!(entry(&outer.profile(), "value").retries, 0);
+        assert_eq!(entry(&inner.profile(), "value").retries, 0);
+        assert_eq!(entry(&outer.profile(), "value").calls, entry(&inner.profile(), "value").calls);
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::HashSet;
+
+use regex::Regex;
+
+use crate::{QueryError, SyntaxNode};
+
+#[derive(Debug, Clone)]
+enum Test {
+    /// `_`: any node.
+    Any,
+    /// `(_ ...)`: any rule node.
+    Named,
+    Kind(String),
+    /// `"text"`: a token with this value.
+    Token(String),
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    test: Test,
+    /// Matched in order against the node's children, which may have others in between.
+    children: Vec<Pattern>,
+    captures: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Argument {
+    Capture(String),
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+enum Predicate {
+    Eq(String, Argument, bool),
+    Match(String, Box<Regex>),
+}
+
+/// A compiled set of tree-sitter style patterns over `SyntaxNode` trees, such as
+/// `(pair (key "id") @k (value _) @v)` or `((key) @k (#match? @k "^[a-z]+$"))`.
+/// `#eq?`, `#not-eq?` and `#match?` compare the text of captured nodes.
+#[derive(Debug, Clone)]
+pub struct Query {
+    patterns: Vec<(Pattern, Vec<Predicate>)>,
+    names: Vec<String>,
+}
+
+/// One match of a pattern, with its captures in pattern order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryMatch<'t> {
+    /// Index of the pattern in the query.
+    pub pattern: usize,
+    pub node: &'t SyntaxNode,
+    pub captures: Captures<'t>,
+}
+
+impl<'t> QueryMatch<'t> {
+    /// The first node captured as `name`.
+    pub fn capture(&self, name: &str) -> Option<&'t SyntaxNode> {
+        self.captures.iter().find(|(capture, _)| capture == name).map(|(_, node)| *node)
+    }
+}
+
+impl Query {
+    pub fn new(text: &str) -> Result<Query, QueryError> {
+        let mut reader = Reader{text, position: 0, names: vec![], bound: vec![]};
+        let mut patterns = vec![];
+        reader.space();
+        while reader.position < text.len() {
+            reader.bound.clear();
+            let mut predicates = vec![];
+            let pattern = reader.pattern(&mut predicates)?;
+            patterns.push((pattern, predicates));
+            reader.space();
+        }
+        if patterns.is_empty() {
+            return Err(reader.error("empty query"));
+        }
+        Ok(Query{patterns, names: reader.names})
+    }
+
+    /// The capture names used by the query, in order of first appearance.
+    pub fn capture_names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Every match in `tree`, in pre-order of the matched nodes and then pattern order.
+    /// A pattern that fits one node in several ways gives one match per distinct capture set.
+    pub fn matches<'t>(&self, tree: &'t SyntaxNode) -> Vec<QueryMatch<'t>> {
+        let mut out = vec![];
+        for node in tree.descendants() {
+            for (i, (pattern, predicates)) in self.patterns.iter().enumerate() {
+                let mut found: Vec<Captures> = vec![];
+                let mut seen = HashSet::new();
+                pattern.each(node, &mut vec![], &mut |captures| {
+                    if predicates.iter().all(|predicate| predicate.holds(captures)) && seen.insert(captures.iter().map(|(_, node)| *node as *const SyntaxNode).collect::<Vec<_>>()) {
+                        found.push(captures.clone());
+                    }
+                });
+                out.extend(found.into_iter().map(|captures| QueryMatch{pattern: i, node, captures}));
+            }
+        }
+        out
+    }
+}
+
+/// Captures so far, in pattern order.
+type Captures<'t> = Vec<(String, &'t SyntaxNode)>;
+
+impl Pattern {
+    fn fits(&self, node: &SyntaxNode) -> bool {
+        match &self.test {
+            Test::Any => true,
+            Test::Named => node.kind.is_some(),
+            Test::Kind(kind) => node.kind.as_ref() == Some(kind),
+            Test::Token(text) => node.kind.is_none() && node.children.is_empty() && node.text == *text,
+        }
+    }
+
+    fn capturing(&self) -> bool {
+        !self.captures.is_empty() || self.children.iter().any(Pattern::capturing)
+    }
+
+    /// Whether the pattern matches `node` at all, taking each child's earliest match.
+    fn found(&self, node: &SyntaxNode) -> bool {
+        if !self.fits(node) {
+            return false;
+        }
+        let mut patterns = self.children.iter().peekable();
+        for child in node.children.iter() {
+            if patterns.peek().is_some_and(|pattern| pattern.found(child)) {
+                patterns.next();
+            }
+        }
+        patterns.peek().is_none()
+    }
+
+    /// Calls `then` once for every way the pattern matches `node`.
+    fn each<'t>(&self, node: &'t SyntaxNode, captures: &mut Captures<'t>, then: &mut dyn FnMut(&mut Captures<'t>)) {
+        if !self.fits(node) {
+            return;
+        }
+        let mark = captures.len();
+        captures.extend(self.captures.iter().map(|name| (name.clone(), node)));
+        Pattern::sequence(&self.children, &node.children, captures, then);
+        captures.truncate(mark);
+    }
+
+    /// Matches `patterns` against the ordered subsequences of `nodes`. Only nodes that leave
+    /// room for the rest of the patterns are tried, and a pattern without captures only at its
+    /// first match, since later ones leave the rest fewer nodes and capture nothing new.
+    fn sequence<'t>(patterns: &[Pattern], nodes: &'t [SyntaxNode], captures: &mut Captures<'t>, then: &mut dyn FnMut(&mut Captures<'t>)) {
+        let (first, rest) = match patterns.split_first() {
+            None => return then(captures),
+            Some(split) => split,
+        };
+        let mut end = nodes.len();
+        for pattern in rest.iter().rev() {
+            match nodes[..end].iter().rposition(|node| pattern.found(node)) {
+                Some(i) => end = i,
+                None => return,
+            }
+        }
+        let mut candidates = nodes[..end].iter().enumerate().filter(|(_, node)| first.found(node));
+        if !first.capturing() {
+            if let Some((i, _)) = candidates.next() {
+                Pattern::sequence(rest, &nodes[i + 1..], captures, then);
+            }
+            return;
+        }
+        for (i, node) in candidates {
+            first.each(node, captures, &mut |captures| Pattern::sequence(rest, &nodes[i + 1..], captures, then));
+        }
+    }
+}
+
+impl Predicate {
+    fn holds(&self, captures: &[(String, &SyntaxNode)]) -> bool {
+        let text = |name: &str| captures.iter().find(|(capture, _)| capture == name).map(|(_, node)| node.text.as_str());
+        match self {
+            Predicate::Eq(name, argument, equal) => {
+                let other = match argument {
+                    Argument::Capture(other) => text(other),
+                    Argument::Text(other) => Some(other.as_str()),
+                };
+                (text(name).is_some() && text(name) == other) == *equal
+            }
+            Predicate::Match(name, regex) => text(name).is_some_and(|text| regex.is_match(text)),
+        }
+    }
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    position: usize,
+    names: Vec<String>,
+    /// The captures of the pattern being read, which its predicates may refer to.
+    bound: Vec<String>,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> QueryError {
+        QueryError{position: self.position, message: message.to_string()}
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn space(&mut self) {
+        loop {
+            let rest = self.rest().trim_start();
+            self.position = self.text.len() - rest.len();
+            match rest.starts_with(';') {
+                true => self.position += rest.find('\n').unwrap_or(rest.len()),
+                false => return,
+            }
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.space();
+        let found = self.rest().starts_with(token);
+        if found {
+            self.position += token.len();
+        }
+        found
+    }
+
+    fn name(&mut self) -> &'a str {
+        let rest = self.rest();
+        let length = rest.find(|c: char| !(c.is_alphanumeric() || "_-.?!".contains(c))).unwrap_or(rest.len());
+        self.position += length;
+        &rest[..length]
+    }
+
+    fn pattern(&mut self, predicates: &mut Vec<Predicate>) -> Result<Pattern, QueryError> {
+        self.space();
+        let mut pattern = if self.eat("(") {
+            self.space();
+            if self.rest().starts_with(['(', '"']) {
+                return self.group(predicates);
+            }
+            let start = self.position;
+            let test = match self.name() {
+                "" => {
+                    self.position = start;
+                    return Err(self.error("expected a rule name or _"));
+                }
+                "_" => Test::Named,
+                name => Test::Kind(name.to_string()),
+            };
+            let mut children = vec![];
+            while !self.eat(")") {
+                if self.rest().is_empty() {
+                    return Err(self.error("expected ')'"));
+                }
+                if self.rest().starts_with("(#") {
+                    self.position += 1;
+                    predicates.push(self.predicate()?);
+                } else {
+                    children.push(self.pattern(predicates)?);
+                }
+            }
+            Pattern{test, children, captures: vec![]}
+        } else if self.rest().starts_with('"') {
+            Pattern{test: Test::Token(self.string()?), children: vec![], captures: vec![]}
+        } else if self.eat("_") {
+            Pattern{test: Test::Any, children: vec![], captures: vec![]}
+        } else {
+            return Err(self.error("expected a pattern"));
+        };
+        while self.eat("@") {
+            let name = self.capture_name()?;
+            pattern.captures.push(name);
+        }
+        Ok(pattern)
+    }
+
+    /// The rest of a `((pattern) predicate...)` group after `(`.
+    fn group(&mut self, predicates: &mut Vec<Predicate>) -> Result<Pattern, QueryError> {
+        let mut pattern = self.pattern(predicates)?;
+        while !self.eat(")") {
+            if !self.rest().starts_with("(#") {
+                return Err(self.error("expected a predicate or ')'; groups hold one pattern"));
+            }
+            self.position += 1;
+            predicates.push(self.predicate()?);
+        }
+        while self.eat("@") {
+            let name = self.capture_name()?;
+            pattern.captures.push(name);
+        }
+        Ok(pattern)
+    }
+
+    fn capture_name(&mut self) -> Result<String, QueryError> {
+        let name = self.name();
+        if name.is_empty() {
+            return Err(self.error("expected a capture name"));
+        }
+        if !self.names.iter().any(|known| known == name) {
+            self.names.push(name.to_string());
+        }
+        if !self.bound.iter().any(|known| known == name) {
+            self.bound.push(name.to_string());
+        }
+        Ok(name.to_string())
+    }
+
+    /// The rest of a predicate after `(`.
+    fn predicate(&mut self) -> Result<Predicate, QueryError> {
+        let start = self.position;
+        self.position += 1;
+        let operator = self.name();
+        let capture = |reader: &mut Self| {
+            reader.space();
+            let at = reader.position;
+            match reader.eat("@") {
+                true => {
+                    let name = reader.name();
+                    match reader.bound.iter().any(|known| known == name) {
+                        true => Ok(name.to_string()),
+                        false => Err(QueryError{position: at, message: format!("unknown capture @{}", name)}),
+                    }
+                }
+                false => Err(reader.error("expected a capture")),
+            }
+        };
+        let predicate = match operator {
+            "eq?" | "not-eq?" => {
+                let name = capture(self)?;
+                self.space();
+                let argument = match self.rest().starts_with('"') {
+                    true => Argument::Text(self.string()?),
+                    false => Argument::Capture(capture(self)?),
+                };
+                Predicate::Eq(name, argument, operator == "eq?")
+            }
+            "match?" => {
+                let name = capture(self)?;
+                self.space();
+                let at = self.position;
+                let pattern = self.string()?;
+                let regex = Regex::new(&pattern).map_err(|e| QueryError{position: at, message: format!("invalid regex: {}", e)})?;
+                Predicate::Match(name, Box::new(regex))
+            }
+            _ => {
+                self.position = start;
+                return Err(self.error("unknown predicate"));
+            }
+        };
+        match self.eat(")") {
+            true => Ok(predicate),
+            false => Err(self.error("expected ')'")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, QueryError> {
+        if !self.eat("\"") {
+            return Err(self.error("expected a quoted string"));
+        }
+        let start = self.position - 1;
+        let mut out = String::new();
+        let mut chars = self.rest().chars();
+        while let Some(c) = chars.next() {
+            self.position += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escape = chars.next().unwrap_or('\\');
+                    self.position += escape.len_utf8();
+                    out.push(match escape {
+                        'n' => '\n',
+                        't' => '\t',
+                        c => c,
+                    });
+                }
+                c => out.push(c),
+            }
+        }
+        self.position = start;
+        Err(self.error("unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn tree(source: &str) -> SyntaxNode {
+        Grammar::parse(r#"
+            object = ~/\s*\{/ flat(list(pair) (~"," pair)*)? ~/\s*\}/ ;
+            pair   = key ~/\s*:/ value ;
+            key    = ~/\s*"/ /[^"]*/ ~"\"" ;
+            value  = ~/\s*/ (/[0-9]+/ | object) ;
+        "#).unwrap().parser(None).unwrap().parse_syntax(source).unwrap()
+    }
+
+    #[test]
+    fn query_ok() {
+        let source = r#"{"id": 1, "b": {"id": 22, "c": 3}}"#;
+        let tree = tree(source);
+        let query = Query::new(r#"(pair (key "id") @k (value _) @v) ; ids"#).unwrap();
+        let matches = query.matches(&tree);
+        let found: Vec<(&str, &str)> = matches.iter().map(|m| (&source[m.capture("k").unwrap().span.clone()], m.capture("v").unwrap().text.as_str())).collect();
+        assert_eq!(found, [("\"id\"", "1"), ("\"id\"", "22")]);
+        assert_eq!(query.capture_names(), ["k", "v"]);
+
+        let query = Query::new(r#"
+            (pair (key) @k (value (object)) @v)
+            ((key) @k (#match? @k "^\"[a-c]\"$"))
+            (object (pair (key "id")) (pair (key) @last (#not-eq? @last "\"b\"")))
+        "#).unwrap();
+        let matches: Vec<(usize, &str)> = query.matches(&tree).iter().map(|m| (m.pattern, m.captures[0].1.text.as_str())).collect();
+        assert_eq!(matches, [(0, "\"b\""), (1, "\"b\""), (2, "\"c\""), (1, "\"c\"")]);
+
+        let nested = Query::new("(object (pair (value (object (pair (key) @inner)))))").unwrap();
+        let inner: Vec<&str> = nested.matches(&tree).iter().map(|m| m.capture("inner").unwrap().text.as_str()).collect();
+        assert_eq!(inner, ["\"id\"", "\"c\""]);
+        assert!(Query::new("(pair (key) @a (value) @b (#eq? @a @b))").unwrap().matches(&tree).is_empty());
+
+        let pairs: Vec<String> = (0..80).map(|i| format!("\"k{}\": {}", i, i)).collect();
+        let wide = self::tree(&format!("{{{}}}", pairs.join(", ")));
+        let query = Query::new("(object (pair) (pair) (pair) (pair) (pair (key) @k) (pair))").unwrap();
+        let keys: Vec<&str> = query.matches(&wide).iter().map(|m| m.capture("k").unwrap().text.as_str()).collect();
+        assert_eq!(keys.len(), 75);
+        assert_eq!((keys[0], keys[74]), ("\"k4\"", "\"k78\""));
+    }
+
+    #[test]
+    fn query_error() {
+        let error = |text: &str| Query::new(text).map_err(|e| (e.position, e.message)).unwrap_err();
+        assert_eq!(error(""), (0, "empty query".to_string()));
+        assert_eq!(error("(pair"), (5, "expected ')'".to_string()));
+        assert_eq!(error("()"), (1, "expected a rule name or _".to_string()));
+        assert_eq!(error("(pair) @"), (8, "expected a capture name".to_string()));
+        assert_eq!(error("(pair (#eq? @x \"a\"))"), (12, "unknown capture @x".to_string()));
+        assert_eq!(error("(pair) @p (#eq? @p \"a\")"), (11, "expected a rule name or _".to_string()));
+        assert_eq!(error("((pair) @p (#same? @p @p))"), (12, "unknown predicate".to_string()));
+        assert_eq!(error("((pair) @p (#match? @p \"(\"))"), (23, "invalid regex: regex parse error:\n    (\n    ^\nerror: unclosed group".to_string()));
+        assert_eq!(error("\"abc"), (0, "unterminated string".to_string()));
+        assert_eq!(error("(key) @a ((value) @v (#not-eq? @a \"1\"))"), (31, "unknown capture @a".to_string()));
+        assert_eq!(Query::new("x").unwrap_err().to_string(), "invalid query at 0: expected a pattern");
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use crate::{Grammar, GrammarError, Parser};
+
+const HELP: &str = "\
+NAME = EXPR ;       define or redefine a rule (may span lines)
+:parse RULE TEXT    parse TEXT (the rest of the line) from RULE
+:rules              print the grammar
+:delete NAME        remove a rule
+:load FILE          add the rules in FILE
+:save FILE          write the grammar to FILE
+:help               show this help
+:quit               leave the shell
+";
+
+/// An interactive shell for growing a grammar and trying it on samples.
+#[derive(Default)]
+pub struct Repl {
+    grammar: Grammar,
+    pending: String,
+    done: bool,
+    /// The grammar's parsers, compiled on the first `:parse` after a change.
+    compiled: Option<Result<Vec<Parser>, GrammarError>>,
+}
+
+impl fmt::Debug for Repl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Repl").field("grammar", &self.grammar).field("pending", &self.pending).field("done", &self.done).finish_non_exhaustive()
+    }
+}
+
+impl Repl {
+    pub fn new(grammar: Grammar) -> Self {
+        Repl{grammar, ..Repl::default()}
+    }
+
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    /// Whether the last line started a definition that is not yet closed by `;`.
+    pub fn pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Runs one input line and returns what to print.
+    pub fn execute(&mut self, line: &str) -> String {
+        if self.pending() || !line.trim_start().starts_with(':') {
+            return self.define(line);
+        }
+        let line = line.trim_start();
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            ":parse" => self.parse(rest),
+            ":rules" => self.grammar.to_string(),
+            ":delete" => {
+                let name = rest.trim();
+                match self.grammar.rule(name) {
+                    Some(_) => {
+                        self.grammar.rules.retain(|rule| rule.name != name);
+                        self.compiled = None;
+                        format!("deleted {}\n", name)
+                    }
+                    None => format!("error: no rule {:?}\n", name),
+                }
+            }
+            ":load" => match fs::read_to_string(rest.trim()) {
+                Ok(text) => match self.extend(&text) {
+                    Ok(names) => format!("loaded {} rules\n", names.len()),
+                    Err(e) => format!("error: {}: {}\n", rest.trim(), e),
+                },
+                Err(e) => format!("error: {}: {}\n", rest.trim(), e),
+            },
+            ":save" => match fs::write(rest.trim(), self.grammar.to_string()) {
+                Ok(()) => format!("saved {} rules to {}\n", self.grammar.rules.len(), rest.trim()),
+                Err(e) => format!("error: {}: {}\n", rest.trim(), e),
+            },
+            ":help" => HELP.to_string(),
+            ":quit" => {
+                self.done = true;
+                String::new()
+            }
+            _ => format!("error: unknown command {:?}; try :help\n", command),
+        }
+    }
+
+    fn define(&mut self, line: &str) -> String {
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        if self.pending.trim().is_empty() {
+            self.pending.clear();
+            return String::new();
+        }
+        if !line.trim_end().ends_with(';') {
+            return String::new();
+        }
+        let text = std::mem::take(&mut self.pending);
+        match self.extend(&text) {
+            Ok(names) => names.iter().map(|name| format!("defined {}\n", name)).collect(),
+            Err(e) => format!("error: {}\n", e),
+        }
+    }
+
+    fn extend(&mut self, text: &str) -> Result<Vec<String>, GrammarError> {
+        self.compiled = None;
+        self.grammar.extend(text)
+    }
+
+    fn parse(&mut self, rest: &str) -> String {
+        let (name, text) = rest.split_once(' ').unwrap_or((rest, ""));
+        let grammar = &self.grammar;
+        let parser = match self.compiled.get_or_insert_with(|| grammar.compile()) {
+            Ok(parsers) => grammar.start(Some(name)).map(|index| parsers[index].clone()),
+            Err(e) => Err(e.clone()),
+        };
+        let parser = match parser {
+            Ok(parser) => parser,
+            Err(e) => return format!("error: {}\n", e),
+        };
+        match parser.parse(text) {
+            Ok(success) => format!("{}\n", success.value.to_json()),
+            Err(failure) => format!("{} (position {})\n", failure.to_error(text), failure.position),
+        }
+    }
+
+    /// Reads lines from `input` until end of input or `:quit`, printing a prompt before each.
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+        let mut line = String::new();
+        while !self.done {
+            output.write_all(if self.pending() {b"| "} else {b"> "})?;
+            output.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                break;
+            }
+            let reply = self.execute(line.trim_end_matches(['\n', '\r']));
+            output.write_all(reply.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repl_ok() {
+        let mut repl = Repl::new(Grammar::default());
+        assert_eq!(repl.execute("pair = key ~\"=\""), "");
+        assert!(repl.pending());
+        assert_eq!(repl.execute("  value ;"), "defined pair\n");
+        assert_eq!(repl.execute("key = /[a-z]+/ ; value = /[0-9]+/ ;"), "defined key\ndefined value\n");
+        assert_eq!(repl.execute(":parse pair ab=12"), "[\"ab\",\"12\"]\n");
+        assert_eq!(repl.execute(":parse value 7"), "\"7\"\n");
+        assert!(repl.compiled.is_some());
+        assert_eq!(repl.execute("value = /[0-9]+/ | \"none\" ;"), "defined value\n");
+        assert!(repl.compiled.is_none());
+        assert_eq!(repl.execute(":parse pair a=none"), "[\"a\",\"none\"]\n");
+        assert_eq!(repl.execute(":delete value"), "deleted value\n");
+        assert_eq!(repl.execute(":rules"), "pair = key ~\"=\" value ;\nkey = /[a-z]+/ ;\n");
+
+        let path = std::env::temp_dir().join(format!("pcc2-repl-{}.peg", std::process::id()));
+        let path = path.to_string_lossy();
+        assert_eq!(repl.execute(&format!(":save {}", path)), format!("saved 2 rules to {}\n", path));
+        let mut other = Repl::new(Grammar::default());
+        assert_eq!(other.execute(&format!(":load {}", path)), "loaded 2 rules\n");
+        assert_eq!(other.grammar().to_string(), repl.grammar().to_string());
+
+        let mut output = vec![];
+        let mut input = "a = /x/\n;\n:parse a x\n:quit\n:parse a y\n".as_bytes();
+        Repl::new(Grammar::default()).run(&mut input, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "> | defined a\n> \"x\"\n> ");
+    }
+
+    #[test]
+    fn repl_error() {
+        let mut repl = Repl::new(Grammar::parse("a = ~\"(\" /[0-9]+/ ~\")\" ;").unwrap());
+        assert_eq!(repl.execute(":parse a (12"), "parse error at line 1, column 4: expected \"\\\\)\" (position 3)\n");
+        assert_eq!(repl.execute(":parse b x"), "error: no rule \"b\" to start from\n");
+        assert_eq!(repl.execute("b = a c ;"), "defined b\n");
+        assert_eq!(repl.execute(":parse b x"), "error: line 1, column 1: undefined rule \"c\"\n");
+        assert_eq!(repl.execute("c = (;"), "error: line 1, column 6: expected an expression\n");
+        assert!(!repl.pending());
+        assert_eq!(repl.execute(":delete z"), "error: no rule \"z\"\n");
+        assert_eq!(repl.execute(":frob"), "error: unknown command \":frob\"; try :help\n");
+        assert!(repl.execute(":load /nonexistent/file").starts_with("error: /nonexistent/file: "));
+    }
+}
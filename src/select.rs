@@ -0,0 +1,335 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
+
+use crate::{Cst, CstTree, Failure, Parser, SelectorError, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Test {
+    Any,
+    /// A child index; negative indexes count from the end.
+    Index(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Predicate {
+    /// Child indexes from the candidate; empty for the candidate itself.
+    path: Vec<i64>,
+    /// Without a text, the predicate only asks that the path exists.
+    text: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    /// `//`: the step applies to the children of every descendant, not just the current nodes.
+    deep: bool,
+    test: Test,
+    predicates: Vec<Predicate>,
+}
+
+/// A compiled path such as `/*/1` or `//*[0="key"]/1`. Values carry no rule names, so
+/// steps select children by position: `*` or an index, optionally with `[0/1="text"]`,
+/// `[.="text"]` or `[2]` predicates on child paths.
+///
+/// Name steps such as `//pair[0="key"]/1` are not supported and are rejected when parsing;
+/// use `*` with a predicate instead, or a `Query` over `SyntaxNode` kinds to match by rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    text: String,
+    steps: Vec<Step>,
+}
+
+/// A selected node of a concrete syntax tree, with the source range from its first to its
+/// last token; `None` for `None` values and empty lists.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    pub value: Value,
+    pub span: Option<Range<usize>>,
+}
+
+/// The trees a selector walks.
+trait Tree: Sized {
+    fn children(&self) -> &[Self];
+    fn text(&self) -> Option<&str>;
+}
+
+impl Tree for Value {
+    fn children(&self) -> &[Value] {
+        match self {
+            Value::List(items) => items,
+            _ => &[],
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Value::Some(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl Tree for Cst {
+    fn children(&self) -> &[Cst] {
+        match self {
+            Cst::List(children) => children,
+            _ => &[],
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Cst::Token{value, ..} => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn child<T: Tree>(node: &T, index: i64) -> Option<&T> {
+    let children = node.children();
+    let index = if index < 0 {children.len() as i64 + index} else {index};
+    children.get(usize::try_from(index).ok()?)
+}
+
+fn descendants<'a, T: Tree>(node: &'a T, out: &mut Vec<&'a T>) {
+    out.push(node);
+    node.children().iter().for_each(|child| descendants(child, out));
+}
+
+fn span(cst: &Cst) -> Option<Range<usize>> {
+    match cst {
+        Cst::None => None,
+        Cst::Token{span, ..} => Some(span.clone()),
+        Cst::List(children) => {
+            let mut spans = children.iter().filter_map(span);
+            let first = spans.next()?;
+            Some(first.start..spans.next_back().unwrap_or(first).end)
+        }
+    }
+}
+
+impl Predicate {
+    fn holds<T: Tree>(&self, node: &T) -> bool {
+        let target = self.path.iter().try_fold(node, |node, &index| child(node, index));
+        match (target, &self.text) {
+            (Some(target), Some(text)) => target.text() == Some(text.as_str()),
+            (target, None) => target.is_some(),
+            (None, _) => false,
+        }
+    }
+}
+
+impl Selector {
+    pub fn parse(text: &str) -> Result<Selector, SelectorError> {
+        let mut scanner = Scanner{text, position: 0};
+        let mut steps = vec![];
+        scanner.space();
+        while scanner.position < text.len() {
+            if !scanner.eat("/") {
+                return Err(scanner.error("expected '/'"));
+            }
+            let deep = scanner.eat("/");
+            scanner.space();
+            let test = match scanner.eat("*") {
+                true => Test::Any,
+                false => Test::Index(scanner.index()?),
+            };
+            let mut predicates = vec![];
+            while scanner.eat("[") {
+                predicates.push(scanner.predicate()?);
+            }
+            steps.push(Step{deep, test, predicates});
+        }
+        if steps.is_empty() {
+            return Err(scanner.error("empty selector"));
+        }
+        Ok(Selector{text: text.to_string(), steps})
+    }
+
+    fn apply<'a, T: Tree>(&self, root: &'a T) -> Vec<&'a T> {
+        let mut nodes = vec![root];
+        for step in self.steps.iter() {
+            let mut context = vec![];
+            match step.deep {
+                true => nodes.iter().for_each(|node| descendants(*node, &mut context)),
+                false => context = nodes,
+            }
+            let mut next: Vec<&T> = vec![];
+            for node in context {
+                let candidates: Vec<&T> = match step.test {
+                    Test::Any => node.children().iter().collect(),
+                    Test::Index(index) => child(node, index).into_iter().collect(),
+                };
+                for candidate in candidates {
+                    let fresh = !next.iter().any(|seen| std::ptr::eq(*seen, candidate));
+                    if fresh && step.predicates.iter().all(|predicate| predicate.holds(candidate)) {
+                        next.push(candidate);
+                    }
+                }
+            }
+            nodes = next;
+        }
+        nodes
+    }
+
+    /// The matching nodes of a value, in document order of the first step that reached them.
+    pub fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        self.apply(value)
+    }
+
+    /// The matching nodes of a concrete syntax tree, with their source spans.
+    pub fn select_cst(&self, tree: &CstTree) -> Vec<Selection> {
+        self.apply(&tree.root).into_iter().map(|cst| Selection{value: cst.value(), span: span(cst)}).collect()
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Parser {
+    /// Parses `s` and selects nodes from the result, keeping their spans in `s`.
+    pub fn select(&self, s: &str, selector: &Selector) -> Result<Vec<Selection>, Failure> {
+        Ok(selector.select_cst(&self.parse_cst(s)?))
+    }
+}
+
+struct Scanner<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl Scanner<'_> {
+    fn error(&self, message: &str) -> SelectorError {
+        SelectorError{position: self.position, message: message.to_string()}
+    }
+
+    fn space(&mut self) {
+        let rest = &self.text[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.space();
+        let found = self.text[self.position..].starts_with(token);
+        if found {
+            self.position += token.len();
+        }
+        found
+    }
+
+    fn index(&mut self) -> Result<i64, SelectorError> {
+        self.space();
+        let rest = &self.text[self.position..];
+        let sign = rest.starts_with('-') as usize;
+        let length = sign + rest[sign..].find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len() - sign);
+        if length == sign {
+            let message = match rest.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+                true => "expected '*' or an index; values have no names to select by",
+                false => "expected '*' or an index",
+            };
+            return Err(self.error(message));
+        }
+        let index = rest[..length].parse().map_err(|_| self.error("index out of range"))?;
+        self.position += length;
+        Ok(index)
+    }
+
+    /// The rest of a predicate after `[`.
+    fn predicate(&mut self) -> Result<Predicate, SelectorError> {
+        let mut path = vec![];
+        if !self.eat(".") {
+            path.push(self.index()?);
+            while self.eat("/") {
+                path.push(self.index()?);
+            }
+        }
+        let text = match self.eat("=") {
+            true => Some(self.string()?),
+            false => None,
+        };
+        if !self.eat("]") {
+            return Err(self.error("expected ']'"));
+        }
+        Ok(Predicate{path, text})
+    }
+
+    fn string(&mut self) -> Result<String, SelectorError> {
+        if !self.eat("\"") {
+            return Err(self.error("expected a quoted string"));
+        }
+        let start = self.position - 1;
+        let mut out = String::new();
+        let mut chars = self.text[self.position..].chars();
+        while let Some(c) = chars.next() {
+            self.position += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => match chars.next() {
+                    Some(c) => {
+                        self.position += c.len_utf8();
+                        out.push(match c {
+                            'n' => '\n',
+                            't' => '\t',
+                            c => c,
+                        });
+                    }
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        self.position = start;
+        Err(self.error("unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn parser() -> Parser {
+        Grammar::parse(r#"
+            object = ~/\s*\{/ flat(list(pair) (~"," pair)*) ~/\s*\}/ ;
+            pair   = ~/\s*/ /[a-z]+/ ~/\s*:/ value ;
+            value  = ~/\s*/ (/[0-9]+/ | object) ;
+        "#).unwrap().parser(None).unwrap()
+    }
+
+    #[test]
+    fn select_ok() {
+        let value = parser().parse("{key: 1, b: {key: 2, c: 3}}").unwrap().value;
+        let texts = |selector: &str| -> Vec<String> {
+            Selector::parse(selector).unwrap().select(&value).iter().map(|value| value.to_json()).collect()
+        };
+        assert_eq!(texts("/*/0"), ["\"key\"", "\"b\""]);
+        assert_eq!(texts("/-1/1/*/1"), ["\"2\"", "\"3\""]);
+        assert_eq!(texts("//*[0=\"key\"]/1"), ["\"1\"", "\"2\""]);
+        assert_eq!(texts("//*[1/0/1]"), ["[\"b\",[[\"key\",\"2\"],[\"c\",\"3\"]]]"]);
+        assert_eq!(texts("//*[.=\"3\"]"), ["\"3\""]);
+        assert_eq!(texts("/5"), Vec::<String>::new());
+
+        let source = "{key: 1, b: {key: 2, c: 3}}";
+        let selections = parser().select(source, &Selector::parse("//*[0=\"key\"]").unwrap()).unwrap();
+        let spans: Vec<&str> = selections.iter().map(|selection| &source[selection.span.clone().unwrap()]).collect();
+        assert_eq!(spans, ["key: 1", "key: 2"]);
+        assert_eq!(selections[1].value, Value::List(vec![Value::Some("key".to_string()), Value::Some("2".to_string())]));
+        assert_eq!(Selector::parse(" //* [ 0 = \"a\\\"\" ] ").unwrap().to_string(), " //* [ 0 = \"a\\\"\" ] ");
+    }
+
+    #[test]
+    fn select_error() {
+        let error = |selector: &str| Selector::parse(selector).map_err(|e| (e.position, e.message)).unwrap_err();
+        assert_eq!(error(""), (0, "empty selector".to_string()));
+        assert_eq!(error("*"), (0, "expected '/'".to_string()));
+        assert_eq!(error("//pair"), (2, "expected '*' or an index; values have no names to select by".to_string()));
+        assert_eq!(error("/*[0=\"a]"), (5, "unterminated string".to_string()));
+        assert_eq!(error("/*[0=a]"), (5, "expected a quoted string".to_string()));
+        assert_eq!(error("/*[0"), (4, "expected ']'".to_string()));
+        assert_eq!(Selector::parse("/x").unwrap_err().to_string(), "invalid selector at 1: expected '*' or an index; values have no names to select by");
+        assert!(parser().select("{a}", &Selector::parse("/*").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use std::cell::RefCell;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::instrument::{self, Wrap};
+use crate::{Failure, Node, Parser};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Kind {
+    Rule(String),
+    Repeat,
+    /// One match of a repeat's item.
+    Item,
+    /// A value-producing terminal, with its value.
+    Token(String),
+}
+
+/// Where a rule, repeat or terminal matched in a successful parse.
+#[derive(Debug, Clone)]
+pub(crate) struct Span {
+    pub(crate) kind: Kind,
+    pub(crate) span: Range<usize>,
+    pub(crate) children: Vec<Span>,
+}
+
+impl Span {
+    pub(crate) fn walk<'a>(&'a self, out: &mut Vec<&'a Span>) {
+        out.push(self);
+        self.children.iter().for_each(|child| child.walk(out));
+    }
+}
+
+thread_local! {
+    /// Spans of the calls in progress; `None` outside `record`.
+    static FRAMES: RefCell<Option<Vec<Vec<Span>>>> = const { RefCell::new(None) };
+}
+
+/// Collects the spans `parser` matches into a new frame and adds them as one span on success.
+fn framed(kind: Kind, parser: &Parser) -> Parser {
+    let inner = parser.func.clone();
+    Parser{node: parser.node.clone(), func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+        let active = FRAMES.with(|frames| frames.borrow_mut().as_mut().map(|frames| frames.push(vec![])).is_some());
+        let result = inner(root, s, i);
+        if active {
+            FRAMES.with(|frames| {
+                if let Some(frames) = frames.borrow_mut().as_mut() {
+                    let children = frames.pop().unwrap();
+                    if let (Ok(success), Some(parent)) = (&result, frames.last_mut()) {
+                        let kind = match (&kind, &success.value) {
+                            (Kind::Token(_), crate::Value::Some(value)) => Kind::Token(value.clone()),
+                            (kind, _) => kind.clone(),
+                        };
+                        parent.push(Span{kind, span: i as usize..success.position as usize, children});
+                    }
+                }
+            });
+        }
+        result
+    })}
+}
+
+/// Drops the spans a failed attempt of `parser` left behind.
+fn rewinding(parser: Parser) -> Parser {
+    let inner = parser.func.clone();
+    Parser{node: parser.node, func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+        let mark = FRAMES.with(|frames| frames.borrow().as_ref().and_then(|frames| frames.last().map(Vec::len)));
+        let result = inner(root, s, i);
+        if let (Err(_), Some(mark)) = (&result, mark) {
+            FRAMES.with(|frames| {
+                if let Some(frame) = frames.borrow_mut().as_mut().and_then(|frames| frames.last_mut()) {
+                    frame.truncate(mark);
+                }
+            });
+        }
+        result
+    })}
+}
+
+/// A copy of `parser` that records spans when run under `record`; `rule` sees each rule once.
+pub(crate) fn recorder(parser: &Parser, rule: impl Fn(&str, &Parser) + Send + Sync + 'static) -> Parser {
+    let wrap: Wrap = Arc::new(move |original: &Parser, rebuilt: Parser| match (&*original.node, &*rebuilt.node) {
+        (Node::Rule(name, _), _) => {
+            rule(name, original);
+            rewinding(framed(Kind::Rule(name.clone()), &rebuilt))
+        }
+        (_, Node::Repeat(item)) => framed(Kind::Repeat, &framed(Kind::Item, item).repeat()),
+        (_, Node::Regex{group, ..}) if *group >= 0 => framed(Kind::Token(String::new()), &rebuilt),
+        _ => rewinding(rebuilt),
+    });
+    instrument::rebuild(parser, &wrap)
+}
+
+/// Runs a `recorder` parser and returns the top-level spans.
+pub(crate) fn record(recorder: &Parser, s: &str) -> Result<Vec<Span>, Failure> {
+    let saved = FRAMES.with(|frames| frames.replace(Some(vec![vec![]])));
+    let result = recorder.parse(s);
+    let mut frames = FRAMES.with(|frames| frames.replace(saved)).unwrap();
+    result.map(|_| frames.pop().unwrap_or_default())
+}
+
+/// A parse tree with rule names, for structural queries: one node per rule match,
+/// with value-producing terminals as unnamed tokens.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxNode {
+    /// The rule name; `None` for tokens and for a root that is not a rule.
+    pub kind: Option<String>,
+    pub span: Range<usize>,
+    /// The captured value for tokens, the matched source otherwise.
+    /// Rule spans leave out surrounding whitespace.
+    pub text: String,
+    pub children: Vec<SyntaxNode>,
+}
+
+impl SyntaxNode {
+    fn build(spans: Vec<Span>, s: &str, out: &mut Vec<SyntaxNode>) {
+        for span in spans {
+            match span.kind {
+                Kind::Rule(name) => {
+                    let mut children = vec![];
+                    SyntaxNode::build(span.children, s, &mut children);
+                    let text = &s[span.span.clone()];
+                    let start = span.span.start + text.len() - text.trim_start().len();
+                    let span = start..start + text.trim().len();
+                    out.push(SyntaxNode{kind: Some(name), text: s[span.clone()].to_string(), span, children});
+                }
+                Kind::Token(value) => out.push(SyntaxNode{kind: None, span: span.span, text: value, children: vec![]}),
+                Kind::Repeat | Kind::Item => SyntaxNode::build(span.children, s, out),
+            }
+        }
+    }
+
+    /// The node and all its descendants, each before its children.
+    pub fn descendants(&self) -> Vec<&SyntaxNode> {
+        let mut out = vec![self];
+        let mut i = 0;
+        while i < out.len() {
+            let node = out[i];
+            out.splice(i + 1..i + 1, node.children.iter());
+            i += 1;
+        }
+        out
+    }
+}
+
+impl Parser {
+    /// Parses `s` into a tree of rule matches, e.g. for `Query::matches`.
+    pub fn parse_syntax(&self, s: &str) -> Result<SyntaxNode, Failure> {
+        let mut nodes = vec![];
+        SyntaxNode::build(record(&recorder(self, |_, _| ()), s)?, s, &mut nodes);
+        Ok(match nodes.len() {
+            1 if nodes[0].kind.is_some() && nodes[0].span == (0..s.len()) => nodes.pop().unwrap(),
+            _ => SyntaxNode{kind: None, span: 0..s.len(), text: s.to_string(), children: nodes},
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn outline(node: &SyntaxNode) -> String {
+        match &node.kind {
+            Some(kind) => format!("({}{})", kind, node.children.iter().map(|child| format!(" {}", outline(child))).collect::<String>()),
+            None if node.children.is_empty() => format!("{:?}@{}", node.text, node.span.start),
+            None => format!("(_{})", node.children.iter().map(|child| format!(" {}", outline(child))).collect::<String>()),
+        }
+    }
+
+    #[test]
+    fn parse_syntax_ok() {
+        let grammar = Grammar::parse(r#"
+            pairs = flat(list(pair) (~"," pair)*) ;
+            pair  = key ~":" value ;
+            key   = ~/\s*/ /[a-z]+/ ;
+            value = ~/\s*/ (/[0-9]+/ | ~"[" pairs ~"]") ;
+        "#).unwrap();
+        let tree = grammar.parser(None).unwrap().parse_syntax("a: 1, b: [c:2]").unwrap();
+        assert_eq!(outline(&tree), "(pairs (pair (key \"a\"@0) (value \"1\"@3)) (pair (key \"b\"@6) (value (pairs (pair (key \"c\"@10) (value \"2\"@12))))))");
+        assert_eq!((tree.children[1].text.as_str(), tree.children[1].span.clone()), ("b: [c:2]", 6..14));
+        assert_eq!(tree.descendants().len(), 16);
+
+        let bare = Parser::regex("[a-z]", 0).and(Parser::skip(",")).repeat().parse_syntax("a,b,").unwrap();
+        assert_eq!(outline(&bare), "(_ \"a\"@0 \"b\"@2)");
+    }
+
+    #[test]
+    fn parse_syntax_error() {
+        let key = Parser::rule("key");
+        key.define(Parser::regex("[a-z]+", 0).or(Parser::regex("[0-9]+", 0)));
+        let failure = key.clone().and(Parser::skip(";")).parse_syntax("ab,").unwrap_err();
+        assert_eq!(failure.position, 2);
+        let tree = key.or(Parser::regex("a", 0)).parse_syntax("12").unwrap();
+        assert_eq!(outline(&tree), "(key \"12\"@0)");
+    }
+}
@@ -0,0 +1,193 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::instrument::{self, Wrap};
+use crate::{Failure, Parser, Success};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceKind {
+    Enter,
+    Exit,
+    Fail,
+}
+
+/// One step of a traced parse. `length` is the input consumed by an `Exit`, or how far
+/// past `position` a `Fail` got before it failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub kind: TraceKind,
+    pub name: String,
+    pub position: i32,
+    pub length: i32,
+    pub expected: Vec<String>,
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Trace {
+    pub events: Vec<TraceEvent>,
+}
+
+struct TraceState {
+    events: Vec<TraceEvent>,
+    depth: usize,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<TraceState>> = const { RefCell::new(None) };
+}
+
+fn record(kind: TraceKind, name: &str, position: i32, end: i32, expected: &[String]) {
+    STATE.with(|state| {
+        if let Some(state) = state.borrow_mut().as_mut() {
+            if kind != TraceKind::Enter {
+                state.depth -= 1;
+            }
+            let event = TraceEvent{kind, name: name.to_string(), position, length: end - position, expected: expected.to_vec(), depth: state.depth};
+            state.events.push(event);
+            if kind == TraceKind::Enter {
+                state.depth += 1;
+            }
+        }
+    })
+}
+
+fn traced(original: &Parser, rebuilt: Parser) -> Parser {
+    let name = match instrument::label(original) {
+        Some(name) => name,
+        None => return rebuilt,
+    };
+    let inner = rebuilt.func.clone();
+    Parser{node: rebuilt.node, func: Arc::new(move |root: &Parser, s: &str, i: i32| {
+        record(TraceKind::Enter, &name, i, i, &[]);
+        let result = inner(root, s, i);
+        match &result {
+            Ok(success) => record(TraceKind::Exit, &name, i, success.position, &[]),
+            Err(failure) => record(TraceKind::Fail, &name, i, failure.position, &failure.expected),
+        }
+        result
+    })}
+}
+
+impl Parser {
+    /// Parses `s` with every rule and terminal attempt recorded.
+    /// The parser itself is not changed, so untraced parses pay nothing.
+    pub fn parse_traced(&self, s: &str) -> (Result<Success, Failure>, Trace) {
+        let wrap: Wrap = Arc::new(traced);
+        let parser = instrument::rebuild(self, &wrap);
+        let saved = STATE.with(|state| state.replace(Some(TraceState{events: vec![], depth: 0})));
+        let result = parser.parse(s);
+        let state = STATE.with(|state| state.replace(saved)).unwrap();
+        (result, Trace{events: state.events})
+    }
+}
+
+impl Trace {
+    /// The events inside attempts of the rule or terminal `name`, re-indented from depth 0.
+    pub fn filter(&self, name: &str) -> Trace {
+        let mut events = vec![];
+        let mut outer: Option<usize> = None;
+        for event in self.events.iter() {
+            let base = match outer {
+                Some(base) => base,
+                None if event.kind == TraceKind::Enter && event.name == name => {
+                    outer = Some(event.depth);
+                    event.depth
+                }
+                None => continue,
+            };
+            events.push(TraceEvent{depth: event.depth - base, ..event.clone()});
+            if event.kind != TraceKind::Enter && event.depth == base {
+                outer = None;
+            }
+        }
+        Trace{events}
+    }
+}
+
+fn write_end(f: &mut fmt::Formatter, event: &TraceEvent) -> fmt::Result {
+    match event.kind {
+        TraceKind::Exit => writeln!(f, " ok +{}", event.length),
+        _ if event.expected.is_empty() => writeln!(f, " fail @{}", event.position + event.length),
+        _ => {
+            let expected: Vec<String> = event.expected.iter().map(|e| format!("{:?}", e)).collect();
+            writeln!(f, " fail @{}: expected {}", event.position + event.length, expected.join(" or "))
+        }
+    }
+}
+
+/// One line per attempt, nested attempts indented; an attempt with nothing nested
+/// is shown on a single line.
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut events = self.events.iter().peekable();
+        while let Some(event) = events.next() {
+            write!(f, "{}{} @{}", "  ".repeat(event.depth), event.name, event.position)?;
+            if event.kind != TraceKind::Enter {
+                write_end(f, event)?;
+                continue;
+            }
+            match events.peek() {
+                Some(next) if next.kind != TraceKind::Enter && next.depth == event.depth => {
+                    write_end(f, next)?;
+                    events.next();
+                }
+                _ => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn grammar() -> Parser {
+        Grammar::parse("list = ~\"(\" flat(item (~\",\" item)*) ~\")\" ; item = /[a-z]+/ | list ;").unwrap().parser(None).unwrap()
+    }
+
+    #[test]
+    fn trace_ok() {
+        let parser = grammar();
+        let (result, trace) = parser.parse_traced("(a,(b))");
+        assert_eq!(result.unwrap().value, parser.parse("(a,(b))").unwrap().value);
+        assert_eq!(trace.events.first().map(|e| (e.kind, e.name.as_str(), e.depth)), Some((TraceKind::Enter, "list", 0)));
+        assert_eq!(trace.events.last().map(|e| (e.kind, e.length, e.depth)), Some((TraceKind::Exit, 7, 0)));
+        assert_eq!(trace.filter("item").to_string(), "\
+item @1
+  /[a-z]+/ @1 ok +1
+item @1 ok +1
+item @3
+  /[a-z]+/ @3 fail @3: expected \"[a-z]+\"
+  list @3
+    ~/\\(/ @3 ok +1
+    item @4
+      /[a-z]+/ @4 ok +1
+    item @4 ok +1
+    ~/,/ @5 fail @5: expected \",\"
+    ~/\\)/ @5 ok +1
+  list @3 ok +3
+item @3 ok +3
+");
+        assert!(parser.parse_traced("").1.filter("item").events.is_empty());
+    }
+
+    #[test]
+    fn trace_error() {
+        let (result, trace) = grammar().parse_traced("(a;");
+        assert_eq!(result.unwrap_err().position, 2);
+        assert_eq!(trace.to_string(), "\
+list @0
+  ~/\\(/ @0 ok +1
+  item @1
+    /[a-z]+/ @1 ok +1
+  item @1 ok +1
+  ~/,/ @2 fail @2: expected \",\"
+  ~/\\)/ @2 fail @2: expected \"\\\\)\"
+list @0 fail @2: expected \"\\\\)\"
+");
+    }
+}
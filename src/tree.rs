@@ -0,0 +1,53 @@
+use crate::json::write_string;
+use crate::Value;
+
+impl Value {
+    /// An S-expression: lists in parentheses, strings quoted, `None` as `nil`.
+    pub fn to_sexp(&self) -> String {
+        match self {
+            Value::None => "nil".to_string(),
+            Value::Some(s) => {
+                let mut out = String::new();
+                write_string(&mut out, s);
+                out
+            }
+            Value::List(items) => format!("({})", items.iter().map(|item| item.to_sexp()).collect::<Vec<_>>().join(" ")),
+        }
+    }
+
+    /// One line per node, children indented by two spaces under `list`.
+    pub fn to_tree(&self) -> String {
+        let mut out = String::new();
+        self.write_tree(&mut out, 0);
+        out
+    }
+
+    fn write_tree(&self, out: &mut String, depth: usize) {
+        out.push_str(&"  ".repeat(depth));
+        match self {
+            Value::None => out.push_str("none\n"),
+            Value::Some(s) => {
+                write_string(out, s);
+                out.push('\n');
+            }
+            Value::List(items) => {
+                out.push_str("list\n");
+                for item in items {
+                    item.write_tree(out, depth + 1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_sexp_ok() {
+        let value = Value::List(vec![Value::Some("a".to_string()), Value::List(vec![Value::None, Value::Some("b c".to_string())])]);
+        assert_eq!(value.to_sexp(), "(\"a\" (nil \"b c\"))");
+        assert_eq!(value.to_tree(), "list\n  \"a\"\n  list\n    none\n    \"b c\"\n");
+    }
+}
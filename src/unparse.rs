@@ -0,0 +1,318 @@
+use std::collections::{HashMap, HashSet};
+use std::ptr;
+use std::sync::Arc;
+
+use crate::{Node, Parser, UnparseError, Value};
+
+static NONE: Value = Value::None;
+
+/// The value a node has to produce.
+#[derive(Clone, Copy, PartialEq)]
+enum Target<'a> {
+    Exact(&'a Value),
+    /// A non-`None` value that `flat` would splice into exactly these items.
+    Items(&'a [Value]),
+    /// A list (or `None` when empty) whose items, each spliced, give exactly these items.
+    Spliced(&'a [Value]),
+}
+
+type TargetKey = (u8, usize, usize);
+
+struct Unparser<'a> {
+    root: &'a Parser,
+    /// Whether a recursive rule may be printed for an empty value inside itself, e.g. `[]` in JSON.
+    /// The first pass forbids it so values are not padded with empty constructs.
+    empty_root: bool,
+    /// Targets being printed through the root parser or a named rule, to cut off cycles.
+    stack: Vec<(usize, Target<'a>)>,
+    cuts: usize,
+    cache: HashMap<(usize, TargetKey), Option<String>>,
+    failures: Vec<(&'a Value, Option<String>)>,
+    printed: HashSet<usize>,
+}
+
+impl<'a> Target<'a> {
+    /// The items of a target that must be a list of exactly these values.
+    fn list(self) -> Option<&'a [Value]> {
+        match self {
+            Target::Exact(Value::List(items)) => Some(items),
+            Target::Items(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Identifies the target by the position of its values in the tree being printed.
+    fn key(self) -> TargetKey {
+        match self {
+            Target::Exact(value) => (0, value as *const Value as usize, 0),
+            Target::Items(items) => (1, items.as_ptr() as usize, items.len()),
+            Target::Spliced(items) => (2, items.as_ptr() as usize, items.len()),
+        }
+    }
+
+    fn is_empty(self) -> bool {
+        matches!(self, Target::Exact(Value::None) | Target::Spliced([]))
+    }
+}
+
+fn is_list(value: &Value) -> bool {
+    matches!(value, Value::List(_))
+}
+
+impl<'a> Unparser<'a> {
+    fn fail(&mut self, target: Target<'a>, pattern: Option<&str>) -> Option<String> {
+        if let Target::Exact(value) = target {
+            self.failures.push((value, pattern.map(|p| p.to_string())));
+        }
+        None
+    }
+
+    fn print(&mut self, parser: &Parser, target: Target<'a>) -> Option<String> {
+        let key = (Arc::as_ptr(&parser.node) as usize, target.key());
+        if let Some(text) = self.cache.get(&key) {
+            return text.clone();
+        }
+        let cuts = self.cuts;
+        let node = Arc::as_ptr(&parser.node) as usize;
+        let recursive = Arc::ptr_eq(&parser.node, &self.root.node) || matches!(&*parser.node, Node::Rule(..));
+        let text = if !recursive {
+            self.print_node(parser, target)
+        } else if self.stack.contains(&(node, target))
+            || (!self.empty_root && target.is_empty() && self.stack.iter().any(|(n, _)| *n == node)) {
+            self.cuts += 1;
+            None
+        } else {
+            self.stack.push((node, target));
+            let text = self.print_node(parser, target);
+            self.stack.pop();
+            text
+        };
+        // A failure caused by cutting a cycle may succeed from elsewhere, so only cache it without cuts.
+        if let (Some(_), Target::Exact(value)) = (&text, target) {
+            self.printed.insert(value as *const Value as usize);
+        }
+        if text.is_some() || self.cuts == cuts {
+            self.cache.insert(key, text.clone());
+        }
+        text
+    }
+
+    fn print_node(&mut self, parser: &Parser, target: Target<'a>) -> Option<String> {
+        match &*parser.node {
+            Node::Regex{pattern, group, canonical, ..} if *group < 0 => match target {
+                Target::Exact(Value::None) | Target::Spliced([]) => match canonical {
+                    Some(canonical) => Some(canonical.clone()),
+                    None => self.fail(target, Some(pattern)),
+                },
+                _ => None,
+            },
+            Node::Regex{pattern, group, canonical, regex, ..} => {
+                let value = match target {
+                    Target::Exact(Value::Some(value)) => value,
+                    Target::Items([Value::Some(value)]) => value,
+                    _ => return self.fail(target, Some(pattern)),
+                };
+                let text = match canonical {
+                    Some(template) => template.replace("{}", value),
+                    None => value.clone(),
+                };
+                let printed = regex.captures(&text).filter(|caps| {
+                    caps.get(0).map(|m| m.end()) == Some(text.len())
+                        && caps.get(*group as usize + 1).map_or("", |m| m.as_str()) == value
+                });
+                match printed {
+                    Some(_) => Some(text),
+                    None => self.fail(target, Some(pattern)),
+                }
+            }
+            Node::And(a, b) => {
+                // Prefer both sides producing a value: `[2, 3]` rather than `[2, 3, ]` for a trailing-comma grammar.
+                let both = match (target, target.list()) {
+                    (_, Some(items)) if items.len() == 2 =>
+                        self.pair(a, Target::Exact(&items[0]), b, Target::Exact(&items[1])),
+                    (Target::Spliced(items), _) => (0..=items.len()).rev()
+                        .find_map(|k| self.pair(a, Target::Items(&items[..k]), b, Target::Items(&items[k..]))),
+                    _ => None,
+                };
+                both.or_else(|| self.pair(a, Target::Exact(&NONE), b, target))
+                    .or_else(|| self.pair(a, target, b, Target::Exact(&NONE)))
+            }
+            Node::Or(a, b) => match self.print(a, target).or_else(|| self.print(b, target)) {
+                Some(text) => Some(text),
+                None => self.fail(target, None),
+            },
+            Node::Repeat(p) => match (target, target.list()) {
+                (_, Some(items)) => items.iter().map(|item| self.print(p, Target::Exact(item))).collect(),
+                (Target::Spliced(items), _) => self.partition(p, items),
+                _ => None,
+            },
+            Node::List(p) => match (target, target.list()) {
+                (Target::Exact(Value::None), _) => self.print(p, target),
+                (_, Some([item])) => self.print(p, Target::Exact(item)),
+                (Target::Spliced([]), _) => self.print(p, Target::Exact(&NONE)),
+                (Target::Spliced(items), _) => self.print(p, Target::Items(items)),
+                _ => None,
+            },
+            Node::Flat(p) => match target {
+                Target::Exact(Value::None) => self.print(p, Target::Spliced(&[])),
+                Target::Exact(Value::List(items)) if !items.is_empty() => self.print(p, Target::Spliced(items)),
+                Target::Exact(Value::List(_)) => None,
+                Target::Exact(_) => self.print(p, target),
+                Target::Items(items) => {
+                    let spliced = if items.is_empty() {None} else {self.print(p, Target::Spliced(items))};
+                    match items {
+                        [item] if !is_list(item) => spliced.or_else(|| self.print(p, Target::Exact(item))),
+                        _ => spliced,
+                    }
+                }
+                Target::Spliced(items) if !items.iter().any(is_list) => self.print(p, target),
+                Target::Spliced(_) => None,
+            },
+            Node::Memo(p) | Node::Layout(_, p) => self.print(p, target),
+            Node::Rule(_, slot) => match slot.get() {
+                Some(body) => self.print(&body, target),
+                None => None,
+            },
+            Node::Recursive(body) => self.print(body, target),
+            Node::Root => self.print(self.root, target),
+            Node::Custom => None,
+        }
+    }
+
+    fn pair(&mut self, a: &Parser, ta: Target<'a>, b: &Parser, tb: Target<'a>) -> Option<String> {
+        let first = self.print(a, ta)?;
+        Some(first + &self.print(b, tb)?)
+    }
+
+    fn partition(&mut self, p: &Parser, items: &'a [Value]) -> Option<String> {
+        if items.is_empty() {
+            return Some(String::new());
+        }
+        (1..=items.len()).find_map(|k| {
+            let first = self.print(p, Target::Items(&items[..k]))?;
+            Some(first + &self.partition(p, &items[k..])?)
+        })
+    }
+}
+
+fn path_to(value: &Value, target: &Value, path: &mut Vec<usize>) -> bool {
+    if ptr::eq(value, target) {
+        return true;
+    }
+    if let Value::List(items) = value {
+        for (i, item) in items.iter().enumerate() {
+            path.push(i);
+            if path_to(item, target, path) {
+                return true;
+            }
+            path.pop();
+        }
+    }
+    false
+}
+
+impl Parser {
+    /// Prints `value` as text that this parser parses back to `value`.
+    /// Skipped terminals are printed as their canonical form.
+    pub fn unparse(&self, value: &Value) -> Result<String, UnparseError> {
+        let mut unparser = Unparser{root: self, empty_root: false, stack: vec![], cuts: 0, cache: HashMap::new(), failures: vec![], printed: HashSet::new()};
+        let mut printed = unparser.print(self, Target::Exact(value));
+        if printed.is_none() {
+            unparser.empty_root = true;
+            unparser.cache.clear();
+            printed = unparser.print(self, Target::Exact(value));
+        }
+        match printed {
+            Some(text) => match self.parse(&text) {
+                Ok(success) if success.value == *value => Ok(text),
+                _ => Err(UnparseError::RoundTrip{text}),
+            },
+            None => {
+                let mut deepest: Option<(Vec<usize>, &Value)> = None;
+                for (failed, _) in unparser.failures.iter() {
+                    if unparser.printed.contains(&(*failed as *const Value as usize)) {
+                        continue;
+                    }
+                    let mut path = vec![];
+                    if path_to(value, failed, &mut path) && deepest.as_ref().is_none_or(|(p, _)| path.len() > p.len()) {
+                        deepest = Some((path, failed));
+                    }
+                }
+                let (path, failed) = deepest.unwrap_or((vec![], value));
+                let mut expected: Vec<String> = vec![];
+                for (other, pattern) in unparser.failures.iter() {
+                    if let (true, Some(pattern)) = (ptr::eq(*other, failed), pattern) {
+                        if !expected.contains(pattern) {
+                            expected.push(pattern.clone());
+                        }
+                    }
+                }
+                Err(UnparseError::NoMatch{path, value: failed.clone(), expected})
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json() -> Parser {
+        let ws = Parser::skip_as("\\s*", "");
+        let item = Parser::regex("true|false", 0)
+            .or(Parser::regex("-?(0|[1-9][0-9]*)", 0))
+            .or(Parser::regex_as("\"([^\"]*)\"", 1, "\"{}\""));
+        let array = Parser::new(Box::new(|root:&Parser|
+            Parser::skip("\\[")
+            .and(root.clone().and(Parser::skip_as(",\\s*", ", ")).repeat().and(root.clone().or(Parser::skip(""))).flat())
+            .and(Parser::skip("]"))
+        ));
+        let object = Parser::new(Box::new(|root:&Parser| {
+            let pair = Parser::regex_as("\"([^\"]*)\"", 1, "\"{}\"").and(Parser::skip_as("\\s*:\\s*", ": ")).and(root.clone());
+            let comma = Parser::skip_as(",\\s*", ", ");
+            Parser::skip("\\{")
+            .and(pair.clone().list().and(comma.clone().and(pair).repeat()).flat())
+            .and(Parser::skip("}"))
+        }));
+        ws.clone().and(item.or(object).or(array)).and(ws)
+    }
+
+    #[test]
+    fn unparse_ok() {
+        let parser = json();
+        for source in ["[]", "[1, true, \"a b\"]", "{\"k\": [1, [2, []]], \"o\": {\"x\": false}}", "-5", " [ 1 ,2 ] "] {
+            let value = parser.parse(source).unwrap().value;
+            let text = parser.unparse(&value).unwrap();
+            assert_eq!(parser.parse(&text).unwrap().value, value);
+        }
+        let value = Value::List(vec![
+            Value::List(vec![Value::Some("a".to_string()), Value::Some("1".to_string())]),
+            Value::List(vec![Value::Some("b".to_string()), Value::List(vec![Value::Some("2".to_string()), Value::Some("3".to_string())])]),
+        ]);
+        assert_eq!(parser.unparse(&value).unwrap(), "{\"a\": 1, \"b\": [2, 3]}");
+        assert_eq!(parser.unparse(&Value::None).unwrap(), "[]");
+    }
+
+    #[test]
+    fn unparse_error() {
+        let parser = json();
+        let value = Value::List(vec![
+            Value::Some("1".to_string()),
+            Value::List(vec![Value::Some("2".to_string()), Value::Some("x\"y".to_string())]),
+        ]);
+        let error = parser.unparse(&value).err().unwrap();
+        assert_eq!(error, UnparseError::NoMatch{
+            path: vec![1, 1],
+            value: Value::Some("x\"y".to_string()),
+            expected: vec!["true|false".to_string(), "-?(0|[1-9][0-9]*)".to_string(), "\"([^\"]*)\"".to_string()],
+        });
+
+        let parser = Parser::regex("a", 0).or(Parser::regex("a+", 0));
+        let error = parser.unparse(&Value::Some("aa".to_string())).err().unwrap();
+        assert_eq!(error, UnparseError::RoundTrip{text: "aa".to_string()});
+
+        assert!(Parser::try_skip_as("[0-9]+", "x").is_err());
+        let error = Parser::skip("[0-9]+").unparse(&Value::None).err().unwrap();
+        assert_eq!(error.to_string(), "no alternative matches value / None: tried \"[0-9]+\"");
+    }
+}
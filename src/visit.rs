@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{PatternError, RewriteError, Value};
+
+/// Callbacks for `Value::accept`, with the child indexes leading to each node.
+pub trait Visitor {
+    /// Called before the children are visited; returning false skips them.
+    fn enter(&mut self, _value: &Value, _path: &[usize]) -> bool {
+        true
+    }
+
+    /// Called after the children are visited.
+    fn leave(&mut self, _value: &Value, _path: &[usize]) {}
+}
+
+impl Value {
+    pub fn accept(&self, visitor: &mut dyn Visitor) {
+        self.accept_at(visitor, &mut vec![]);
+    }
+
+    fn accept_at(&self, visitor: &mut dyn Visitor, path: &mut Vec<usize>) {
+        if visitor.enter(self, path) {
+            if let Value::List(items) = self {
+                for (i, item) in items.iter().enumerate() {
+                    path.push(i);
+                    item.accept_at(visitor, path);
+                    path.pop();
+                }
+            }
+        }
+        visitor.leave(self, path);
+    }
+
+    /// Every node, each before its children.
+    pub fn pre_order(&self) -> impl Iterator<Item = &Value> {
+        let mut stack = vec![self];
+        std::iter::from_fn(move || {
+            let value = stack.pop()?;
+            if let Value::List(items) = value {
+                stack.extend(items.iter().rev());
+            }
+            Some(value)
+        })
+    }
+
+    /// Every node, each after its children.
+    pub fn post_order(&self) -> impl Iterator<Item = &Value> {
+        let mut stack = vec![(self, false)];
+        std::iter::from_fn(move || loop {
+            let (value, expanded) = stack.pop()?;
+            match value {
+                Value::List(items) if !expanded => {
+                    stack.push((value, true));
+                    stack.extend(items.iter().rev().map(|item| (item, false)));
+                }
+                _ => return Some(value),
+            }
+        })
+    }
+
+    /// Bottom-up evaluation: `f` gets each node with the results for its children.
+    pub fn fold<T>(&self, f: &mut impl FnMut(&Value, Vec<T>) -> T) -> T {
+        let children = match self {
+            Value::List(items) => items.iter().map(|item| item.fold(f)).collect(),
+            _ => vec![],
+        };
+        f(self, children)
+    }
+}
+
+/// A value pattern in `to_sexp` notation: `"text"`, `nil` and `(...)` match themselves,
+/// `_` matches anything, `?name` binds a node and a final `?name..` binds the remaining
+/// items of a list. A name bound twice must match equal values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Any,
+    None,
+    Text(String),
+    Bind(String),
+    List(Vec<Pattern>, Option<String>),
+}
+
+/// What `Pattern::matches` bound; a rest binding holds a `Value::List` of the items.
+pub type Bindings = HashMap<String, Value>;
+
+impl Pattern {
+    pub fn parse(text: &str) -> Result<Pattern, PatternError> {
+        let mut reader = Reader{text, position: 0};
+        let pattern = reader.pattern()?;
+        reader.space();
+        match reader.position < text.len() {
+            true => Err(reader.error("trailing characters")),
+            false => Ok(pattern),
+        }
+    }
+
+    pub fn matches(&self, value: &Value) -> Option<Bindings> {
+        let mut bindings = Bindings::new();
+        match self.bind(value, &mut bindings) {
+            true => Some(bindings),
+            false => None,
+        }
+    }
+
+    fn bind(&self, value: &Value, bindings: &mut Bindings) -> bool {
+        match (self, value) {
+            (Pattern::Any, _) | (Pattern::None, Value::None) => true,
+            (Pattern::Text(text), Value::Some(s)) => text == s,
+            (Pattern::Bind(name), value) => bind(name, value.clone(), bindings),
+            (Pattern::List(patterns, rest), Value::List(items)) => {
+                let fits = match rest {
+                    Some(_) => items.len() >= patterns.len(),
+                    None => items.len() == patterns.len(),
+                };
+                fits && patterns.iter().zip(items).all(|(pattern, item)| pattern.bind(item, bindings))
+                    && rest.as_ref().is_none_or(|name| bind(name, Value::List(items[patterns.len()..].to_vec()), bindings))
+            }
+            _ => false,
+        }
+    }
+
+    /// Builds a value from a template pattern, splicing `?name..` bindings into lists.
+    fn instantiate(&self, bindings: &Bindings) -> Value {
+        match self {
+            Pattern::Any => unreachable!("templates are checked for `_`"),
+            Pattern::None => Value::None,
+            Pattern::Text(text) => Value::Some(text.clone()),
+            Pattern::Bind(name) => bindings[name].clone(),
+            Pattern::List(patterns, rest) => {
+                let mut items: Vec<Value> = patterns.iter().map(|pattern| pattern.instantiate(bindings)).collect();
+                if let Some(Value::List(rest)) = rest.as_ref().map(|name| &bindings[name]) {
+                    items.extend(rest.iter().cloned());
+                }
+                Value::List(items)
+            }
+        }
+    }
+
+    fn names(&self, out: &mut Vec<String>) {
+        match self {
+            Pattern::Bind(name) => out.push(name.clone()),
+            Pattern::List(patterns, rest) => {
+                patterns.iter().for_each(|pattern| pattern.names(out));
+                out.extend(rest.iter().cloned());
+            }
+            _ => (),
+        }
+    }
+
+    /// The names of `?name..` rest bindings.
+    fn rests(&self, out: &mut Vec<String>) {
+        if let Pattern::List(patterns, rest) = self {
+            patterns.iter().for_each(|pattern| pattern.rests(out));
+            out.extend(rest.iter().cloned());
+        }
+    }
+
+    fn has_any(&self) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::List(patterns, _) => patterns.iter().any(Pattern::has_any),
+            _ => false,
+        }
+    }
+}
+
+fn bind(name: &str, value: Value, bindings: &mut Bindings) -> bool {
+    match bindings.get(name) {
+        Some(bound) => *bound == value,
+        None => {
+            bindings.insert(name.to_string(), value);
+            true
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Any => f.write_str("_"),
+            Pattern::None => f.write_str("nil"),
+            Pattern::Text(text) => f.write_str(&Value::Some(text.clone()).to_sexp()),
+            Pattern::Bind(name) => write!(f, "?{}", name),
+            Pattern::List(patterns, rest) => {
+                let mut items: Vec<String> = patterns.iter().map(Pattern::to_string).collect();
+                items.extend(rest.iter().map(|name| format!("?{}..", name)));
+                write!(f, "({})", items.join(" "))
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> PatternError {
+        PatternError{position: self.position, message: message.to_string()}
+    }
+
+    fn space(&mut self) {
+        let rest = &self.text[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, PatternError> {
+        self.space();
+        let start = self.position;
+        let rest = self.rest();
+        if rest.starts_with('(') {
+            self.position += 1;
+            let mut patterns = vec![];
+            loop {
+                self.space();
+                if self.rest().starts_with(')') {
+                    self.position += 1;
+                    return Ok(Pattern::List(patterns, None));
+                }
+                match self.pattern()? {
+                    Pattern::Bind(name) if self.rest().starts_with("..") => {
+                        self.position += 2;
+                        self.space();
+                        if !self.rest().starts_with(')') {
+                            return Err(self.error("expected ')' after a rest binding"));
+                        }
+                        self.position += 1;
+                        return Ok(Pattern::List(patterns, Some(name)));
+                    }
+                    pattern => patterns.push(pattern),
+                }
+            }
+        }
+        if rest.starts_with('"') {
+            return self.string().map(Pattern::Text);
+        }
+        let length = rest.find(|c: char| c.is_whitespace() || "()\"".contains(c)).unwrap_or(rest.len());
+        let word = &rest[..length];
+        let word = word.strip_suffix("..").filter(|word| word.starts_with('?')).unwrap_or(word);
+        self.position += word.len();
+        match word {
+            "" if rest.is_empty() => Err(self.error("unexpected end of pattern")),
+            "" => Err(self.error("unexpected ')'")),
+            "_" => Ok(Pattern::Any),
+            "nil" => Ok(Pattern::None),
+            _ if word.len() > 1 && word.starts_with('?') => Ok(Pattern::Bind(word[1..].to_string())),
+            _ => {
+                self.position = start;
+                Err(self.error("expected a string, nil, _, ?name or a list"))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, PatternError> {
+        let start = self.position;
+        self.position += 1;
+        let mut out = String::new();
+        let mut chars = self.rest().chars();
+        while let Some(c) = chars.next() {
+            self.position += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escape = chars.next().unwrap_or('\\');
+                    self.position += escape.len_utf8();
+                    out.push(match escape {
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        c => c,
+                    });
+                }
+                c => out.push(c),
+            }
+        }
+        self.position = start;
+        Err(self.error("unterminated string"))
+    }
+}
+
+type Compute = Box<dyn Fn(&Bindings) -> Option<Value> + Send + Sync>;
+
+enum Replacement {
+    Template(Pattern),
+    Function(Compute),
+}
+
+/// Rewrites a value bottom-up with pattern → replacement rules, tried in order, until no
+/// rule applies anywhere.
+pub struct Rewriter {
+    rules: Vec<(Pattern, Replacement)>,
+    limit: usize,
+}
+
+impl Default for Rewriter {
+    fn default() -> Self {
+        Rewriter::new()
+    }
+}
+
+impl Rewriter {
+    pub fn new() -> Self {
+        Rewriter{rules: vec![], limit: 10_000}
+    }
+
+    /// Replaces matches of `pattern` with `template`, a pattern built from the bindings.
+    pub fn rule(mut self, pattern: &str, template: &str) -> Result<Self, PatternError> {
+        let pattern = Pattern::parse(pattern)?;
+        let template = Pattern::parse(template)?;
+        let (mut bound, mut used) = (vec![], vec![]);
+        pattern.names(&mut bound);
+        template.names(&mut used);
+        if template.has_any() {
+            return Err(PatternError{position: 0, message: "`_` cannot appear in a replacement".to_string()});
+        }
+        if let Some(name) = used.iter().find(|name| !bound.contains(name)) {
+            return Err(PatternError{position: 0, message: format!("?{} is not bound by the pattern", name)});
+        }
+        let (mut rests, mut spliced) = (vec![], vec![]);
+        pattern.rests(&mut rests);
+        template.rests(&mut spliced);
+        if let Some(name) = spliced.iter().find(|name| !rests.contains(name)) {
+            return Err(PatternError{position: 0, message: format!("?{}.. splices a single node; bind it with ?{}.. in the pattern", name, name)});
+        }
+        self.rules.push((pattern, Replacement::Template(template)));
+        Ok(self)
+    }
+
+    /// Replaces matches of `pattern` with what `f` computes; `None` leaves the node alone.
+    pub fn rule_fn(mut self, pattern: &str, f: impl Fn(&Bindings) -> Option<Value> + Send + Sync + 'static) -> Result<Self, PatternError> {
+        self.rules.push((Pattern::parse(pattern)?, Replacement::Function(Box::new(f))));
+        Ok(self)
+    }
+
+    /// The most rewrites one `rewrite` call may make before giving up; 10000 by default.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn rewrite(&self, value: &Value) -> Result<Value, RewriteError> {
+        let mut count = 0;
+        self.normalize(value.clone(), &mut count)
+    }
+
+    fn normalize(&self, value: Value, count: &mut usize) -> Result<Value, RewriteError> {
+        let mut value = match value {
+            Value::List(items) => Value::List(items.into_iter().map(|item| self.normalize(item, count)).collect::<Result<_, _>>()?),
+            value => value,
+        };
+        while let Some(replaced) = self.step(&value) {
+            *count += 1;
+            if *count > self.limit {
+                return Err(RewriteError::Limit(self.limit));
+            }
+            value = match replaced {
+                Value::List(items) => Value::List(items.into_iter().map(|item| self.normalize(item, count)).collect::<Result<_, _>>()?),
+                replaced => replaced,
+            };
+        }
+        Ok(value)
+    }
+
+    /// The first rule's replacement for `value`, if it changes anything.
+    fn step(&self, value: &Value) -> Option<Value> {
+        self.rules.iter().find_map(|(pattern, replacement)| {
+            let bindings = pattern.matches(value)?;
+            let replaced = match replacement {
+                Replacement::Template(template) => template.instantiate(&bindings),
+                Replacement::Function(f) => f(&bindings)?,
+            };
+            Some(replaced).filter(|replaced| replaced != value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Value {
+        fn build(pattern: Pattern) -> Value {
+            match pattern {
+                Pattern::None => Value::None,
+                Pattern::Text(text) => Value::Some(text),
+                Pattern::List(patterns, _) => Value::List(patterns.into_iter().map(build).collect()),
+                _ => panic!("not a value"),
+            }
+        }
+        build(Pattern::parse(text).unwrap())
+    }
+
+    #[test]
+    fn visit_ok() {
+        let tree = value(r#"("+" "1" ("*" "2" nil))"#);
+        let pre: Vec<String> = tree.pre_order().map(Value::to_sexp).collect();
+        assert_eq!(pre, ["(\"+\" \"1\" (\"*\" \"2\" nil))", "\"+\"", "\"1\"", "(\"*\" \"2\" nil)", "\"*\"", "\"2\"", "nil"]);
+        let post: Vec<String> = tree.post_order().map(Value::to_sexp).collect();
+        assert_eq!(post, ["\"+\"", "\"1\"", "\"*\"", "\"2\"", "nil", "(\"*\" \"2\" nil)", "(\"+\" \"1\" (\"*\" \"2\" nil))"]);
+
+        struct Paths(Vec<String>);
+        impl Visitor for Paths {
+            fn enter(&mut self, value: &Value, path: &[usize]) -> bool {
+                self.0.push(format!("{:?}", path));
+                path.is_empty() || value.to_sexp() != "(\"*\" \"2\" nil)"
+            }
+            fn leave(&mut self, _: &Value, path: &[usize]) {
+                self.0.push(format!("/{:?}", path));
+            }
+        }
+        let mut paths = Paths(vec![]);
+        tree.accept(&mut paths);
+        assert_eq!(paths.0.join(" "), "[] [0] /[0] [1] /[1] [2] /[2] /[]");
+
+        let depth = tree.fold(&mut |_, children: Vec<usize>| children.into_iter().max().map_or(0, |depth| depth + 1));
+        assert_eq!(depth, 2);
+    }
+
+    #[test]
+    fn rewrite_ok() {
+        let number = |bindings: &Bindings, name: &str| match &bindings[name] {
+            Value::Some(s) => s.parse::<i64>().ok(),
+            _ => None,
+        };
+        let rewriter = Rewriter::new()
+            .rule(r#"("-" ?a ?b)"#, r#"("+" ?a ("neg" ?b))"#).unwrap()
+            .rule(r#"("+" ?a "0")"#, "?a").unwrap()
+            .rule(r#"("call" ?f ?args..)"#, r#"(?f ?args..)"#).unwrap()
+            .rule_fn(r#"("neg" ?a)"#, move |b| Some(Value::Some((-number(b, "a")?).to_string()))).unwrap()
+            .rule_fn(r#"("+" ?a ?b)"#, move |b| Some(Value::Some((number(b, "a")? + number(b, "b")?).to_string()))).unwrap();
+        assert_eq!(rewriter.rewrite(&value(r#"("-" "5" ("+" "2" "0"))"#)), Ok(value(r#""3""#)));
+        assert_eq!(rewriter.rewrite(&value(r#"("call" "f" "x" ("-" "x" "1") nil)"#)), Ok(value(r#"("f" "x" ("+" "x" "-1") nil)"#)));
+        assert_eq!(rewriter.rewrite(&value(r#"("+" "x" "y")"#)), Ok(value(r#"("+" "x" "y")"#)));
+
+        let pattern = Pattern::parse(r#"(?x ?x _ ?rest..)"#).unwrap();
+        assert_eq!(pattern.to_string(), "(?x ?x _ ?rest..)");
+        assert!(pattern.matches(&value(r#"("a" "b" "c")"#)).is_none());
+        let bindings = pattern.matches(&value(r#"("a" "a" "c" "d" nil)"#)).unwrap();
+        assert_eq!(bindings["rest"], value(r#"("d" nil)"#));
+    }
+
+    #[test]
+    fn rewrite_error() {
+        let error = |text: &str| Pattern::parse(text).map_err(|e| (e.position, e.message)).unwrap_err();
+        assert_eq!(error("(\"a\" "), (5, "unexpected end of pattern".to_string()));
+        assert_eq!(error("(?a.. ?b)"), (6, "expected ')' after a rest binding".to_string()));
+        assert_eq!(error("x"), (0, "expected a string, nil, _, ?name or a list".to_string()));
+        assert_eq!(error("\"a"), (0, "unterminated string".to_string()));
+        assert_eq!(error("nil nil"), (4, "trailing characters".to_string()));
+        assert_eq!(error(")"), (0, "unexpected ')'".to_string()));
+        assert_eq!(Rewriter::new().rule("?a", "(?a ?b)").err().unwrap().message, "?b is not bound by the pattern");
+        assert_eq!(Rewriter::new().rule("?a", "(?a _)").err().unwrap().message, "`_` cannot appear in a replacement");
+        assert_eq!(Rewriter::new().rule(r#"("f" ?x)"#, "(?x..)").err().unwrap().message, "?x.. splices a single node; bind it with ?x.. in the pattern");
+        assert!(Rewriter::new().rule(r#"("f" ?x..)"#, "(?x..)").is_ok());
+
+        let looping = Rewriter::new().rule("(?a)", "((?a))").unwrap().limit(50);
+        assert_eq!(looping.rewrite(&value("(\"a\")")), Err(RewriteError::Limit(50)));
+        assert_eq!(RewriteError::Limit(50).to_string(), "rewriting did not finish within 50 steps");
+    }
+}
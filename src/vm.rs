@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use regex::Regex;
+
+use crate::{analysis, key, CompileError, Failure, Node, Parser, Success, Value};
+
+/// How `Close` combines the values produced since the matching `Open`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Capture {
+    /// Like `Parser::and`: the non-`None` values, a single one unwrapped.
+    Join,
+    /// Like `Parser::repeat`: the non-`None` values as a list.
+    Collect,
+    List,
+    Flat,
+}
+
+#[derive(Debug, Clone)]
+enum Instr {
+    /// Terminals; the index is into `Program::terminals`.
+    Char(char, usize),
+    Text(String, usize),
+    Set(Vec<(char, char)>, usize),
+    Regex(usize),
+    /// Tries the following code; on failure, backtracks to the address and merges both failures.
+    Choice(usize),
+    /// Like `Choice`, but a failure is dropped: the loop of a repeat.
+    Catch(usize),
+    /// Pops the innermost `Choice` or `Catch` and jumps.
+    Commit(usize),
+    /// Pops the failure kept by a `Choice` once its alternative has succeeded.
+    Discard,
+    Call(usize),
+    Return,
+    Open,
+    Close(Capture),
+    /// Fails with nothing expected, as an undefined rule does.
+    Fail,
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Terminal {
+    pattern: String,
+    group: isize,
+    regex: Regex,
+}
+
+enum Frame {
+    Backtrack{alt: usize, position: usize, values: usize, marks: usize, merge: bool},
+    Pending(Failure),
+    Return(usize),
+}
+
+/// A parser compiled to instructions for a virtual machine with an explicit backtracking
+/// stack, so deeply nested input cannot overflow the Rust stack.
+/// Parses with the same values and failures as the parser it was compiled from.
+#[derive(Debug, Clone)]
+pub struct Program {
+    code: Vec<Instr>,
+    terminals: Vec<Terminal>,
+    /// Name and address of every procedure, for listings.
+    procedures: Vec<(String, usize)>,
+}
+
+struct Compiler<'p> {
+    root: &'p Parser,
+    code: Vec<Instr>,
+    terminals: Vec<Terminal>,
+    procedures: HashMap<usize, usize>,
+    bodies: Vec<(String, Parser)>,
+}
+
+impl<'p> Compiler<'p> {
+    fn emit(&mut self, instr: Instr) -> usize {
+        self.code.push(instr);
+        self.code.len() - 1
+    }
+
+    fn terminal(&mut self, pattern: &str, group: isize, regex: &Regex) {
+        let index = self.terminals.len();
+        self.terminals.push(Terminal{pattern: pattern.to_string(), group, regex: regex.clone()});
+        let instr = match (analysis::literal(pattern), analysis::class(pattern)) {
+            _ if group > 0 => Instr::Regex(index),
+            (Some(text), _) if text.chars().count() == 1 => Instr::Char(text.chars().next().unwrap(), index),
+            (Some(text), _) => Instr::Text(text, index),
+            (_, Some(ranges)) => Instr::Set(ranges, index),
+            _ => Instr::Regex(index),
+        };
+        self.emit(instr);
+    }
+
+    /// A call to the procedure for a rule, a recursive parser or the root.
+    fn call(&mut self, parser: &Parser) {
+        let procedure = match self.procedures.get(&key(parser)) {
+            Some(procedure) => *procedure,
+            None => {
+                let name = parser.name().map_or_else(|| "<root>".to_string(), str::to_string);
+                self.procedures.insert(key(parser), self.bodies.len());
+                self.bodies.push((name, parser.clone()));
+                self.bodies.len() - 1
+            }
+        };
+        self.emit(Instr::Call(procedure));
+    }
+
+    fn expr(&mut self, parser: &Parser) -> Result<(), CompileError> {
+        match &*parser.node {
+            Node::Rule(..) | Node::Recursive(_) => self.call(parser),
+            _ if key(parser) == key(self.root) => self.call(parser),
+            _ => self.node(parser)?,
+        }
+        Ok(())
+    }
+
+    fn node(&mut self, parser: &Parser) -> Result<(), CompileError> {
+        match &*parser.node {
+            Node::Regex{pattern, group, regex, ..} => self.terminal(pattern, *group, regex),
+            Node::And(a, b) => {
+                self.emit(Instr::Open);
+                self.expr(a)?;
+                self.expr(b)?;
+                self.emit(Instr::Close(Capture::Join));
+            }
+            Node::Or(a, b) => {
+                let choice = self.emit(Instr::Choice(0));
+                self.expr(a)?;
+                let commit = self.emit(Instr::Commit(0));
+                self.code[choice] = Instr::Choice(self.code.len());
+                self.expr(b)?;
+                self.emit(Instr::Discard);
+                self.code[commit] = Instr::Commit(self.code.len());
+            }
+            Node::Repeat(p) => {
+                self.emit(Instr::Open);
+                let catch = self.emit(Instr::Catch(0));
+                self.expr(p)?;
+                self.emit(Instr::Commit(catch));
+                self.code[catch] = Instr::Catch(self.code.len());
+                self.emit(Instr::Close(Capture::Collect));
+            }
+            Node::List(p) | Node::Flat(p) => {
+                self.emit(Instr::Open);
+                self.expr(p)?;
+                let capture = if let Node::List(_) = &*parser.node {Capture::List} else {Capture::Flat};
+                self.emit(Instr::Close(capture));
+            }
+            Node::Memo(p) | Node::Layout(_, p) => self.expr(p)?,
+            Node::Rule(_, slot) => match slot.get() {
+                Some(body) => self.expr(&body)?,
+                None => {
+                    self.emit(Instr::Fail);
+                }
+            },
+            Node::Recursive(body) => self.expr(body)?,
+            Node::Root => self.call(self.root),
+            Node::Custom => return Err(CompileError::Custom),
+        }
+        Ok(())
+    }
+}
+
+fn close(capture: Capture, mut values: Vec<Value>) -> Value {
+    match capture {
+        Capture::Join | Capture::Collect => {
+            values.retain(|value| *value != Value::None);
+            match (capture, values.len()) {
+                (Capture::Join, 0) => Value::None,
+                (Capture::Join, 1) => values.pop().unwrap(),
+                _ => Value::List(values),
+            }
+        }
+        Capture::List => match values.pop().unwrap() {
+            Value::None => Value::None,
+            value => Value::List(vec![value]),
+        },
+        Capture::Flat => match values.pop().unwrap() {
+            Value::List(items) => {
+                let mut flat = vec![];
+                for item in items {
+                    match item {
+                        Value::List(inner) => flat.extend(inner),
+                        Value::None => (),
+                        item => flat.push(item),
+                    }
+                }
+                if flat.is_empty() {Value::None} else {Value::List(flat)}
+            }
+            value => value,
+        },
+    }
+}
+
+impl Program {
+    /// The terminal an instruction matches and the length and value of its match at the start of `rest`.
+    fn terminal(&self, instr: &Instr, rest: &str) -> Option<(usize, Option<(usize, Value)>)> {
+        let (index, length) = match instr {
+            Instr::Char(c, index) => (*index, Some(c.len_utf8()).filter(|_| rest.starts_with(*c))),
+            Instr::Text(text, index) => (*index, Some(text.len()).filter(|_| rest.starts_with(text.as_str()))),
+            Instr::Set(ranges, index) => (*index, rest.chars().next().filter(|c| ranges.iter().any(|&(start, end)| start <= *c && *c <= end)).map(char::len_utf8)),
+            Instr::Regex(index) => {
+                let terminal = &self.terminals[*index];
+                let matched = terminal.regex.captures(rest).map(|captures| {
+                    let value = match terminal.group < 0 {
+                        true => Value::None,
+                        false => Value::Some(captures.get(terminal.group as usize + 1).map_or("", |m| m.as_str()).to_string()),
+                    };
+                    (captures.get(0).unwrap().end(), value)
+                });
+                return Some((*index, matched));
+            }
+            _ => return None,
+        };
+        let capture = self.terminals[index].group >= 0;
+        Some((index, length.map(|length| (length, if capture {Value::Some(rest[..length].to_string())} else {Value::None}))))
+    }
+
+    fn run(&self, s: &str) -> Result<Success, Failure> {
+        let (mut pc, mut i) = (0, 0);
+        let mut values: Vec<Value> = vec![];
+        let mut marks: Vec<usize> = vec![];
+        let mut stack: Vec<Frame> = vec![];
+        loop {
+            let instr = &self.code[pc];
+            let mut failure = match self.terminal(instr, &s[i..]) {
+                Some((_, Some((length, value)))) => {
+                    i += length;
+                    values.push(value);
+                    pc += 1;
+                    continue;
+                }
+                Some((index, None)) => Failure{position: i as i32, expected: vec![self.terminals[index].pattern.clone()]},
+                None => match instr {
+                    Instr::Choice(alt) | Instr::Catch(alt) => {
+                        let merge = matches!(instr, Instr::Choice(_));
+                        stack.push(Frame::Backtrack{alt: *alt, position: i, values: values.len(), marks: marks.len(), merge});
+                        pc += 1;
+                        continue;
+                    }
+                    Instr::Commit(target) => {
+                        stack.pop();
+                        pc = *target;
+                        continue;
+                    }
+                    Instr::Discard => {
+                        stack.pop();
+                        pc += 1;
+                        continue;
+                    }
+                    Instr::Call(target) => {
+                        stack.push(Frame::Return(pc + 1));
+                        pc = *target;
+                        continue;
+                    }
+                    Instr::Return => {
+                        if let Some(Frame::Return(next)) = stack.pop() {
+                            pc = next;
+                        }
+                        continue;
+                    }
+                    Instr::Open => {
+                        marks.push(values.len());
+                        pc += 1;
+                        continue;
+                    }
+                    Instr::Close(capture) => {
+                        let items = values.split_off(marks.pop().unwrap());
+                        values.push(close(*capture, items));
+                        pc += 1;
+                        continue;
+                    }
+                    Instr::End => return Ok(Success{position: i as i32, value: values.pop().unwrap_or(Value::None)}),
+                    _ => Failure{position: i as i32, expected: vec![]},
+                },
+            };
+            loop {
+                match stack.pop() {
+                    None => return Err(failure),
+                    Some(Frame::Return(_)) => (),
+                    Some(Frame::Pending(first)) => failure = Parser::merge_errs(first, failure),
+                    Some(Frame::Backtrack{alt, position, values: depth, marks: mark, merge}) => {
+                        values.truncate(depth);
+                        marks.truncate(mark);
+                        i = position;
+                        pc = alt;
+                        if merge {
+                            stack.push(Frame::Pending(failure));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parses all of `s`, as `Parser::parse` does.
+    pub fn parse(&self, s: &str) -> Result<Success, Failure> {
+        let success = self.run(s)?;
+        if success.position < s.len() as i32 {
+            return Err(Failure{position: success.position, expected: vec!["no length".to_string()]});
+        }
+        Ok(success)
+    }
+}
+
+fn ranges(ranges: &[(char, char)]) -> String {
+    let text = ranges.iter().map(|&(start, end)| match start == end {
+        true => start.escape_debug().to_string(),
+        false => format!("{}-{}", start.escape_debug(), end.escape_debug()),
+    });
+    format!("[{}]", text.collect::<String>())
+}
+
+impl fmt::Display for Program {
+    /// A listing with one instruction per line, each procedure headed by its name.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (pc, instr) in self.code.iter().enumerate() {
+            for (name, _) in self.procedures.iter().filter(|(_, start)| *start == pc) {
+                writeln!(f, "{}:", name)?;
+            }
+            let skip = |index: &usize| if self.terminals[*index].group < 0 {"~"} else {""};
+            let text = match instr {
+                Instr::Char(c, index) => format!("char {}{:?}", skip(index), c),
+                Instr::Text(text, index) => format!("text {}{:?}", skip(index), text),
+                Instr::Set(set, index) => format!("set {}{}", skip(index), ranges(set)),
+                Instr::Regex(index) => format!("regex {}/{}/ {}", skip(index), self.terminals[*index].pattern, self.terminals[*index].group),
+                Instr::Choice(alt) => format!("choice {}", alt),
+                Instr::Catch(alt) => format!("catch {}", alt),
+                Instr::Commit(target) => format!("commit {}", target),
+                Instr::Discard => "discard".to_string(),
+                Instr::Call(target) => format!("call {}", self.procedures.iter().find(|(_, start)| start == target).map_or("?", |(name, _)| name)),
+                Instr::Return => "return".to_string(),
+                Instr::Open => "open".to_string(),
+                Instr::Close(capture) => format!("close {}", format!("{:?}", capture).to_lowercase()),
+                Instr::Fail => "fail".to_string(),
+                Instr::End => "end".to_string(),
+            };
+            writeln!(f, "{:>4}  {}", pc, text)?;
+        }
+        Ok(())
+    }
+}
+
+impl Parser {
+    /// Compiles the parser for the virtual machine; fails for parsers built from functions.
+    pub fn compile(&self) -> Result<Program, CompileError> {
+        let mut compiler = Compiler{root: self, code: vec![], terminals: vec![], procedures: HashMap::new(), bodies: vec![]};
+        compiler.call(self);
+        compiler.emit(Instr::End);
+        let mut starts = vec![];
+        while starts.len() < compiler.bodies.len() {
+            let body = compiler.bodies[starts.len()].1.clone();
+            starts.push(compiler.code.len());
+            compiler.node(&body)?;
+            compiler.emit(Instr::Return);
+        }
+        for instr in compiler.code.iter_mut() {
+            if let Instr::Call(procedure) = instr {
+                *procedure = starts[*procedure];
+            }
+        }
+        let procedures = compiler.bodies.into_iter().map(|(name, _)| name).zip(starts).collect();
+        Ok(Program{code: compiler.code, terminals: compiler.terminals, procedures})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::Grammar;
+
+    #[test]
+    fn compile_ok() {
+        let parser = Grammar::parse(r#"
+            value  = ~/\s*/ (object | array | string | number | "true" | "null") ;
+            object = ~"{" list(flat(memo(member) (~"," member)*)?) ~/\s*\}/ ;
+            member = ~/\s*/ string ~/\s*:/ value ;
+            array  = ~"[" flat(list(value) (~"," value)*)? ~/\s*\]/ ;
+            string = /"((?:[^"\\]|\\.)*)"/1 ;
+            number = /-?[0-9]+(\.[0-9]+)?/ | /[a-f]/ ;
+        "#).unwrap().parser(None).unwrap();
+        let program = parser.compile().unwrap();
+        for input in [
+            r#"{"a": [1, -2.5, "x\"y"], "b": {}, "c": [true, null, []]}"#,
+            "[]", " 7", "[c, d]", r#"{"k": {"j": ["deep", {"i": 0}]}}"#,
+            "", "[1,", "{\"a\" 1}", "[1] x", "tru", "{\"a\": [1, }",
+        ] {
+            assert_eq!(program.parse(input), parser.parse(input), "{:?}", input);
+        }
+        let listing = program.to_string();
+        assert!(listing.starts_with("   0  call value\n   1  end\nvalue:\n   2  open\n"), "{}", listing);
+        for instr in ["char ~'{'", "text \"true\"", "set [a-f]", "regex ~/\\s*/ -1", "choice", "catch", "close flat"] {
+            assert!(listing.contains(instr), "{} in\n{}", instr, listing);
+        }
+
+        let nested = Parser::new(Box::new(|root: &Parser| Parser::regex("x", 0).or(Parser::skip("\\[").and(root.clone()).and(Parser::skip("]")))));
+        let program = nested.compile().unwrap();
+        for input in ["[[x]]", "[[x]"] {
+            assert_eq!(program.parse(input), nested.parse(input), "{:?}", input);
+        }
+
+        let deep = Grammar::parse(r#"nest = ~"(" nest? ~")" ;"#).unwrap().parser(None).unwrap().compile().unwrap();
+        let input = format!("{}{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(deep.parse(&input).unwrap().position, 200_000);
+    }
+
+    #[test]
+    fn compile_error() {
+        let custom = Parser::regex("a", 0).and(Parser::from_func(Arc::new(|_: &Parser, _: &str, i: i32| Ok(Success{position: i, value: Value::None}))));
+        assert_eq!(custom.compile().unwrap_err(), CompileError::Custom);
+
+        let undefined = Parser::rule("undefined");
+        let parser = Parser::skip("a").or(undefined.clone()).and(Parser::regex("[0-9]", 0));
+        let program = parser.compile().unwrap();
+        for input in ["a1", "b", "ax", "a1b"] {
+            assert_eq!(program.parse(input), parser.parse(input), "{:?}", input);
+        }
+        assert_eq!(undefined.compile().unwrap().parse("z").unwrap_err().expected, Vec::<String>::new());
+    }
+}